@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+
+use tracing::warn;
+
+use crate::core::error::{Locator, QueryError};
+use crate::core::parser::{JoinCondition, Query, RowProperty, WhereCondition};
+use crate::pg_client::schema::{Column, TableSchema};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TypeCategory {
+    Numeric,
+    Text,
+    Bool,
+    Temporal,
+    Other,
+}
+
+impl TypeCategory {
+    fn of(column: &Column) -> Self {
+        match column.data_type.as_str() {
+            "smallint" | "integer" | "bigint" | "numeric" | "real" | "double precision" => {
+                TypeCategory::Numeric
+            }
+            "text" | "character varying" | "character" | "uuid" => TypeCategory::Text,
+            "boolean" => TypeCategory::Bool,
+            data_type
+                if data_type.starts_with("timestamp")
+                    || data_type.starts_with("time")
+                    || data_type == "date" =>
+            {
+                TypeCategory::Temporal
+            }
+            _ => TypeCategory::Other,
+        }
+    }
+}
+
+// Validates a parsed query against the catalog of the source database
+pub struct Binder {
+    schemas: HashMap<String, TableSchema>,
+}
+
+impl Binder {
+    pub fn new(schemas: HashMap<String, TableSchema>) -> Self {
+        Binder { schemas }
+    }
+
+    // Check that all referenced tables and columns exist and that the compared types fit.
+    // Returns the query with every column qualified by its table and `*` expanded.
+    pub fn bind(&self, query: Query) -> Result<Query, QueryError> {
+        let locator = Locator::new(&query.sql);
+        for table in &query.tables {
+            if !self
+                .schemas
+                .get(table)
+                .is_some_and(|schema| schema.exists())
+            {
+                return Err(QueryError::UnknownTable {
+                    table: table.clone(),
+                    span: locator.find(table),
+                });
+            }
+        }
+
+        let mut bound = query.clone();
+        bound.rows = query
+            .rows
+            .iter()
+            .map(|row| self.bind_column(&query, row, &locator))
+            .collect::<Result<Vec<_>, _>>()?;
+        if bound.rows.is_empty() {
+            // `SELECT *` reads every column of every table
+            bound.rows = query
+                .tables
+                .iter()
+                .flat_map(|table| {
+                    self.schemas[table]
+                        .columns
+                        .iter()
+                        .map(move |column| RowProperty {
+                            table: table.clone(),
+                            row: column.name.clone(),
+                        })
+                })
+                .collect();
+        }
+        bound.conditions = query
+            .conditions
+            .iter()
+            .map(|condition| self.bind_condition(&query, condition, &locator))
+            .collect::<Result<Vec<_>, _>>()?;
+        bound.joins = query
+            .joins
+            .iter()
+            .map(|join| self.bind_join(&query, join, &locator))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(bound)
+    }
+
+    fn bind_column(
+        &self,
+        query: &Query,
+        row: &RowProperty,
+        locator: &Locator,
+    ) -> Result<RowProperty, QueryError> {
+        let span = locator.find(&row.to_string());
+        if !row.table.is_empty() {
+            return match self.schemas.get(&row.table) {
+                Some(schema) if schema.column(&row.row).is_some() => Ok(row.clone()),
+                Some(_) => Err(QueryError::UnknownColumn {
+                    column: row.to_string(),
+                    span,
+                }),
+                None => Err(QueryError::UnknownTable {
+                    table: row.table.clone(),
+                    span,
+                }),
+            };
+        }
+
+        // Unqualified columns have to be unique among all tables of the query
+        let tables = query
+            .tables
+            .iter()
+            .filter(|table| {
+                self.schemas
+                    .get(*table)
+                    .is_some_and(|schema| schema.column(&row.row).is_some())
+            })
+            .collect::<Vec<_>>();
+        match tables.as_slice() {
+            [table] => Ok(RowProperty {
+                table: table.to_string(),
+                row: row.row.clone(),
+            }),
+            [] => Err(QueryError::UnknownColumn {
+                column: row.to_string(),
+                span,
+            }),
+            _ => Err(QueryError::Unsupported {
+                message: format!(
+                    "Column {} is ambiguous, it exists in {}",
+                    row.row,
+                    tables
+                        .iter()
+                        .map(|table| table.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                span,
+            }),
+        }
+    }
+
+    fn column(&self, row: &RowProperty) -> &Column {
+        // Only called for bound columns
+        self.schemas[&row.table].column(&row.row).unwrap()
+    }
+
+    fn bind_condition(
+        &self,
+        query: &Query,
+        condition: &WhereCondition,
+        locator: &Locator,
+    ) -> Result<WhereCondition, QueryError> {
+        let left = self.bind_column(query, &condition.left, locator)?;
+        let column = self.column(&left);
+        let category = TypeCategory::of(column);
+        let compatible = match condition.op.as_str() {
+            // The filter compares ordering operators numerically
+            ">" | "<" | ">=" | "<=" => {
+                category == TypeCategory::Numeric
+                    && (condition.placeholder.is_some() || condition.right.parse::<f64>().is_ok())
+            }
+            // The argument of a placeholder is checked when the template is instantiated
+            _ if condition.placeholder.is_some() => true,
+            _ => match category {
+                TypeCategory::Numeric => condition.right.parse::<f64>().is_ok(),
+                TypeCategory::Bool => condition.right == "true" || condition.right == "false",
+                _ => true,
+            },
+        };
+        if !compatible {
+            return Err(QueryError::TypeMismatch {
+                message: format!(
+                    "{} of type {} can't be compared with '{}' using {}",
+                    left,
+                    column.data_type,
+                    condition.right,
+                    condition.op
+                ),
+                span: locator.find(&condition.left.to_string()),
+            });
+        }
+        Ok(WhereCondition {
+            left,
+            op: condition.op.clone(),
+            right: condition.right.clone(),
+            placeholder: condition.placeholder,
+        })
+    }
+
+    fn bind_join(
+        &self,
+        query: &Query,
+        join: &JoinCondition,
+        locator: &Locator,
+    ) -> Result<JoinCondition, QueryError> {
+        let bound = JoinCondition {
+            left: self.bind_column(query, &join.left, locator)?,
+            operator: join.operator.clone(),
+            right: self.bind_column(query, &join.right, locator)?,
+        };
+        let span = locator.find(&join.left.to_string());
+        // Only known once unqualified columns are bound, `id = "buyerId"` may compare two tables
+        if bound.left.table == bound.right.table {
+            return Err(QueryError::Unsupported {
+                message: format!("{} must compare columns of two different tables", bound),
+                span: locator.find(&join.to_string()),
+            });
+        }
+
+        let left = self.column(&bound.left);
+        let right = self.column(&bound.right);
+        let (left_category, right_category) = (TypeCategory::of(left), TypeCategory::of(right));
+        let compatible = match (left_category, right_category) {
+            (TypeCategory::Other, TypeCategory::Other) => left.data_type == right.data_type,
+            (left_category, right_category) => left_category == right_category,
+        };
+        if !compatible {
+            return Err(QueryError::TypeMismatch {
+                message: format!(
+                    "{} of type {} can't be compared with {} of type {}",
+                    bound.left,
+                    left.data_type,
+                    bound.right,
+                    right.data_type
+                ),
+                span,
+            });
+        }
+
+        // Equi joins are keyed lookups, a missing key or index usually means a wrong column.
+        // Range joins are commonly done on plain columns, so they only get a warning.
+        for side in [&bound.left, &bound.right] {
+            if self.schemas[&side.table].is_keyed_or_indexed(&side.row) {
+                continue;
+            }
+            if bound.is_equi() {
+                return Err(QueryError::Unindexed {
+                    column: side.to_string(),
+                    span,
+                });
+            }
+            warn!(
+                "Join column {} is neither a key nor indexed",
+                side.to_string()
+            );
+        }
+        Ok(bound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::parser::parse_query;
+    use crate::pg_client::schema::{Key, KeyType};
+
+    fn table(columns: &[(&str, &str)], key: &str, indexed: &[&str]) -> TableSchema {
+        TableSchema {
+            columns: columns
+                .iter()
+                .map(|(name, data_type)| Column {
+                    name: name.to_string(),
+                    data_type: data_type.to_string(),
+                })
+                .collect(),
+            keys: vec![Key {
+                column_name: key.to_string(),
+                key_type: KeyType::PrimaryKey,
+            }],
+            indexed_columns: indexed.iter().map(|column| column.to_string()).collect(),
+        }
+    }
+
+    fn binder() -> Binder {
+        let users = table(&[("id", "integer"), ("name", "text")], "id", &[]);
+        let orders = table(
+            &[
+                ("orderId", "integer"),
+                ("buyerId", "integer"),
+                ("total", "numeric"),
+            ],
+            "orderId",
+            &["buyerId"],
+        );
+        Binder::new(HashMap::from([
+            ("User".to_string(), users),
+            ("Order".to_string(), orders),
+        ]))
+    }
+
+    fn bind(sql: &str) -> Result<Query, QueryError> {
+        binder().bind(parse_query(sql).unwrap())
+    }
+
+    #[test]
+    fn binds_unqualified_join_columns_of_different_tables() {
+        let query =
+            bind(r#"SELECT name, total FROM "User", "Order" WHERE id = "buyerId""#).unwrap();
+        assert_eq!(query.joins.len(), 1);
+        assert_eq!(query.joins[0].to_string(), "User.id = Order.buyerId");
+    }
+
+    #[test]
+    fn rejects_joins_comparing_columns_of_one_table() {
+        let sql = r#"SELECT * FROM "User", "Order" WHERE "orderId" = "buyerId""#;
+        match bind(sql) {
+            Err(QueryError::Unsupported { message, span }) => {
+                assert!(message.contains("two different tables"), "{}", message);
+                assert_eq!(span.map(|span| span.column), Some(37));
+            }
+            result => panic!("Expected an unsupported join, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_tables() {
+        match bind(r#"SELECT id FROM "User", "Invoice""#) {
+            Err(QueryError::UnknownTable { table, span }) => {
+                assert_eq!(table, "Invoice");
+                assert_eq!(span.map(|span| span.column), Some(24));
+            }
+            result => panic!("Expected an unknown table, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_columns() {
+        match bind(r#"SELECT "User".email FROM "User""#) {
+            Err(QueryError::UnknownColumn { column, span }) => {
+                assert_eq!(column, "User.email");
+                assert_eq!(span.map(|span| span.column), Some(8));
+            }
+            result => panic!("Expected an unknown column, got {:?}", result),
+        }
+        assert!(matches!(
+            bind(r#"SELECT * FROM "User" WHERE email = 'a@b.c'"#),
+            Err(QueryError::UnknownColumn { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_ambiguous_unqualified_columns() {
+        let mut binder = binder();
+        binder
+            .schemas
+            .insert("Refund".to_string(), table(&[("id", "integer")], "id", &[]));
+        let query = parse_query(r#"SELECT id FROM "User", "Refund""#).unwrap();
+        assert!(matches!(
+            binder.bind(query),
+            Err(QueryError::Unsupported { message, .. }) if message.contains("ambiguous")
+        ));
+    }
+
+    #[test]
+    fn rejects_literals_of_the_wrong_type() {
+        match bind(r#"SELECT * FROM "Order" WHERE total = 'many'"#) {
+            Err(QueryError::TypeMismatch { span, .. }) => {
+                assert_eq!(span.map(|span| span.column), Some(29));
+            }
+            result => panic!("Expected a type mismatch, got {:?}", result),
+        }
+        assert!(matches!(
+            bind(r#"SELECT * FROM "User" WHERE name > 5"#),
+            Err(QueryError::TypeMismatch { .. })
+        ));
+        assert!(bind(r#"SELECT * FROM "User" WHERE name = 'Ada'"#).is_ok());
+    }
+
+    #[test]
+    fn rejects_joins_of_columns_with_different_types() {
+        let sql = r#"SELECT * FROM "User" JOIN "Order" ON "User".name = "Order"."buyerId""#;
+        match bind(sql) {
+            Err(QueryError::TypeMismatch { message, .. }) => {
+                assert!(message.contains("of type text"), "{}", message);
+            }
+            result => panic!("Expected a type mismatch, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_equi_joins_on_columns_without_key_or_index() {
+        let sql = r#"SELECT * FROM "User" JOIN "Order" ON "User".id = "Order".total"#;
+        assert!(matches!(
+            bind(sql),
+            Err(QueryError::Unindexed { column, .. }) if column == "Order.total"
+        ));
+    }
+}
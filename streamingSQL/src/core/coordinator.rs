@@ -1,45 +1,314 @@
 use std::collections::HashMap;
 
-use tokio::task;
-use tracing::{debug, info};
+use tokio::sync::watch;
 
-use crate::core::parser::parse_query;
+use crate::core::binder::Binder;
+use crate::core::error::QueryError;
+use crate::core::explain::{explain_physical, explain_plan, explain_query, ExplainStage};
+use crate::core::parser::{parse_query, parse_statement, stable_hash, Query, ViewStatement};
+use crate::core::plan::LogicalPlan;
+use crate::core::template::ViewTemplate;
+use crate::core::view::{inline_views, SchemaChangePolicy, ViewDefinition};
 
 use crate::core::types::source::Source;
-use crate::pg_client::data::WalEvent;
-use crate::pg_client::schema::get_keys_for_table;
-use crate::pg_client::stream::start_streaming_changes;
+use crate::pg_client::catalog::{find_view, record_view, CatalogEntry};
+use crate::pg_client::publication::TableRead;
+use crate::pg_client::replication::StreamStatus;
+use crate::pg_client::schema::{get_table_schema, quote_identifier, QualifiedName, TableSchema};
+use crate::pg_client::stream::ReplicationStream;
 
 use super::planer::QueryPlaner;
 
-pub struct Coordinator {}
+pub struct Coordinator {
+    templates: HashMap<String, ViewTemplate>,
+    views: HashMap<String, ViewDefinition>,
+    stream: ReplicationStream,
+}
 
 impl Coordinator {
     pub fn new() -> Self {
-        Coordinator {}
+        Coordinator {
+            templates: HashMap::new(),
+            views: HashMap::new(),
+            stream: ReplicationStream::new(),
+        }
     }
 
-    pub async fn process_view_query(
-        &mut self,
+    // Condition of the replication stream feeding the views, and the views that stopped
+    pub fn status(&self) -> watch::Receiver<StreamStatus> {
+        self.stream.status()
+    }
+
+    // Drop the replication slot and publication of the views, which otherwise outlive them
+    pub async fn teardown(&mut self) -> Result<(), tokio_postgres::Error> {
+        self.stream.teardown().await
+    }
+
+    // Validate a parsed query against the source database and the views created so far
+    async fn bind(
+        &self,
+        query_info: Query,
+    ) -> Result<(Query, HashMap<String, TableSchema>), QueryError> {
+        let mut schemas = HashMap::new();
+        for table in query_info.tables.clone() {
+            let schema = match self.views.get(&table) {
+                Some(view) => view.schema.clone(),
+                None => get_table_schema(table.to_string()).await?,
+            };
+            schemas.insert(table, schema);
+        }
+        let query_info = Binder::new(schemas.clone()).bind(query_info)?;
+        Ok((query_info, schemas))
+    }
+
+    async fn bind_query(
+        &self,
         query: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let query_str = query.to_string();
-        let query_info = parse_query(&query_str).unwrap();
-
-        let mut source = Source::new();
-        let mut table_identities = HashMap::new();
-        for table in &query_info.tables {
-            let table_name = table.to_string();
-            let (tx, rx) = tokio::sync::broadcast::channel::<Vec<WalEvent>>(10000);
-            task::spawn(async { start_streaming_changes(tx, table_name).await });
-            source.insert(table.to_string(), rx);
-            table_identities.insert(
-                table.to_string(),
-                get_keys_for_table(table.to_string()).await.unwrap(),
-            );
+    ) -> Result<(Query, HashMap<String, TableSchema>), QueryError> {
+        self.bind(parse_query(query)?).await
+    }
+
+    // Plan a bound query, reading the views it refers to from their plans
+    fn plan(&self, query_info: &Query) -> LogicalPlan {
+        inline_views(QueryPlaner::new().plan(query_info), &self.views)
+    }
+
+    // Record the sink table of a view in the catalog. A view keeps its table across restarts,
+    // but a name can't be reused for a different query.
+    async fn record(
+        &self,
+        name: &str,
+        table_name: &str,
+        query_info: &Query,
+    ) -> Result<(), QueryError> {
+        let query = query_info.normalized();
+        let entry = CatalogEntry {
+            name: name.to_string(),
+            table_name: table_name.to_string(),
+            query_hash: format!("{:016x}", stable_hash(&query)),
+            query,
+        };
+        if let Some(existing) = find_view(name).await? {
+            if existing.query_hash != entry.query_hash {
+                return Err(QueryError::Unsupported {
+                    message: format!(
+                        "View {} already exists for another query: {}",
+                        name, existing.query
+                    ),
+                    span: None,
+                });
+            }
+        }
+        record_view(&entry).await?;
+        Ok(())
+    }
+
+    // A SELECT starts a dataflow writing to `view_<hash of the query>`. `CREATE VIEW` only
+    // defines a view, materialized views are started together by `start_defined_views`.
+    pub async fn process_view_query(&mut self, query: &str) -> Result<(), QueryError> {
+        match parse_statement(query)? {
+            ViewStatement::Select(query_info) => {
+                // Validate the query before replicating anything
+                let (query_info, _) = self.bind(query_info).await?;
+                let plan = self.plan(&query_info);
+                let table_name = query_info.to_table_string();
+                self.record(&table_name, &table_name, &query_info).await?;
+                self.start_views(vec![(table_name, plan, SchemaChangePolicy::default())])
+                    .await?;
+            }
+            ViewStatement::CreateView {
+                name,
+                columns,
+                query: query_info,
+                materialized,
+                options,
+            } => {
+                if self.views.contains_key(&name) {
+                    return Err(QueryError::Unsupported {
+                        message: format!("View {} already exists", name),
+                        span: None,
+                    });
+                }
+                let on_schema_change = SchemaChangePolicy::from_options(&options)?;
+                let (query_info, schemas) = self.bind(query_info).await?;
+                let plan = self.plan(&query_info);
+                let table_name = materialized.then(|| name.clone());
+                let view = ViewDefinition::new(
+                    name.clone(),
+                    table_name.clone(),
+                    columns,
+                    &query_info,
+                    plan,
+                    &schemas,
+                    on_schema_change,
+                )?;
+                if let Some(table_name) = table_name {
+                    self.record(&name, &table_name, &query_info).await?;
+                }
+                self.views.insert(name, view);
+            }
         }
-        let planer = QueryPlaner::new(table_identities);
-        planer.build_dataflow(query_info, source).await;
         Ok(())
     }
+
+    // Run all materialized views in one dataflow, each writing to the table named like it
+    pub async fn start_defined_views(&mut self) -> Result<(), QueryError> {
+        let views = self
+            .views
+            .values()
+            .filter_map(|view| {
+                Some((
+                    view.table_name.clone()?,
+                    view.plan.clone(),
+                    view.on_schema_change,
+                ))
+            })
+            .collect();
+        self.start_views(views).await
+    }
+
+    // Bind and plan a view query with `$n` placeholders, views are created by instantiating it
+    pub async fn register_template(&mut self, name: &str, query: &str) -> Result<(), QueryError> {
+        let (query_info, schemas) = self.bind_query(query).await?;
+        let plan = self.plan(&query_info);
+        self.templates.insert(
+            name.to_string(),
+            ViewTemplate::new(name.to_string(), query_info, plan, schemas),
+        );
+        Ok(())
+    }
+
+    // Create one view per argument list of a template. The views run in a single dataflow,
+    // sharing their inputs and the arrangements of the parts of the plan they have in common.
+    pub async fn instantiate_template(
+        &mut self,
+        name: &str,
+        arguments: Vec<Vec<String>>,
+    ) -> Result<(), QueryError> {
+        let template =
+            self.templates
+                .get(name)
+                .cloned()
+                .ok_or_else(|| QueryError::Unsupported {
+                    message: format!("Unknown view template: {}", name),
+                    span: None,
+                })?;
+        let mut views = vec![];
+        for arguments in &arguments {
+            let instance = template.instantiate(arguments)?;
+            self.record(&instance.table_name, &instance.table_name, &instance.query)
+                .await?;
+            views.push((
+                instance.table_name,
+                instance.plan,
+                SchemaChangePolicy::default(),
+            ));
+        }
+        self.start_views(views).await
+    }
+
+    // Describe how the view would be built, without creating it
+    pub async fn explain(
+        &mut self,
+        stage: ExplainStage,
+        query: &str,
+    ) -> Result<String, QueryError> {
+        let (query_info, _) = self.bind_query(query).await?;
+        let plan = self.plan(&query_info);
+
+        let mut sections = vec![explain_query(&query_info), explain_plan(&plan)];
+        if stage == ExplainStage::Physical {
+            sections.push(explain_physical(&query_info.to_table_string(), &plan));
+        }
+        Ok(sections.join("\n"))
+    }
+
+    // Subscribe to the tables of the plans and run the dataflow writing each plan to its sink
+    // table. The subscription ends with the dataflow, the stream keeps its slot for the next
+    // views.
+    async fn start_views(
+        &mut self,
+        views: Vec<(String, LogicalPlan, SchemaChangePolicy)>,
+    ) -> Result<(), QueryError> {
+        let mut tables: Vec<String> = vec![];
+        for (_, plan, _) in &views {
+            for table in plan.source_tables() {
+                if !tables.contains(&table) {
+                    tables.push(table);
+                }
+            }
+        }
+        let reads = tables
+            .iter()
+            .map(|table| table_read(table, views.iter().map(|(_, plan, _)| plan)))
+            .collect::<Vec<_>>();
+        let subscription = self.stream.subscribe(&reads).await?;
+        self.stream.start().await?;
+        // Sink tables are named like their views, which may be schema qualified or mixed case
+        let views = views
+            .into_iter()
+            .map(|(table_name, plan, on_schema_change)| {
+                (
+                    QualifiedName::parse(&table_name).quoted(),
+                    plan,
+                    on_schema_change,
+                )
+            })
+            .collect();
+        let built = QueryPlaner::new()
+            .build_dataflow(
+                views,
+                Source::new(subscription.receiver),
+                subscription.progress,
+                subscription.status,
+            )
+            .await;
+        self.stream.unsubscribe(subscription.id).await?;
+        Ok(built?)
+    }
+}
+
+// The rows and columns of a source table the plans read. Filters become SQL conditions of the
+// publication, so the literals are compared the way Postgres casts them.
+fn table_read<'a>(table: &str, plans: impl Iterator<Item = &'a LogicalPlan>) -> TableRead {
+    plans
+        .filter(|plan| plan.source_tables().iter().any(|name| name == table))
+        .map(|plan| {
+            let filters = plan.source_filters(table);
+            let mut filter_columns = filters
+                .iter()
+                .flatten()
+                .flatten()
+                .map(|condition| condition.left.row.clone())
+                .collect::<Vec<_>>();
+            filter_columns.sort();
+            filter_columns.dedup();
+            let row_filter = filters.map(|filters| {
+                filters
+                    .iter()
+                    .map(|conditions| {
+                        let conditions = conditions
+                            .iter()
+                            .map(|condition| {
+                                format!(
+                                    "{} {} '{}'",
+                                    quote_identifier(&condition.left.row),
+                                    condition.op,
+                                    condition.right.replace('\'', "''")
+                                )
+                            })
+                            .collect::<Vec<_>>();
+                        format!("({})", conditions.join(" AND "))
+                    })
+                    .collect()
+            });
+            TableRead {
+                table: table.to_string(),
+                row_filter,
+                filter_columns,
+                columns: plan.source_columns(table),
+            }
+        })
+        .reduce(|left, right| left.union(&right))
+        .unwrap_or_else(|| TableRead::all(table))
 }
@@ -0,0 +1,252 @@
+use std::fmt;
+
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::ParserError;
+use sqlparser::tokenizer::{Token, TokenWithLocation, Tokenizer};
+
+// Position inside the query text, both starting from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: u64,
+    pub column: u64,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum QueryError {
+    // The query text is not valid SQL
+    Syntax { message: String, span: Option<Span> },
+    // Valid SQL that the service can't (yet) turn into a dataflow
+    Unsupported { message: String, span: Option<Span> },
+    UnknownTable { table: String, span: Option<Span> },
+    UnknownColumn { column: String, span: Option<Span> },
+    TypeMismatch { message: String, span: Option<Span> },
+    // Join column that is neither part of a key nor of an index in the source table
+    Unindexed { column: String, span: Option<Span> },
+    // The source database could not be queried while preparing the view
+    Catalog { message: String },
+}
+
+impl QueryError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            QueryError::Syntax { span, .. }
+            | QueryError::Unsupported { span, .. }
+            | QueryError::UnknownTable { span, .. }
+            | QueryError::UnknownColumn { span, .. }
+            | QueryError::TypeMismatch { span, .. }
+            | QueryError::Unindexed { span, .. } => *span,
+            QueryError::Catalog { .. } => None,
+        }
+    }
+
+    // Render the error together with the offending line of the query and a marker below it
+    pub fn report(&self, sql: &str) -> String {
+        let span = match self.span() {
+            Some(span) => span,
+            None => return self.to_string(),
+        };
+        let line = sql
+            .lines()
+            .nth(span.line.saturating_sub(1) as usize)
+            .unwrap_or("");
+        format!(
+            "{}\n  {}\n  {}^",
+            self,
+            line,
+            " ".repeat(span.column.saturating_sub(1) as usize)
+        )
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Syntax { message, .. } => write!(f, "Syntax error: {}", message)?,
+            QueryError::Unsupported { message, .. } => write!(f, "Unsupported: {}", message)?,
+            QueryError::UnknownTable { table, .. } => write!(f, "Unknown table: {}", table)?,
+            QueryError::UnknownColumn { column, .. } => write!(f, "Unknown column: {}", column)?,
+            QueryError::TypeMismatch { message, .. } => write!(f, "Type mismatch: {}", message)?,
+            QueryError::Unindexed { column, .. } => {
+                write!(f, "Join column is neither a key nor indexed: {}", column)?
+            }
+            QueryError::Catalog { message } => write!(f, "Catalog error: {}", message)?,
+        }
+        if let Some(span) = self.span() {
+            write!(f, " at {}", span)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl From<ParserError> for QueryError {
+    fn from(error: ParserError) -> Self {
+        let message = match error {
+            ParserError::TokenizerError(message) | ParserError::ParserError(message) => message,
+            ParserError::RecursionLimitExceeded => "recursion limit exceeded".to_string(),
+        };
+        // sqlparser appends the location as " at Line: <line>, Column: <column>"
+        match message.rsplit_once(" at Line: ") {
+            Some((text, location)) => {
+                let span = location
+                    .split_once(", Column: ")
+                    .and_then(|(line, column)| {
+                        Some(Span {
+                            line: line.parse().ok()?,
+                            column: column.parse().ok()?,
+                        })
+                    });
+                QueryError::Syntax {
+                    message: text.to_string(),
+                    span,
+                }
+            }
+            None => QueryError::Syntax {
+                message,
+                span: None,
+            },
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for QueryError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        QueryError::Catalog {
+            message: error.to_string(),
+        }
+    }
+}
+
+// Finds where fragments of the AST are located in the original query text
+pub struct Locator {
+    tokens: Vec<TokenWithLocation>,
+}
+
+impl Locator {
+    pub fn new(sql: &str) -> Self {
+        Locator {
+            tokens: tokenize(sql),
+        }
+    }
+
+    // Span of the first occurrence of `fragment`, e.g. the `to_string()` of an expression
+    pub fn find(&self, fragment: &str) -> Option<Span> {
+        let needle = tokenize(fragment)
+            .into_iter()
+            .map(|token| token.token)
+            .collect::<Vec<_>>();
+        if needle.is_empty() || needle.len() > self.tokens.len() {
+            return None;
+        }
+        self.tokens
+            .windows(needle.len())
+            .find(|window| {
+                window
+                    .iter()
+                    .zip(needle.iter())
+                    .all(|(token, expected)| same_token(&token.token, expected))
+            })
+            .map(|window| Span {
+                line: window[0].location.line,
+                column: window[0].location.column,
+            })
+    }
+}
+
+fn tokenize(sql: &str) -> Vec<TokenWithLocation> {
+    let dialect = PostgreSqlDialect {};
+    Tokenizer::new(&dialect, sql)
+        .tokenize_with_location()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|token| !matches!(token.token, Token::Whitespace(_)))
+        .collect()
+}
+
+// Identifiers match regardless of their quoting, `"Order".total` is found for `Order.total`
+fn same_token(token: &Token, expected: &Token) -> bool {
+    match (token, expected) {
+        (Token::Word(word), Token::Word(expected)) => word.value == expected.value,
+        _ => token == expected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::parser::parse_query;
+
+    #[test]
+    fn syntax_errors_take_the_location_reported_by_the_parser() {
+        let error = parse_query("SELECT id\nFROM users WHERE id = = 1").unwrap_err();
+        assert!(matches!(error, QueryError::Syntax { .. }), "{:?}", error);
+        assert_eq!(
+            error.span(),
+            Some(Span {
+                line: 2,
+                column: 23
+            })
+        );
+    }
+
+    #[test]
+    fn unsupported_clauses_point_at_their_text() {
+        let error = parse_query("SELECT id FROM users GROUP BY id").unwrap_err();
+        assert!(
+            matches!(error, QueryError::Unsupported { .. }),
+            "{:?}",
+            error
+        );
+        assert_eq!(
+            error.span(),
+            Some(Span {
+                line: 1,
+                column: 22
+            })
+        );
+    }
+
+    #[test]
+    fn report_marks_the_column_below_the_offending_line() {
+        let sql = "SELECT id\nFROM users u";
+        let error = parse_query(sql).unwrap_err();
+        assert_eq!(
+            error.report(sql),
+            "Unsupported: Table aliases are not supported: u at line 2, column 12\n  \
+             FROM users u\n             ^"
+        );
+    }
+
+    #[test]
+    fn errors_without_a_span_are_reported_on_their_own() {
+        let error = QueryError::Catalog {
+            message: "connection refused".to_string(),
+        };
+        assert_eq!(
+            error.report("SELECT 1"),
+            "Catalog error: connection refused"
+        );
+    }
+
+    #[test]
+    fn locator_finds_identifiers_whatever_their_quoting() {
+        let locator = Locator::new("SELECT \"Order\".total\nFROM \"Order\"");
+        assert_eq!(
+            locator.find("Order.total"),
+            Some(Span { line: 1, column: 8 })
+        );
+        assert_eq!(locator.find("Order"), Some(Span { line: 1, column: 8 }));
+        assert_eq!(
+            locator.find("FROM Order"),
+            Some(Span { line: 2, column: 1 })
+        );
+        assert_eq!(locator.find("missing"), None);
+    }
+}
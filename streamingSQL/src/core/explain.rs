@@ -0,0 +1,256 @@
+use crate::core::parser::Query;
+use crate::core::plan::{LogicalPlan, Predicate};
+use crate::core::planer::{describe_operators, OperatorNode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExplainStage {
+    // Parsed query and logical plan
+    Logical,
+    // Additionally the operators the planer builds for the plan
+    Physical,
+}
+
+// Split `EXPLAIN [PHYSICAL] <query>` into the stage and the explained query
+pub fn parse_explain(sql: &str) -> Option<(ExplainStage, &str)> {
+    let query = strip_keyword(sql.trim_start(), "EXPLAIN")?;
+    match strip_keyword(query, "PHYSICAL") {
+        Some(query) => Some((ExplainStage::Physical, query)),
+        None => Some((ExplainStage::Logical, query)),
+    }
+}
+
+fn strip_keyword<'a>(sql: &'a str, keyword: &str) -> Option<&'a str> {
+    let (word, rest) = sql.split_at(sql.find(char::is_whitespace)?);
+    if word.eq_ignore_ascii_case(keyword) {
+        Some(rest.trim_start())
+    } else {
+        None
+    }
+}
+
+fn line(lines: &mut Vec<String>, depth: usize, text: String) {
+    lines.push(format!("{}{}", "  ".repeat(depth), text));
+}
+
+fn list(items: Vec<String>) -> String {
+    items.join(", ")
+}
+
+pub fn explain_query(query: &Query) -> String {
+    let mut lines = vec!["Query".to_string()];
+    describe_query(query, 1, &mut lines);
+    lines.join("\n")
+}
+
+fn describe_query(query: &Query, depth: usize, lines: &mut Vec<String>) {
+    line(
+        lines,
+        depth,
+        format!("tables: {}", list(query.tables.clone())),
+    );
+    line(
+        lines,
+        depth,
+        format!("columns: {}", list(query.output_columns())),
+    );
+    if !query.joins.is_empty() {
+        let joins = query.joins.iter().map(|join| join.to_string()).collect();
+        line(lines, depth, format!("join conditions: {}", list(joins)));
+    }
+    if !query.conditions.is_empty() {
+        let conditions = query
+            .conditions
+            .iter()
+            .map(|condition| Predicate::Literal(condition.clone()).to_string())
+            .collect();
+        line(lines, depth, format!("filters: {}", list(conditions)));
+    }
+}
+
+pub fn explain_plan(plan: &LogicalPlan) -> String {
+    let mut lines = vec!["Logical plan".to_string()];
+    describe_plan(plan, 1, &mut lines);
+    lines.join("\n")
+}
+
+fn describe_plan(plan: &LogicalPlan, depth: usize, lines: &mut Vec<String>) {
+    match plan {
+        LogicalPlan::Get { table, columns } => {
+            let columns = match columns {
+                Some(columns) => list(columns.clone()),
+                None => "all columns".to_string(),
+            };
+            line(lines, depth, format!("Get {} ({})", table, columns));
+        }
+        LogicalPlan::Filter { input, predicates } => {
+            let predicates = predicates
+                .iter()
+                .map(|predicate| predicate.to_string())
+                .collect();
+            line(lines, depth, format!("Filter {}", list(predicates)));
+            describe_plan(input, depth + 1, lines);
+        }
+        LogicalPlan::Project { input, columns } => {
+            line(lines, depth, format!("Project {}", projection(columns)));
+            describe_plan(input, depth + 1, lines);
+        }
+        LogicalPlan::Join {
+            left,
+            right,
+            conditions,
+        } => {
+            if conditions.is_empty() {
+                line(lines, depth, "Join (cross product)".to_string());
+            } else {
+                let conditions = conditions.iter().map(|join| join.to_string()).collect();
+                line(lines, depth, format!("Join on {}", list(conditions)));
+            }
+            describe_plan(left, depth + 1, lines);
+            describe_plan(right, depth + 1, lines);
+        }
+        LogicalPlan::View {
+            name,
+            input,
+            columns,
+        } => {
+            line(
+                lines,
+                depth,
+                format!("View {} ({})", name, projection(columns)),
+            );
+            describe_plan(input, depth + 1, lines);
+        }
+    }
+}
+
+pub fn projection(columns: &[(String, String)]) -> String {
+    list(
+        columns
+            .iter()
+            .map(|(column, name)| {
+                if column == name {
+                    column.clone()
+                } else {
+                    format!("{} AS {}", column, name)
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Describe the timely and differential operators built for the plan, outermost first.
+///
+/// The renderer builds the operators of the view in a dataflow that never runs and reports
+/// each of them. Arrangements hold state for the lifetime of the view and usually decide how
+/// expensive it is.
+pub fn explain_physical(table_name: &str, plan: &LogicalPlan) -> String {
+    let mut lines = vec!["Physical plan".to_string()];
+    describe_operator(&describe_operators(table_name, plan), 1, &mut lines);
+    lines.join("\n")
+}
+
+fn describe_operator(operator: &OperatorNode, depth: usize, lines: &mut Vec<String>) {
+    if operator.shared {
+        line(lines, depth, format!("{} (shared)", operator.description));
+        return;
+    }
+    line(lines, depth, operator.description.clone());
+    for input in &operator.inputs {
+        describe_operator(input, depth + 1, lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::optimizer::optimize;
+    use crate::core::parser::parse_query;
+
+    const BIG_ORDERS: &str = r#"SELECT "User".name, "Order".total FROM "User", "Order"
+        WHERE "User".id = "Order"."buyerId" AND "Order".total > 600"#;
+
+    fn plan(sql: &str) -> LogicalPlan {
+        optimize(LogicalPlan::from_query(&parse_query(sql).unwrap()))
+    }
+
+    #[test]
+    fn parses_the_explained_stage() {
+        assert_eq!(
+            parse_explain("explain SELECT 1"),
+            Some((ExplainStage::Logical, "SELECT 1"))
+        );
+        assert_eq!(
+            parse_explain("  EXPLAIN PHYSICAL\n SELECT 1"),
+            Some((ExplainStage::Physical, "SELECT 1"))
+        );
+        assert_eq!(
+            parse_explain("EXPLAIN physicalities"),
+            Some((ExplainStage::Logical, "physicalities"))
+        );
+        assert_eq!(parse_explain("EXPLAINED SELECT 1"), None);
+        assert_eq!(parse_explain("SELECT 1"), None);
+    }
+
+    #[test]
+    fn explains_the_parsed_query() {
+        assert_eq!(
+            explain_query(&parse_query(BIG_ORDERS).unwrap()),
+            "Query
+  tables: User, Order
+  columns: User.name, Order.total
+  join conditions: User.id = Order.buyerId
+  filters: Order.total > '600'"
+        );
+    }
+
+    #[test]
+    fn explains_the_logical_plan() {
+        assert_eq!(
+            explain_plan(&plan(BIG_ORDERS)),
+            "Logical plan
+  Project User.name, Order.total
+    Join on Order.buyerId = User.id
+      Filter Order.total > '600'
+        Get Order (buyerId, total)
+      Get User (id, name)"
+        );
+    }
+
+    #[test]
+    fn explains_the_operators_of_the_plan() {
+        assert_eq!(
+            explain_physical("big_orders", &plan(BIG_ORDERS)),
+            "Physical plan
+  sink: write completed times to big_orders
+    consolidate: arrangement of the view records
+      map: project User.name, Order.total
+        filter: Order.buyerId = User.id
+          join_core: arrangements of both inputs by key
+            arrange_by_key: key by hash(Order.buyerId)
+              filter: Order.total > '600'
+                map: read Order (buyerId, total) with qualified names
+                  input: Order
+            arrange_by_key: key by hash(User.id)
+              map: read User (id, name) with qualified names
+                input: User"
+        );
+    }
+
+    #[test]
+    fn explains_range_joins_by_their_indexed_condition() {
+        let budgets =
+            plan(r#"SELECT "User".name FROM "User", "Order" WHERE "Order".total > "User".budget"#);
+        assert_eq!(
+            explain_physical("budgets", &budgets),
+            "Physical plan
+  sink: write completed times to budgets
+    consolidate: arrangement of the view records
+      map: project User.name
+        RangeJoin: ordered indexes on User.budget and Order.total, checks User.budget < Order.total
+          map: read User (budget, name) with qualified names
+            input: User
+          map: read Order (total) with qualified names
+            input: Order"
+        );
+    }
+}
@@ -1,5 +1,13 @@
+pub mod binder;
 pub mod coordinator;
+pub mod error;
+pub mod explain;
+pub mod optimizer;
 pub mod parser;
+pub mod plan;
 pub mod planer;
+pub mod range_join;
 pub mod sink;
+pub mod template;
 pub mod types;
+pub mod view;
@@ -0,0 +1,330 @@
+use std::cmp::Reverse;
+
+use crate::core::parser::JoinCondition;
+use crate::core::plan::{LogicalPlan, Predicate};
+
+/// Rewrite a logical plan into the one that is turned into a dataflow.
+///
+/// Joins are reordered first, so that the following pushdown can move every predicate to
+/// the lowest join or table it refers to. Pruning runs last and restricts the tables to the
+/// columns that are still read above them.
+pub fn optimize(plan: LogicalPlan) -> LogicalPlan {
+    let plan = order_joins(plan);
+    let plan = push_down_predicates(plan, vec![]);
+    prune_columns(plan, None)
+}
+
+fn order_joins(plan: LogicalPlan) -> LogicalPlan {
+    match plan {
+        LogicalPlan::Filter { .. } | LogicalPlan::Join { .. } => {
+            let mut relations = vec![];
+            let mut predicates = vec![];
+            collect_join_block(plan, &mut relations, &mut predicates);
+            let relations = relations.into_iter().map(order_joins).collect();
+            let plan = join_greedy(relations, &mut predicates);
+            plan.filter(predicates)
+        }
+        LogicalPlan::Project { input, columns } => LogicalPlan::Project {
+            input: Box::new(order_joins(*input)),
+            columns,
+        },
+        plan => plan,
+    }
+}
+
+// Flatten a tree of joins and filters into its relations and all predicates between them
+fn collect_join_block(
+    plan: LogicalPlan,
+    relations: &mut Vec<LogicalPlan>,
+    predicates: &mut Vec<Predicate>,
+) {
+    match plan {
+        LogicalPlan::Filter {
+            input,
+            predicates: own,
+        } => {
+            predicates.extend(own);
+            collect_join_block(*input, relations, predicates);
+        }
+        LogicalPlan::Join {
+            left,
+            right,
+            conditions,
+        } => {
+            predicates.extend(conditions.into_iter().map(Predicate::Columns));
+            collect_join_block(*left, relations, predicates);
+            collect_join_block(*right, relations, predicates);
+        }
+        relation => relations.push(relation),
+    }
+}
+
+// Build a left deep join tree. It starts with the relation restricted by most literal
+// predicates and then prefers relations connected by an equality, then by any condition, so
+// cross products only remain for relations that aren't connected at all. Ties keep the
+// order of the FROM clause. Predicates that become join conditions are removed.
+fn join_greedy(relations: Vec<LogicalPlan>, predicates: &mut Vec<Predicate>) -> LogicalPlan {
+    let filters = |relation: &LogicalPlan| {
+        let tables = relation.tables();
+        predicates
+            .iter()
+            .filter(|predicate| matches!(predicate, Predicate::Literal(_)))
+            .filter(|predicate| {
+                predicate
+                    .tables()
+                    .iter()
+                    .all(|table| tables.contains(table))
+            })
+            .count()
+    };
+    let mut remaining = relations
+        .into_iter()
+        .map(|relation| (filters(&relation), relation))
+        .collect::<Vec<_>>();
+
+    let start = (0..remaining.len())
+        .max_by_key(|&i| (remaining[i].0, Reverse(i)))
+        .expect("a join block has at least one relation");
+    let mut plan = remaining.remove(start).1;
+
+    while !remaining.is_empty() {
+        let joined = plan.tables();
+        let connection = |relation: &LogicalPlan| {
+            let tables = relation.tables();
+            predicates
+                .iter()
+                .filter_map(|predicate| predicate.join_condition(&joined, &tables))
+                .map(|condition| if condition.is_equi() { 2 } else { 1 })
+                .max()
+                .unwrap_or(0)
+        };
+        let next = (0..remaining.len())
+            .max_by_key(|&i| (connection(&remaining[i].1), remaining[i].0, Reverse(i)))
+            .unwrap();
+        let relation = remaining.remove(next).1;
+        let tables = relation.tables();
+
+        let mut conditions = vec![];
+        predicates.retain(
+            |predicate| match predicate.join_condition(&joined, &tables) {
+                Some(condition) => {
+                    conditions.push(condition);
+                    false
+                }
+                None => true,
+            },
+        );
+        plan = LogicalPlan::Join {
+            left: Box::new(plan),
+            right: Box::new(relation),
+            conditions,
+        };
+    }
+    plan
+}
+
+// Move each predicate down to the lowest node that provides all of its columns. Predicates
+// comparing both sides of a join become conditions of that join.
+fn push_down_predicates(plan: LogicalPlan, predicates: Vec<Predicate>) -> LogicalPlan {
+    match plan {
+        LogicalPlan::Filter {
+            input,
+            predicates: mut own,
+        } => {
+            own.extend(predicates);
+            push_down_predicates(*input, own)
+        }
+        LogicalPlan::Join {
+            left,
+            right,
+            mut conditions,
+        } => {
+            let (left_tables, right_tables) = (left.tables(), right.tables());
+            let mut left_predicates = vec![];
+            let mut right_predicates = vec![];
+            let mut remaining = vec![];
+            for predicate in predicates {
+                let tables = predicate.tables();
+                if tables.iter().all(|table| left_tables.contains(table)) {
+                    left_predicates.push(predicate);
+                } else if tables.iter().all(|table| right_tables.contains(table)) {
+                    right_predicates.push(predicate);
+                } else if let Some(condition) =
+                    predicate.join_condition(&left_tables, &right_tables)
+                {
+                    conditions.push(condition);
+                } else {
+                    remaining.push(predicate);
+                }
+            }
+            LogicalPlan::Join {
+                left: Box::new(push_down_predicates(*left, left_predicates)),
+                right: Box::new(push_down_predicates(*right, right_predicates)),
+                conditions,
+            }
+            .filter(remaining)
+        }
+        // Predicates above a projection refer to renamed columns and stay in place
+        LogicalPlan::Project { input, columns } => LogicalPlan::Project {
+            input: Box::new(push_down_predicates(*input, vec![])),
+            columns,
+        }
+        .filter(predicates),
+        plan @ (LogicalPlan::Get { .. } | LogicalPlan::View { .. }) => plan.filter(predicates),
+    }
+}
+
+// Restrict every table to the columns read above it, `None` if all of them are
+fn prune_columns(plan: LogicalPlan, required: Option<Vec<String>>) -> LogicalPlan {
+    match plan {
+        LogicalPlan::Get { table, columns } => {
+            let columns = match required {
+                Some(required) => {
+                    let prefix = format!("{}.", table);
+                    let mut columns = required
+                        .iter()
+                        .filter_map(|column| column.strip_prefix(&prefix))
+                        .map(|column| column.to_string())
+                        .collect::<Vec<_>>();
+                    columns.sort();
+                    columns.dedup();
+                    Some(columns)
+                }
+                None => columns,
+            };
+            LogicalPlan::Get { table, columns }
+        }
+        // The view's own plan is kept as it is, so it can be shared with the view's sink
+        LogicalPlan::View {
+            name,
+            input,
+            mut columns,
+        } => {
+            if let Some(required) = required {
+                columns.retain(|(_, column)| required.contains(column));
+            }
+            LogicalPlan::View {
+                name,
+                input,
+                columns,
+            }
+        }
+        LogicalPlan::Filter { input, predicates } => {
+            let required = required.map(|mut required| {
+                required.extend(predicates.iter().flat_map(|predicate| predicate.columns()));
+                required
+            });
+            LogicalPlan::Filter {
+                input: Box::new(prune_columns(*input, required)),
+                predicates,
+            }
+        }
+        LogicalPlan::Project { input, columns } => LogicalPlan::Project {
+            input: Box::new(prune_columns(
+                *input,
+                Some(columns.iter().map(|(column, _)| column.clone()).collect()),
+            )),
+            columns,
+        },
+        LogicalPlan::Join {
+            left,
+            right,
+            conditions,
+        } => {
+            let required = required.map(|mut required| {
+                required.extend(conditions.iter().flat_map(condition_columns));
+                required
+            });
+            LogicalPlan::Join {
+                left: Box::new(prune_columns(*left, required.clone())),
+                right: Box::new(prune_columns(*right, required)),
+                conditions,
+            }
+        }
+    }
+}
+
+fn condition_columns(condition: &JoinCondition) -> Vec<String> {
+    vec![condition.left.to_string(), condition.right.to_string()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::explain::explain_plan;
+    use crate::core::parser::parse_query;
+
+    // Columns are qualified in the queries, so they can be planned without binding them
+    fn optimized(sql: &str) -> String {
+        let plan = LogicalPlan::from_query(&parse_query(sql).unwrap());
+        explain_plan(&optimize(plan))
+    }
+
+    #[test]
+    fn pushes_literal_predicates_down_to_the_most_filtered_table() {
+        let plan = optimized(
+            r#"SELECT "User".name, "Order".total FROM "User", "Order"
+            WHERE "User".id = "Order"."buyerId" AND "Order".total > 600 AND "Order".paid = true"#,
+        );
+        assert_eq!(
+            plan,
+            "Logical plan
+  Project User.name, Order.total
+    Join on Order.buyerId = User.id
+      Filter Order.total > '600', Order.paid = 'true'
+        Get Order (buyerId, paid, total)
+      Get User (id, name)"
+        );
+    }
+
+    #[test]
+    fn orders_joins_along_their_conditions_and_prunes_the_columns() {
+        let plan = optimized(
+            r#"SELECT "User".name FROM "User", "Item", "Order"
+            WHERE "Item"."orderId" = "Order"."orderId" AND "Order"."buyerId" = "User".id"#,
+        );
+        assert_eq!(
+            plan,
+            "Logical plan
+  Project User.name
+    Join on Order.orderId = Item.orderId
+      Join on User.id = Order.buyerId
+        Get User (id, name)
+        Get Order (buyerId, orderId)
+      Get Item (orderId)"
+        );
+    }
+
+    #[test]
+    fn cross_products_remain_for_unconnected_tables() {
+        let plan = optimized(
+            r#"SELECT "User".name, "Item".sku FROM "User", "Item", "Order"
+            WHERE "Order"."buyerId" = "User".id"#,
+        );
+        assert_eq!(
+            plan,
+            "Logical plan
+  Project User.name, Item.sku
+    Join (cross product)
+      Join on User.id = Order.buyerId
+        Get User (id, name)
+        Get Order (buyerId)
+      Get Item (sku)"
+        );
+    }
+
+    #[test]
+    fn range_predicates_between_two_tables_become_join_conditions() {
+        let plan = optimized(
+            r#"SELECT "User".name FROM "User", "Order" WHERE "Order".total > "User".budget"#,
+        );
+        assert_eq!(
+            plan,
+            "Logical plan
+  Project User.name
+    Join on User.budget < Order.total
+      Get User (budget, name)
+      Get Order (total)"
+        );
+    }
+}
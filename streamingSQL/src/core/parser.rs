@@ -1,43 +1,96 @@
-use std::collections::hash_map::DefaultHasher;
+use std::fmt;
 
-use serde_json::de;
-use sqlparser::ast::Expr::{self, BinaryOp, CompoundIdentifier, Identifier, Value};
-use sqlparser::ast::SetExpr::Select;
-use sqlparser::ast::{JoinOperator, Statement, TableFactor, TableWithJoins};
+use sqlparser::ast::Expr::{
+    self, Between, BinaryOp, CompoundIdentifier, Identifier, Nested, Value,
+};
+use sqlparser::ast::{
+    BinaryOperator, CreateTableOptions, GroupByExpr, JoinConstraint, JoinOperator, ObjectName,
+    SetExpr, Statement, TableFactor, TableWithJoins,
+};
 use sqlparser::dialect::PostgreSqlDialect;
 use sqlparser::parser::Parser;
-use std::hash::Hash;
-use std::hash::Hasher;
-use tokio_postgres::Row;
+
+use super::error::{Locator, QueryError, Span};
 
 #[derive(Debug, Clone)]
 pub struct Query {
+    // Original query text, used to locate errors
+    pub sql: String,
     pub tables: Vec<String>,
     pub rows: Vec<RowProperty>,
-    pub condition: Option<WhereCondition>,
+    pub conditions: Vec<WhereCondition>,
     pub joins: Vec<JoinCondition>,
 }
 
 impl Query {
-    pub fn to_table_string(&self) -> String {
-        let mut table_name = "".to_string();
-        table_name.push_str(&self.tables.join(", "));
-        table_name.push_str(&self.joins.iter().fold("".to_string(), |acc, join| {
-            format!(
-                " {} JOIN {} ON {} {} {}",
-                acc,
-                join.right.table,
-                join.left.to_string(),
-                join.operator,
-                join.right.to_string()
+    // Names of the view columns
+    pub fn output_columns(&self) -> Vec<String> {
+        self.rows.iter().map(|row| row.to_string()).collect()
+    }
+
+    // Number of arguments a view template needs, the highest `$n` placeholder
+    pub fn parameters(&self) -> usize {
+        self.conditions
+            .iter()
+            .filter_map(|condition| condition.placeholder)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Substitute the placeholders of a view template
+    pub fn with_arguments(&self, arguments: &[String]) -> Query {
+        let mut query = self.clone();
+        query.conditions = self
+            .conditions
+            .iter()
+            .map(|condition| condition.with_arguments(arguments))
+            .collect();
+        query
+    }
+
+    // Canonical text of the bound query. Queries that only differ in formatting or in the
+    // order of their tables and predicates have the same text.
+    pub fn normalized(&self) -> String {
+        let mut tables = self.tables.clone();
+        tables.sort();
+        let mut predicates = self
+            .joins
+            .iter()
+            .map(|join| join.to_string())
+            .chain(
+                self.conditions
+                    .iter()
+                    .map(|condition| condition.to_string()),
             )
-        }));
-        let mut hasher = DefaultHasher::new();
-        table_name.hash(&mut hasher);
-        let table_name = hasher.finish();
+            .collect::<Vec<_>>();
+        predicates.sort();
 
-        return format!("view_{}", table_name);
+        let mut sql = format!(
+            "SELECT {} FROM {}",
+            self.output_columns().join(", "),
+            tables.join(", ")
+        );
+        if !predicates.is_empty() {
+            sql.push_str(&format!(" WHERE {}", predicates.join(" AND ")));
+        }
+        sql
+    }
+
+    // Sink table of a view created without a name
+    pub fn to_table_string(&self) -> String {
+        format!("view_{:016x}", stable_hash(&self.normalized()))
+    }
+}
+
+// FNV-1a, unlike `DefaultHasher` it gives the same hash in every build, so names derived
+// from it survive restarts and upgrades
+pub fn stable_hash(text: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
     }
+    hash
 }
 
 #[derive(Debug, Clone)]
@@ -48,6 +101,39 @@ pub struct JoinCondition {
     pub right: RowProperty,
 }
 
+impl JoinCondition {
+    pub fn is_equi(&self) -> bool {
+        self.operator == "="
+    }
+
+    // Swap both sides of the condition, mirroring the comparison so it keeps its meaning
+    pub fn flipped(&self) -> JoinCondition {
+        JoinCondition {
+            left: self.right.clone(),
+            operator: mirror_operator(&self.operator),
+            right: self.left.clone(),
+        }
+    }
+}
+
+impl fmt::Display for JoinCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.left, self.operator, self.right)
+    }
+}
+
+// Operator that keeps the meaning of a comparison when its operands are swapped
+pub fn mirror_operator(operator: &str) -> String {
+    match operator {
+        "<" => ">",
+        "<=" => ">=",
+        ">" => "<",
+        ">=" => "<=",
+        op => op,
+    }
+    .to_string()
+}
+
 #[derive(Debug, Clone)]
 
 pub struct RowProperty {
@@ -55,12 +141,12 @@ pub struct RowProperty {
     pub row: String,
 }
 
-impl RowProperty {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for RowProperty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.table.is_empty() {
-            return self.row.to_string();
+            write!(f, "{}", self.row)
         } else {
-            return format!("{}.{}", self.table, self.row);
+            write!(f, "{}.{}", self.table, self.row)
         }
     }
 }
@@ -71,169 +157,672 @@ pub struct WhereCondition {
     pub left: RowProperty,
     pub op: String,
     pub right: String,
+    // Position of the `$n` placeholder standing in for `right` in a view template
+    pub placeholder: Option<usize>,
 }
 
-pub fn parse_query(sql: &str) -> Result<Query, String> {
-    let dialect = PostgreSqlDialect {};
-    let ast = Parser::parse_sql(&dialect, sql).unwrap();
-    let mut tables: Vec<String> = vec![];
-    let statement = &ast[0];
-    match statement {
-        Statement::Query(ref query) => {
-            let body = if let Select(select) = *query.body.clone() {
-                select
-            } else {
-                return Err("Unsupported query type".to_string());
-            };
+// Literals are quoted, placeholders are kept as `$n`
+impl fmt::Display for WhereCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.placeholder {
+            Some(_) => write!(f, "{} {} {}", self.left, self.op, self.right),
+            None => write!(f, "{} {} '{}'", self.left, self.op, self.right),
+        }
+    }
+}
+
+impl WhereCondition {
+    // Replace the placeholder by its argument, arguments are numbered from 1
+    pub fn with_arguments(&self, arguments: &[String]) -> WhereCondition {
+        match self.placeholder {
+            Some(position) => WhereCondition {
+                left: self.left.clone(),
+                op: self.op.clone(),
+                right: arguments[position - 1].clone(),
+                placeholder: None,
+            },
+            None => self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ViewStatement {
+    // A query whose result is written to a sink table
+    Select(Query),
+    // `CREATE [MATERIALIZED] VIEW name [(columns)] [WITH (options)] AS query`, later views
+    // can read it like a table. Only materialized views are written to a sink table, which is
+    // named like them.
+    CreateView {
+        name: String,
+        columns: Vec<String>,
+        query: Query,
+        materialized: bool,
+        // Pairs of lowercase option name and value
+        options: Vec<(String, String)>,
+    },
+}
 
-            let rows = parse_projection(body.projection);
-            tables = parse_from(body.from.clone());
-            let joins = parse_joins(body.from);
-            let condition = parse_condition(body.selection);
+pub fn parse_query(sql: &str) -> Result<Query, QueryError> {
+    match parse_statement(sql)? {
+        ViewStatement::Select(query) => Ok(query),
+        ViewStatement::CreateView { .. } => Err(QueryError::Unsupported {
+            message: "Expected a SELECT query".to_string(),
+            span: None,
+        }),
+    }
+}
 
-            let query = Query {
-                tables,
-                rows,
-                joins,
-                condition,
+// `WITH (name = value)` options of a view, values are identifiers or strings
+fn parse_view_options(
+    options: &CreateTableOptions,
+    locator: &Locator,
+) -> Result<Vec<(String, String)>, QueryError> {
+    let options = match options {
+        CreateTableOptions::None => return Ok(vec![]),
+        CreateTableOptions::With(options) => options,
+        options => {
+            return Err(QueryError::Unsupported {
+                message: format!("Unsupported view options: {}", options),
+                span: locator.find(&options.to_string()),
+            })
+        }
+    };
+    options
+        .iter()
+        .map(|option| {
+            let value = match &option.value {
+                Identifier(ident) => ident.value.clone(),
+                Value(sqlparser::ast::Value::SingleQuotedString(value)) => value.clone(),
+                value => {
+                    return Err(QueryError::Unsupported {
+                        message: format!("Unsupported value of view option: {}", option),
+                        span: locator.find(&value.to_string()),
+                    })
+                }
             };
-            Ok(query)
+            Ok((option.name.value.to_lowercase(), value))
+        })
+        .collect()
+}
+
+pub fn parse_statement(sql: &str) -> Result<ViewStatement, QueryError> {
+    let dialect = PostgreSqlDialect {};
+    let ast = Parser::parse_sql(&dialect, sql)?;
+    let locator = Locator::new(sql);
+    let statement = match ast.as_slice() {
+        [statement] => statement,
+        [] => {
+            return Err(QueryError::Syntax {
+                message: "Expected a query".to_string(),
+                span: None,
+            })
         }
         _ => {
-            return Err("Unsupported query type".to_string());
+            return Err(QueryError::Unsupported {
+                message: "Only a single statement can be used as view".to_string(),
+                span: None,
+            })
         }
+    };
+    match statement {
+        Statement::Query(ref query) => Ok(ViewStatement::Select(parse_body(
+            *query.body.clone(),
+            sql,
+            &locator,
+        )?)),
+        Statement::CreateView {
+            or_replace: false,
+            materialized,
+            name,
+            columns,
+            query,
+            options,
+            ..
+        } => Ok(ViewStatement::CreateView {
+            name: parse_table_name(name, &locator)?,
+            columns: columns
+                .iter()
+                .map(|column| column.name.value.clone())
+                .collect(),
+            query: parse_body(*query.body.clone(), sql, &locator)?,
+            materialized: *materialized,
+            options: parse_view_options(options, &locator)?,
+        }),
+        Statement::CreateView { .. } => Err(QueryError::Unsupported {
+            message: "Only CREATE [MATERIALIZED] VIEW name AS query is supported".to_string(),
+            span: locator.find("CREATE"),
+        }),
+        statement => Err(QueryError::Unsupported {
+            message: "Only SELECT queries and CREATE VIEW are supported".to_string(),
+            span: locator.find(&statement.to_string()),
+        }),
     }
 }
 
-fn parse_projection(select: Vec<sqlparser::ast::SelectItem>) -> Vec<RowProperty> {
-    let mut rows: Vec<RowProperty> = vec![];
-    select
-        .iter()
-        .for_each(|p: &sqlparser::ast::SelectItem| match p {
-            sqlparser::ast::SelectItem::UnnamedExpr(ref expr) => match expr {
-                Identifier(ident) => {
-                    let row = RowProperty {
-                        table: "".to_string(),
-                        row: ident.value.clone(),
-                    };
-                    rows.push(row);
-                }
-                CompoundIdentifier(expr) => {
-                    let row = RowProperty {
-                        table: expr[0].value.clone(),
-                        row: expr[1].value.clone(),
-                    };
-                    rows.push(row);
-                }
-                _ => {}
-            },
-            _ => {}
+fn parse_body(body: SetExpr, sql: &str, locator: &Locator) -> Result<Query, QueryError> {
+    match body {
+        SetExpr::Select(select) => parse_select(*select, sql, locator),
+        SetExpr::Query(query) => parse_body(*query.body, sql, locator),
+        body => Err(QueryError::Unsupported {
+            message: "Only SELECT queries are supported".to_string(),
+            span: locator.find(&body.to_string()),
+        }),
+    }
+}
+
+fn parse_select(
+    select: sqlparser::ast::Select,
+    sql: &str,
+    locator: &Locator,
+) -> Result<Query, QueryError> {
+    if let Some(having) = select.having {
+        return Err(QueryError::Unsupported {
+            message: "HAVING is not supported".to_string(),
+            span: locator.find(&having.to_string()),
+        });
+    }
+    if let Some(distinct) = select.distinct {
+        return Err(QueryError::Unsupported {
+            message: format!("{} is not supported", distinct),
+            span: locator.find(&distinct.to_string()),
+        });
+    }
+    match select.group_by {
+        GroupByExpr::Expressions(expressions, _) if expressions.is_empty() => {}
+        group_by => {
+            return Err(QueryError::Unsupported {
+                message: "GROUP BY is not supported".to_string(),
+                span: locator.find(&group_by.to_string()),
+            })
+        }
+    }
+
+    let rows = parse_projection(select.projection, locator)?;
+    let tables = parse_from(select.from.clone(), locator)?;
+    if tables.is_empty() {
+        return Err(QueryError::Unsupported {
+            message: "A view has to read at least one table".to_string(),
+            span: None,
         });
-    rows
+    }
+    let mut joins = parse_joins(select.from, locator)?;
+    // Predicates comparing columns of two tables are join conditions,
+    // e.g. `FROM "User", "Order" WHERE "User".id = "Order"."buyerId"`
+    let (conditions, mut where_joins) = parse_selection(select.selection, locator)?;
+    joins.append(&mut where_joins);
+
+    let mut query = Query {
+        sql: sql.to_string(),
+        tables,
+        rows,
+        joins,
+        conditions,
+    };
+    resolve_references(&mut query, locator)?;
+    Ok(query)
 }
 
-fn parse_from(from: Vec<TableWithJoins>) -> Vec<String> {
-    let mut tables: Vec<String> = vec![];
-    from.iter().for_each(|from| {
-        match from.relation.clone() {
-            TableFactor::Table { name, alias, .. } => {
-                tables.push(name.to_string().trim_matches('"').to_string());
+// Every qualified column has to belong to a table of the FROM clause. Columns may name a
+// table without its schema, `events.id` refers to `analytics.events` unless that is ambiguous.
+fn resolve_references(query: &mut Query, locator: &Locator) -> Result<(), QueryError> {
+    let tables = query.tables.clone();
+    let mut columns = query.rows.iter_mut().collect::<Vec<_>>();
+    for join in &mut query.joins {
+        columns.push(&mut join.left);
+        columns.push(&mut join.right);
+    }
+    for condition in &mut query.conditions {
+        columns.push(&mut condition.left);
+    }
+    for column in columns {
+        if column.table.is_empty() || tables.contains(&column.table) {
+            continue;
+        }
+        let suffix = format!(".{}", column.table);
+        let matching = tables
+            .iter()
+            .filter(|table| table.ends_with(&suffix))
+            .collect::<Vec<_>>();
+        match matching.as_slice() {
+            [table] => column.table = table.to_string(),
+            [] => {
+                return Err(QueryError::UnknownTable {
+                    table: column.table.clone(),
+                    span: locator.find(&column.to_string()),
+                })
+            }
+            _ => {
+                return Err(QueryError::Unsupported {
+                    message: format!(
+                        "Table reference {} is ambiguous, qualify it with its schema",
+                        column.table
+                    ),
+                    span: locator.find(&column.to_string()),
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_projection(
+    select: Vec<sqlparser::ast::SelectItem>,
+    locator: &Locator,
+) -> Result<Vec<RowProperty>, QueryError> {
+    let mut rows: Vec<RowProperty> = vec![];
+    for p in select.iter() {
+        match p {
+            sqlparser::ast::SelectItem::UnnamedExpr(ref expr) => {
+                rows.push(parse_column(expr.clone(), locator)?)
+            }
+            // All columns are selected
+            sqlparser::ast::SelectItem::Wildcard(_) => {}
+            item => {
+                return Err(QueryError::Unsupported {
+                    message: format!("Unsupported projection: {}", item),
+                    span: locator.find(&item.to_string()),
+                })
             }
-            _ => {}
         }
+    }
+    Ok(rows)
+}
 
-        from.joins
-            .iter()
-            .for_each(|join| match join.relation.clone() {
-                TableFactor::Table { name, alias, .. } => {
-                    tables.push(name.to_string().trim_matches('"').to_string());
-                }
-                _ => {}
-            });
-    });
-    tables
+fn parse_from(from: Vec<TableWithJoins>, locator: &Locator) -> Result<Vec<String>, QueryError> {
+    let mut tables: Vec<String> = vec![];
+    let mut relations = vec![];
+    for from in from.iter() {
+        relations.push(from.relation.clone());
+        for join in from.joins.iter() {
+            relations.push(join.relation.clone());
+        }
+    }
+    for relation in relations {
+        match relation {
+            TableFactor::Table {
+                name, alias: None, ..
+            } => {
+                tables.push(parse_table_name(&name, locator)?);
+            }
+            TableFactor::Table {
+                alias: Some(alias), ..
+            } => {
+                return Err(QueryError::Unsupported {
+                    message: format!("Table aliases are not supported: {}", alias),
+                    span: locator.find(&alias.to_string()),
+                })
+            }
+            relation => {
+                return Err(QueryError::Unsupported {
+                    message: format!("Only tables can be used in FROM: {}", relation),
+                    span: locator.find(&relation.to_string()),
+                })
+            }
+        }
+    }
+    Ok(tables)
 }
 
-fn parse_joins(from: Vec<sqlparser::ast::TableWithJoins>) -> Vec<JoinCondition> {
+fn parse_joins(
+    from: Vec<sqlparser::ast::TableWithJoins>,
+    locator: &Locator,
+) -> Result<Vec<JoinCondition>, QueryError> {
     let mut joins: Vec<JoinCondition> = Vec::new();
-    from.iter().for_each(|from: &TableWithJoins| {
-        if !from.joins.is_empty() {
-            from.joins.iter().for_each(|join: &sqlparser::ast::Join| {
-                let join_constraint = match join.join_operator.clone() {
-                    JoinOperator::Inner(constraint) => (constraint),
-                    _ => return,
-                };
-
-                let on = match join_constraint {
-                    sqlparser::ast::JoinConstraint::On(expr) => expr,
-                    _ => return,
-                };
+    for from in from.iter() {
+        for join in from.joins.iter() {
+            let join_constraint = match join.join_operator.clone() {
+                JoinOperator::Inner(constraint) => constraint,
+                // The tables are combined as a cross join, restricted by the WHERE clause
+                JoinOperator::CrossJoin => continue,
+                _ => {
+                    return Err(QueryError::Unsupported {
+                        message: "Only inner and cross joins are supported".to_string(),
+                        span: locator.find(&join.relation.to_string()),
+                    })
+                }
+            };
 
-                match on {
-                    BinaryOp { left, op, right } => {
-                        let left = match *left {
-                            Identifier(ident) => ["".to_string(), ident.value.to_string()],
-                            CompoundIdentifier(expr) => {
-                                [expr[0].value.clone(), expr[1].value.clone()]
-                            }
-                            _ => ["".to_string(), "".to_string()],
-                        };
-                        let right = match *right {
-                            Identifier(ident) => ["".to_string(), ident.value.to_string()],
-                            CompoundIdentifier(expr) => {
-                                [expr[0].value.clone(), expr[1].value.clone()]
-                            }
-                            _ => ["".to_string(), "".to_string()],
-                        };
-                        let join = JoinCondition {
-                            left: RowProperty {
-                                table: left[0].clone(),
-                                row: left[1].clone(),
-                            },
-                            operator: op.to_string(),
-                            right: RowProperty {
-                                table: right[0].clone(),
-                                row: right[1].clone(),
-                            },
-                        };
-                        joins.push(join);
-                    }
-                    _ => {}
+            let on = match join_constraint {
+                JoinConstraint::On(expr) => expr,
+                _ => {
+                    return Err(QueryError::Unsupported {
+                        message: "Only ON is supported as join constraint".to_string(),
+                        span: locator.find(&join.relation.to_string()),
+                    })
                 }
-            });
+            };
+
+            for predicate in split_conjunction(on) {
+                joins.append(&mut parse_join_predicate(predicate, locator)?);
+            }
         }
-    });
-    joins
+    }
+    Ok(joins)
 }
 
-fn parse_condition(selection: Option<Expr>) -> Option<WhereCondition> {
-    if selection.is_none() {
-        return None;
+// Flatten `a AND b AND c` into its individual predicates
+fn split_conjunction(expr: Expr) -> Vec<Expr> {
+    match expr {
+        BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            let mut predicates = split_conjunction(*left);
+            predicates.append(&mut split_conjunction(*right));
+            predicates
+        }
+        Nested(inner) => split_conjunction(*inner),
+        expr => vec![expr],
     }
-    match selection.unwrap() {
-        BinaryOp { left, op, right } => {
-            let left = match *left {
-                Identifier(ident) => ["".to_string(), ident.value.to_string()],
-                CompoundIdentifier(expr) => [expr[0].value.clone(), expr[1].value.clone()],
-                _ => ["".to_string(), "".to_string()],
-            };
-            let right = match *right {
-                Value(val) => match val {
-                    sqlparser::ast::Value::Number(num, _) => num.to_string(),
-                    sqlparser::ast::Value::Boolean(b) => b.to_string(),
-                    _ => "".to_string(),
+}
+
+fn parse_join_predicate(expr: Expr, locator: &Locator) -> Result<Vec<JoinCondition>, QueryError> {
+    let span = locator.find(&expr.to_string());
+    match expr {
+        BinaryOp { left, op, right } => match op {
+            BinaryOperator::Eq
+            | BinaryOperator::Lt
+            | BinaryOperator::LtEq
+            | BinaryOperator::Gt
+            | BinaryOperator::GtEq => Ok(vec![JoinCondition {
+                left: parse_column(*left, locator)?,
+                operator: op.to_string(),
+                right: parse_column(*right, locator)?,
+            }]),
+            op => Err(QueryError::Unsupported {
+                message: format!("Unsupported join operator: {}", op),
+                span,
+            }),
+        },
+        // `a BETWEEN b AND c` is the conjunction of `a >= b` and `a <= c`
+        Between {
+            expr,
+            negated: false,
+            low,
+            high,
+        } => {
+            let column = parse_column(*expr, locator)?;
+            Ok(vec![
+                JoinCondition {
+                    left: column.clone(),
+                    operator: ">=".to_string(),
+                    right: parse_column(*low, locator)?,
                 },
-                _ => "".to_string(),
-            };
-            return Some(WhereCondition {
-                left: RowProperty {
-                    table: left[0].clone(),
-                    row: left[1].clone(),
+                JoinCondition {
+                    left: column,
+                    operator: "<=".to_string(),
+                    right: parse_column(*high, locator)?,
                 },
-                op: op.to_string(),
-                right,
-            });
+            ])
         }
-        _ => return None,
+        expr => Err(QueryError::Unsupported {
+            message: format!("Unsupported join condition: {}", expr),
+            span,
+        }),
+    }
+}
+
+// Tables are named `schema.table`, except for those of the `public` schema which keep their
+// bare name, e.g. `analytics.events` and `"User"`
+fn parse_table_name(name: &ObjectName, locator: &Locator) -> Result<String, QueryError> {
+    let unsupported = |message: String| QueryError::Unsupported {
+        message,
+        span: locator.find(&name.to_string()),
+    };
+    if name.0.iter().any(|ident| ident.value.contains('.')) {
+        return Err(unsupported(format!(
+            "Table names containing a dot are not supported: {}",
+            name
+        )));
+    }
+    match name.0.as_slice() {
+        [table] => Ok(table.value.clone()),
+        [schema, table] if schema.value == "public" => Ok(table.value.clone()),
+        [schema, table] => Ok(format!("{}.{}", schema.value, table.value)),
+        _ => Err(unsupported(format!(
+            "Expected a table or schema.table, found: {}",
+            name
+        ))),
+    }
+}
+
+fn parse_column(expr: Expr, locator: &Locator) -> Result<RowProperty, QueryError> {
+    match expr {
+        Identifier(ident) => Ok(RowProperty {
+            table: "".to_string(),
+            row: ident.value.to_string(),
+        }),
+        CompoundIdentifier(expr) if expr.len() == 2 => Ok(RowProperty {
+            table: expr[0].value.clone(),
+            row: expr[1].value.clone(),
+        }),
+        CompoundIdentifier(expr) if expr.len() == 3 => Ok(RowProperty {
+            table: parse_table_name(&ObjectName(expr[..2].to_vec()), locator)?,
+            row: expr[2].value.clone(),
+        }),
+        expr => Err(QueryError::Unsupported {
+            message: format!("Expected a column reference, found: {}", expr),
+            span: locator.find(&expr.to_string()),
+        }),
+    }
+}
+
+fn parse_selection(
+    selection: Option<Expr>,
+    locator: &Locator,
+) -> Result<(Vec<WhereCondition>, Vec<JoinCondition>), QueryError> {
+    let mut conditions: Vec<WhereCondition> = vec![];
+    let mut joins: Vec<JoinCondition> = vec![];
+    let selection = match selection {
+        Some(selection) => selection,
+        None => return Ok((conditions, joins)),
     };
+
+    for predicate in split_conjunction(selection) {
+        let span = locator.find(&predicate.to_string());
+        match predicate {
+            BinaryOp { left, op, right } => {
+                let condition = match (parse_literal(&right), parse_literal(&left)) {
+                    // column <op> literal
+                    (Some(value), _) => WhereCondition {
+                        placeholder: parse_placeholder(&right),
+                        left: parse_column(*left, locator)?,
+                        op: op.to_string(),
+                        right: value,
+                    },
+                    // literal <op> column
+                    (None, Some(value)) => WhereCondition {
+                        placeholder: parse_placeholder(&left),
+                        left: parse_column(*right, locator)?,
+                        op: mirror_operator(&op.to_string()),
+                        right: value,
+                    },
+                    // column <op> column, the binder checks that both belong to different tables
+                    (None, None) => {
+                        let mut join = parse_join_predicate(BinaryOp { left, op, right }, locator)?;
+                        joins.append(&mut join);
+                        continue;
+                    }
+                };
+                check_condition(&condition, span)?;
+                conditions.push(condition);
+            }
+            predicate => {
+                return Err(QueryError::Unsupported {
+                    message: format!("Unsupported condition: {}", predicate),
+                    span,
+                })
+            }
+        }
+    }
+    Ok((conditions, joins))
+}
+
+// The filter compares ordering operators numerically, so their literal has to be a number.
+// Placeholders are checked once the template is instantiated.
+fn check_condition(condition: &WhereCondition, span: Option<Span>) -> Result<(), QueryError> {
+    match condition.op.as_str() {
+        "=" | "!=" | "<>" => Ok(()),
+        ">" | "<" | ">=" | "<=" if condition.placeholder.is_some() => Ok(()),
+        ">" | "<" | ">=" | "<=" => match condition.right.parse::<f64>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(QueryError::TypeMismatch {
+                message: format!(
+                    "{} {} '{}' requires a numeric value",
+                    condition.left,
+                    condition.op,
+                    condition.right
+                ),
+                span,
+            }),
+        },
+        op => Err(QueryError::Unsupported {
+            message: format!("Unsupported operator: {}", op),
+            span,
+        }),
+    }
+}
+
+fn parse_literal(expr: &Expr) -> Option<String> {
+    match expr {
+        Value(val) => match val {
+            sqlparser::ast::Value::Number(num, _) => Some(num.to_string()),
+            sqlparser::ast::Value::Boolean(b) => Some(b.to_string()),
+            sqlparser::ast::Value::SingleQuotedString(str) => Some(str.to_string()),
+            sqlparser::ast::Value::Placeholder(placeholder)
+                if parse_placeholder(expr).is_some() =>
+            {
+                Some(placeholder.to_string())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Position of a `$n` placeholder, positions start at 1
+fn parse_placeholder(expr: &Expr) -> Option<usize> {
+    match expr {
+        Value(sqlparser::ast::Value::Placeholder(placeholder)) => placeholder
+            .strip_prefix('$')?
+            .parse::<usize>()
+            .ok()
+            .filter(|position| *position > 0),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables(sql: &str) -> Vec<String> {
+        parse_query(sql).unwrap().tables
+    }
+
+    #[test]
+    fn tables_of_the_public_schema_keep_their_bare_name() {
+        assert_eq!(tables("SELECT * FROM users"), vec!["users"]);
+        assert_eq!(tables("SELECT * FROM public.users"), vec!["users"]);
+        assert_eq!(tables(r#"SELECT * FROM "User""#), vec!["User"]);
+    }
+
+    #[test]
+    fn tables_of_other_schemas_are_qualified() {
+        assert_eq!(
+            tables(r#"SELECT * FROM analytics.events, "Sales"."Order""#),
+            vec!["analytics.events", "Sales.Order"]
+        );
+    }
+
+    #[test]
+    fn rejects_table_names_that_cant_be_told_apart_from_a_schema() {
+        for sql in [
+            r#"SELECT * FROM "analytics.events""#,
+            "SELECT * FROM db.analytics.events",
+        ] {
+            assert!(
+                matches!(parse_query(sql), Err(QueryError::Unsupported { .. })),
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn columns_may_name_their_table_with_or_without_its_schema() {
+        let query = parse_query(
+            "SELECT analytics.events.id, events.kind FROM analytics.events
+            WHERE public.users.id = analytics.events.user_id",
+        );
+        // `public.users` isn't read by the query
+        assert!(matches!(query, Err(QueryError::UnknownTable { table, .. }) if table == "users"));
+
+        let query = parse_query(
+            "SELECT analytics.events.id, events.kind FROM analytics.events, users
+            WHERE public.users.id = events.user_id",
+        )
+        .unwrap();
+        assert_eq!(
+            query.output_columns(),
+            vec!["analytics.events.id", "analytics.events.kind"]
+        );
+        assert_eq!(
+            query.joins[0].to_string(),
+            "users.id = analytics.events.user_id"
+        );
+    }
+
+    #[test]
+    fn rejects_table_references_matching_several_schemas() {
+        let query = parse_query("SELECT events.id FROM analytics.events, audit.events");
+        assert!(
+            matches!(query, Err(QueryError::Unsupported { ref message, .. }) if message.contains("ambiguous")),
+            "{:?}",
+            query
+        );
+    }
+
+    #[test]
+    fn stable_hash_is_fnv_1a() {
+        assert_eq!(stable_hash(""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash("foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn queries_differing_in_formatting_and_order_share_their_sink_table() {
+        let query = parse_query(
+            r#"SELECT "User".name FROM "User", "Order"
+            WHERE "User".id = "Order"."buyerId" AND "Order".total > 600"#,
+        )
+        .unwrap();
+        let reordered = parse_query(
+            r#"select "User".name from "Order", "User" where "Order".total > 600
+            and "User".id = "Order"."buyerId""#,
+        )
+        .unwrap();
+        assert_eq!(
+            query.normalized(),
+            "SELECT User.name FROM Order, User WHERE Order.total > '600' AND User.id = Order.buyerId"
+        );
+        assert_eq!(query.normalized(), reordered.normalized());
+        assert_eq!(
+            query.to_table_string(),
+            format!("view_{:016x}", stable_hash(&query.normalized()))
+        );
+        assert_eq!(query.to_table_string(), reordered.to_table_string());
+    }
+
+    #[test]
+    fn different_queries_get_different_sink_tables() {
+        let query = parse_query(r#"SELECT "Order".id FROM "Order" WHERE "Order".total > 600"#);
+        let other = parse_query(r#"SELECT "Order".id FROM "Order" WHERE "Order".total > 700"#);
+        assert_ne!(
+            query.unwrap().to_table_string(),
+            other.unwrap().to_table_string()
+        );
+    }
 }
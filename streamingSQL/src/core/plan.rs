@@ -0,0 +1,325 @@
+use std::fmt;
+
+use serde_json::{Number, Value};
+
+use crate::core::parser::{JoinCondition, Query, WhereCondition};
+use crate::core::types::dataflow_types::{compare_values, operator_holds, DBRecord};
+
+// Columns are named `table.column` throughout the plan, projections rename them
+#[derive(Debug, Clone)]
+pub enum Predicate {
+    // Column compared with a literal, e.g. `"Order".total > 600`
+    Literal(WhereCondition),
+    // Two columns compared with each other, e.g. `"User".id = "Order"."buyerId"`
+    Columns(JoinCondition),
+}
+
+impl Predicate {
+    pub fn tables(&self) -> Vec<String> {
+        match self {
+            Predicate::Literal(condition) => vec![condition.left.table.clone()],
+            Predicate::Columns(condition) => {
+                vec![condition.left.table.clone(), condition.right.table.clone()]
+            }
+        }
+    }
+
+    pub fn columns(&self) -> Vec<String> {
+        match self {
+            Predicate::Literal(condition) => vec![condition.left.to_string()],
+            Predicate::Columns(condition) => {
+                vec![condition.left.to_string(), condition.right.to_string()]
+            }
+        }
+    }
+
+    // The predicate as join condition from the `left` to the `right` tables, if it compares
+    // a column of each side
+    pub fn join_condition(&self, left: &[String], right: &[String]) -> Option<JoinCondition> {
+        match self {
+            Predicate::Columns(condition)
+                if left.contains(&condition.left.table)
+                    && right.contains(&condition.right.table) =>
+            {
+                Some(condition.clone())
+            }
+            Predicate::Columns(condition)
+                if left.contains(&condition.right.table)
+                    && right.contains(&condition.left.table) =>
+            {
+                Some(condition.flipped())
+            }
+            _ => None,
+        }
+    }
+
+    // NULL never satisfies a comparison
+    pub fn evaluate(&self, record: &DBRecord) -> bool {
+        let (left, operator, right) = match self {
+            Predicate::Literal(condition) => {
+                let left = record.get(&condition.left.to_string());
+                let right = literal_value(&left, &condition.right);
+                (left, &condition.op, right)
+            }
+            Predicate::Columns(condition) => (
+                record.get(&condition.left.to_string()),
+                &condition.operator,
+                record.get(&condition.right.to_string()),
+            ),
+        };
+        compare_values(&left, &right).is_some_and(|ordering| operator_holds(operator, ordering))
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Predicate::Literal(condition) => write!(f, "{}", condition),
+            Predicate::Columns(condition) => write!(f, "{}", condition),
+        }
+    }
+}
+
+// Literals are kept as text by the parser, they are read with the type of the column
+fn literal_value(column: &Value, literal: &str) -> Value {
+    match column {
+        Value::Number(_) => literal
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map_or(Value::Null, Value::Number),
+        Value::Bool(_) => literal.parse::<bool>().map_or(Value::Null, Value::Bool),
+        _ => Value::String(literal.to_string()),
+    }
+}
+
+/// Relational plan of a view, built from a bound `Query` and rewritten by the optimizer
+/// before it is translated into a dataflow.
+#[derive(Debug, Clone)]
+pub enum LogicalPlan {
+    // Records of a source table, restricted to `columns` once they are pruned
+    Get {
+        table: String,
+        columns: Option<Vec<String>>,
+    },
+    Filter {
+        input: Box<LogicalPlan>,
+        predicates: Vec<Predicate>,
+    },
+    // Pairs of input column and output name, in the order of the SELECT list
+    Project {
+        input: Box<LogicalPlan>,
+        columns: Vec<(String, String)>,
+    },
+    // Conditions are oriented so their left side refers to the left input
+    Join {
+        left: Box<LogicalPlan>,
+        right: Box<LogicalPlan>,
+        conditions: Vec<JoinCondition>,
+    },
+    // Records of another view, whose plan is rendered once for all views reading it. Pairs
+    // of the view plan's column and its name here, `view.column`.
+    View {
+        name: String,
+        input: Box<LogicalPlan>,
+        columns: Vec<(String, String)>,
+    },
+}
+
+impl LogicalPlan {
+    // Straightforward plan of the query: a cross product of all tables, filtered by every
+    // predicate and projected to the selected columns
+    pub fn from_query(query: &Query) -> LogicalPlan {
+        let mut relations = query.tables.iter().map(|table| LogicalPlan::Get {
+            table: table.clone(),
+            columns: None,
+        });
+        let mut plan = relations.next().expect("a query reads at least one table");
+        for relation in relations {
+            plan = LogicalPlan::Join {
+                left: Box::new(plan),
+                right: Box::new(relation),
+                conditions: vec![],
+            };
+        }
+
+        let predicates = query
+            .joins
+            .iter()
+            .cloned()
+            .map(Predicate::Columns)
+            .chain(query.conditions.iter().cloned().map(Predicate::Literal))
+            .collect::<Vec<_>>();
+        LogicalPlan::Project {
+            input: Box::new(plan.filter(predicates)),
+            columns: query
+                .output_columns()
+                .into_iter()
+                .map(|column| (column.clone(), column))
+                .collect(),
+        }
+    }
+
+    // Wrap the plan in a filter, merging with an existing one
+    pub fn filter(self, mut predicates: Vec<Predicate>) -> LogicalPlan {
+        if predicates.is_empty() {
+            return self;
+        }
+        match self {
+            LogicalPlan::Filter {
+                input,
+                predicates: mut existing,
+            } => {
+                existing.append(&mut predicates);
+                LogicalPlan::Filter {
+                    input,
+                    predicates: existing,
+                }
+            }
+            plan => LogicalPlan::Filter {
+                input: Box::new(plan),
+                predicates,
+            },
+        }
+    }
+
+    // Substitute the placeholders of a view template, leaving the plan's shape as it is
+    pub fn with_arguments(&self, arguments: &[String]) -> LogicalPlan {
+        let bind = |input: &LogicalPlan| Box::new(input.with_arguments(arguments));
+        match self {
+            // Views can't have placeholders
+            LogicalPlan::Get { .. } | LogicalPlan::View { .. } => self.clone(),
+            LogicalPlan::Filter { input, predicates } => LogicalPlan::Filter {
+                input: bind(input),
+                predicates: predicates
+                    .iter()
+                    .map(|predicate| match predicate {
+                        Predicate::Literal(condition) => {
+                            Predicate::Literal(condition.with_arguments(arguments))
+                        }
+                        predicate => predicate.clone(),
+                    })
+                    .collect(),
+            },
+            LogicalPlan::Project { input, columns } => LogicalPlan::Project {
+                input: bind(input),
+                columns: columns.clone(),
+            },
+            LogicalPlan::Join {
+                left,
+                right,
+                conditions,
+            } => LogicalPlan::Join {
+                left: bind(left),
+                right: bind(right),
+                conditions: conditions.clone(),
+            },
+        }
+    }
+
+    // Tables and views read by the plan, each listed once
+    pub fn tables(&self) -> Vec<String> {
+        let mut tables = vec![];
+        self.collect_tables(&mut tables, false);
+        tables
+    }
+
+    // Tables replicated for the plan, including those read by its views
+    pub fn source_tables(&self) -> Vec<String> {
+        let mut tables = vec![];
+        self.collect_tables(&mut tables, true);
+        tables
+    }
+
+    // Columns of the source table `table` read by the plan, `None` if it reads all of them
+    pub fn source_columns(&self, table: &str) -> Option<Vec<String>> {
+        let mut columns = Some(vec![]);
+        self.collect_columns(table, &mut columns);
+        columns
+    }
+
+    fn collect_columns(&self, table: &str, read: &mut Option<Vec<String>>) {
+        match self {
+            LogicalPlan::Get {
+                table: name,
+                columns,
+            } if name == table => match (read.as_mut(), columns) {
+                (Some(read), Some(columns)) => read.extend(columns.iter().cloned()),
+                _ => *read = None,
+            },
+            LogicalPlan::Get { .. } => {}
+            LogicalPlan::Filter { input, .. }
+            | LogicalPlan::Project { input, .. }
+            | LogicalPlan::View { input, .. } => input.collect_columns(table, read),
+            LogicalPlan::Join { left, right, .. } => {
+                left.collect_columns(table, read);
+                right.collect_columns(table, read);
+            }
+        }
+    }
+
+    // Conditions on literals that filter the source table `table` right where it's read, one
+    // list per read. `None` if a read of it isn't filtered.
+    pub fn source_filters(&self, table: &str) -> Option<Vec<Vec<WhereCondition>>> {
+        let mut filters = Some(vec![]);
+        self.collect_filters(table, &mut filters);
+        filters
+    }
+
+    fn collect_filters(&self, table: &str, filters: &mut Option<Vec<Vec<WhereCondition>>>) {
+        match self {
+            LogicalPlan::Filter { input, predicates } if matches!(input.as_ref(), LogicalPlan::Get { table: name, .. } if name == table) =>
+            {
+                let conditions = predicates
+                    .iter()
+                    .filter_map(|predicate| match predicate {
+                        Predicate::Literal(condition)
+                            if condition.left.table == table && condition.placeholder.is_none() =>
+                        {
+                            Some(condition.clone())
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                match filters.as_mut() {
+                    Some(filters) if !conditions.is_empty() => filters.push(conditions),
+                    _ => *filters = None,
+                }
+            }
+            LogicalPlan::Get { table: name, .. } if name == table => *filters = None,
+            LogicalPlan::Get { .. } => {}
+            LogicalPlan::Filter { input, .. }
+            | LogicalPlan::Project { input, .. }
+            | LogicalPlan::View { input, .. } => input.collect_filters(table, filters),
+            LogicalPlan::Join { left, right, .. } => {
+                left.collect_filters(table, filters);
+                right.collect_filters(table, filters);
+            }
+        }
+    }
+
+    fn collect_tables(&self, tables: &mut Vec<String>, through_views: bool) {
+        match self {
+            LogicalPlan::Get { table, .. } => {
+                if !tables.contains(table) {
+                    tables.push(table.clone());
+                }
+            }
+            LogicalPlan::View { input, .. } if through_views => {
+                input.collect_tables(tables, through_views)
+            }
+            LogicalPlan::View { name, .. } => {
+                if !tables.contains(name) {
+                    tables.push(name.clone());
+                }
+            }
+            LogicalPlan::Filter { input, .. } | LogicalPlan::Project { input, .. } => {
+                input.collect_tables(tables, through_views)
+            }
+            LogicalPlan::Join { left, right, .. } => {
+                left.collect_tables(tables, through_views);
+                right.collect_tables(tables, through_views);
+            }
+        }
+    }
+}
@@ -1,269 +1,975 @@
-use crate::{
-    core::{
-        parser::RowProperty,
-        sink::Sink,
-        types::{
-            buffer::Buffer,
-            dataflow_types::{DBRecord, DataflowData, DataflowInput, Keys, RecordType},
-            inputs::InputSessions,
-            source::Source,
-        },
+use crate::core::{
+    explain::projection,
+    optimizer::optimize,
+    parser::JoinCondition,
+    plan::{LogicalPlan, Predicate},
+    range_join::range_join,
+    sink::{write_completed, Sink},
+    types::{
+        dataflow_types::{DBRecord, DataflowData, DataflowInput, SortKey},
+        flush::FlushTracker,
+        inputs::InputSessions,
+        source::Source,
     },
-    pg_client::schema::{Key, KeyType},
+    view::SchemaChangePolicy,
 };
-use std::{collections::HashMap, sync::Arc};
-use std::{sync::Mutex, thread};
+use crate::pg_client::data::{SchemaChange, Update, WalData, WalEvent};
+use crate::pg_client::progress::DataflowProgress;
+use crate::pg_client::replication::StreamStatus;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
 
 use super::parser::Query;
 extern crate differential_dataflow;
 extern crate timely;
 use crate::core::planer::differential_dataflow::operators::Consolidate;
-use crate::core::planer::differential_dataflow::operators::JoinCore;
-use differential_dataflow::operators::{arrange::Arrange, Join, Threshold};
+use differential_dataflow::input::Input;
+use differential_dataflow::operators::arrange::{ArrangeByKey, Arranged, TraceAgent};
+use differential_dataflow::operators::{Join, JoinCore};
+use differential_dataflow::trace::implementations::ord::OrdValSpine;
 use differential_dataflow::Collection;
-use differential_dataflow::{
-    operators::arrange::{ArrangeByKey, ArrangeBySelf},
-    trace::TraceReader,
-};
-use timely::{communication::Allocator, dataflow::scopes::Child};
-use timely::{dataflow::operators::Probe, worker::Worker as TimelyWorker};
+use timely::dataflow::operators::Probe;
+use timely::dataflow::{ProbeHandle, Scope};
+use tokio::sync::watch;
 use tracing::{debug, info, warn};
 
-pub struct QueryPlaner {
-    table_identities: HashMap<String, Vec<Key>>,
+pub struct QueryPlaner {}
+
+#[derive(Debug, Clone)]
+pub enum JoinStrategy {
+    // Hash join on the equality conditions, all conditions are checked on the joined records
+    Equi { keys: Vec<JoinCondition> },
+    // Ordered index join for range conditions, see `range_join`
+    Range { conditions: Vec<JoinCondition> },
+    // Cartesian product of both inputs
+    Cross,
 }
-type DBState = Arc<Mutex<HashMap<usize, (Option<usize>, DBRecord)>>>;
 
-impl QueryPlaner {
-    pub fn new(table_identities: HashMap<String, Vec<Key>>) -> Self {
-        QueryPlaner {
-            table_identities: table_identities,
+impl JoinStrategy {
+    pub fn for_conditions(conditions: &[JoinCondition]) -> Self {
+        let (keys, ranges): (Vec<_>, Vec<_>) = conditions
+            .iter()
+            .cloned()
+            .partition(|condition| condition.is_equi());
+        if !keys.is_empty() {
+            JoinStrategy::Equi { keys }
+        } else if ranges.is_empty() {
+            JoinStrategy::Cross
+        } else {
+            JoinStrategy::Range { conditions: ranges }
+        }
+    }
+}
+
+// Hash of the join columns, `None` if one of them is NULL since that never matches
+fn join_key(record: &DBRecord, columns: &[String]) -> Option<usize> {
+    let mut hasher = DefaultHasher::new();
+    for column in columns {
+        SortKey::from_value(&record.get(column))?.hash(&mut hasher);
+    }
+    Some(hasher.finish() as usize)
+}
+
+type Arrangement<G> = Arranged<G, TraceAgent<OrdValSpine<usize, DBRecord, usize, isize>>>;
+
+/// An operator built by the renderer, with the operators it reads from. EXPLAIN PHYSICAL
+/// prints the tree of a view.
+#[derive(Debug, Clone)]
+pub struct OperatorNode {
+    pub description: String,
+    pub inputs: Vec<OperatorNode>,
+    // Built for an earlier reader and reused, its inputs are listed there
+    pub shared: bool,
+}
+
+impl OperatorNode {
+    fn new(description: String, inputs: Vec<OperatorNode>) -> Self {
+        OperatorNode {
+            description,
+            inputs,
+            shared: false,
+        }
+    }
+
+    fn reused(&self) -> Self {
+        OperatorNode {
+            description: self.description.clone(),
+            inputs: vec![],
+            shared: true,
+        }
+    }
+}
+
+fn list(items: Vec<String>) -> String {
+    items.join(", ")
+}
+
+// Translates logical plans into differential operators over records with qualified columns.
+// Identical parts of plans are rendered once, so views of the same dataflow share them. Every
+// rendered collection comes with the operator that built it.
+struct Renderer<G: Scope<Timestamp = usize>> {
+    inputs: HashMap<String, Collection<G, DataflowData, isize>>,
+    rendered: HashMap<String, (Collection<G, DBRecord, isize>, OperatorNode)>,
+    // Join inputs arranged by the hash of their key columns
+    arranged: HashMap<(String, Vec<String>), (Arrangement<G>, OperatorNode)>,
+}
+
+impl<G: Scope<Timestamp = usize>> Renderer<G> {
+    fn new(inputs: HashMap<String, Collection<G, DataflowData, isize>>) -> Self {
+        Renderer {
+            inputs,
+            rendered: HashMap::new(),
+            arranged: HashMap::new(),
+        }
+    }
+
+    // The consolidated changes of a view, as they are written to its sink table
+    fn render_view(
+        &mut self,
+        plan: &LogicalPlan,
+        table_name: &str,
+    ) -> (Collection<G, DBRecord, isize>, OperatorNode) {
+        let (collection, operator) = self.render(plan);
+        let consolidated = OperatorNode::new(
+            "consolidate: arrangement of the view records".to_string(),
+            vec![operator],
+        );
+        let sink = OperatorNode::new(
+            format!("sink: write completed times to {}", table_name),
+            vec![consolidated],
+        );
+        (collection.consolidate(), sink)
+    }
+
+    fn render(&mut self, plan: &LogicalPlan) -> (Collection<G, DBRecord, isize>, OperatorNode) {
+        let id = format!("{:?}", plan);
+        if let Some((collection, operator)) = self.rendered.get(&id) {
+            return (collection.clone(), operator.reused());
+        }
+        let (collection, operator) = self.render_plan(plan);
+        self.rendered
+            .insert(id, (collection.clone(), operator.clone()));
+        (collection, operator)
+    }
+
+    fn arrange(&mut self, plan: &LogicalPlan, keys: Vec<String>) -> (Arrangement<G>, OperatorNode) {
+        let id = (format!("{:?}", plan), keys.clone());
+        if let Some((arrangement, operator)) = self.arranged.get(&id) {
+            return (arrangement.clone(), operator.reused());
+        }
+        let (collection, input) = self.render(plan);
+        let operator = OperatorNode::new(
+            format!("arrange_by_key: key by hash({})", list(keys.clone())),
+            vec![input],
+        );
+        let arrangement = collection
+            .flat_map(move |record| join_key(&record, &keys).map(|key| (key, record)))
+            .arrange_by_key();
+        self.arranged
+            .insert(id, (arrangement.clone(), operator.clone()));
+        (arrangement, operator)
+    }
+
+    fn render_plan(
+        &mut self,
+        plan: &LogicalPlan,
+    ) -> (Collection<G, DBRecord, isize>, OperatorNode) {
+        match plan {
+            LogicalPlan::Get { table, columns } => {
+                let described = match columns {
+                    Some(columns) => list(columns.clone()),
+                    None => "all columns".to_string(),
+                };
+                let operator = OperatorNode::new(
+                    format!("map: read {} ({}) with qualified names", table, described),
+                    vec![OperatorNode::new(format!("input: {}", table), vec![])],
+                );
+                let table = table.clone();
+                let columns = columns.clone();
+                let collection = self.inputs[&table].map(move |DataflowData(_, (_, record))| {
+                    let record = match &columns {
+                        Some(columns) => record.pick(columns.clone()),
+                        None => record,
+                    };
+                    record.prefix_keys(table.clone())
+                });
+                (collection, operator)
+            }
+            LogicalPlan::Filter { input, predicates } => {
+                let (input, operator) = self.render(input);
+                let operator = OperatorNode::new(
+                    format!(
+                        "filter: {}",
+                        list(
+                            predicates
+                                .iter()
+                                .map(|predicate| predicate.to_string())
+                                .collect()
+                        )
+                    ),
+                    vec![operator],
+                );
+                let predicates = predicates.clone();
+                let collection = input.filter(move |record| {
+                    predicates
+                        .iter()
+                        .all(|predicate| predicate.evaluate(record))
+                });
+                (collection, operator)
+            }
+            LogicalPlan::Project { input, columns } => {
+                let (input, operator) = self.render(input);
+                let operator = OperatorNode::new(
+                    format!("map: project {}", projection(columns)),
+                    vec![operator],
+                );
+                let columns = columns.clone();
+                (input.map(move |record| record.project(&columns)), operator)
+            }
+            LogicalPlan::Join {
+                left,
+                right,
+                conditions,
+            } => {
+                let described = list(conditions.iter().map(|join| join.to_string()).collect());
+                match JoinStrategy::for_conditions(conditions) {
+                    JoinStrategy::Equi { keys } => {
+                        let (left_keys, right_keys): (Vec<_>, Vec<_>) = keys
+                            .iter()
+                            .map(|key| (key.left.to_string(), key.right.to_string()))
+                            .unzip();
+                        let (left, left_operator) = self.arrange(left, left_keys);
+                        let (right, right_operator) = self.arrange(right, right_keys);
+                        let operator = OperatorNode::new(
+                            format!("filter: {}", described),
+                            vec![OperatorNode::new(
+                                "join_core: arrangements of both inputs by key".to_string(),
+                                vec![left_operator, right_operator],
+                            )],
+                        );
+                        // Checking all conditions also rules out collisions of the key hashes
+                        let conditions = conditions
+                            .iter()
+                            .cloned()
+                            .map(Predicate::Columns)
+                            .collect::<Vec<_>>();
+                        let collection = left
+                            .join_core(&right, |_, left, right| {
+                                Some(left.clone().merge(right.clone()))
+                            })
+                            .filter(move |record| {
+                                conditions
+                                    .iter()
+                                    .all(|condition| condition.evaluate(record))
+                            });
+                        (collection, operator)
+                    }
+                    JoinStrategy::Range { conditions } => {
+                        let (left, left_operator) = self.render(left);
+                        let (right, right_operator) = self.render(right);
+                        let index = &conditions[0];
+                        let operator = OperatorNode::new(
+                            format!(
+                                "RangeJoin: ordered indexes on {} and {}, checks {}",
+                                index.left, index.right, described
+                            ),
+                            vec![left_operator, right_operator],
+                        );
+                        (range_join(&left, &right, conditions), operator)
+                    }
+                    JoinStrategy::Cross => {
+                        let (left, left_operator) = self.render(left);
+                        let (right, right_operator) = self.render(right);
+                        let operator = OperatorNode::new(
+                            "join: arrangements of both inputs by a constant key (cross product)"
+                                .to_string(),
+                            vec![left_operator, right_operator],
+                        );
+                        // Every record shares the same key, so the join pairs all of them
+                        let left = left.map(|record| (0, record));
+                        let right = right.map(|record| (0, record));
+                        let collection = left
+                            .join(&right)
+                            .map(|(_, (mut left, right))| left.merge(right));
+                        (collection, operator)
+                    }
+                }
+            }
+            LogicalPlan::View {
+                name,
+                input,
+                columns,
+            } => {
+                let (input, operator) = self.render(input);
+                let operator = OperatorNode::new(
+                    format!("map: read view {} as {}", name, projection(columns)),
+                    vec![operator],
+                );
+                let columns = columns.clone();
+                (input.map(move |record| record.project(&columns)), operator)
+            }
         }
     }
-    pub async fn build_dataflow(&self, query: Query, source: Source) {
-        let table_name = query.to_table_string();
-        let sink = Sink::new(table_name.clone()).await;
-        let table_identities = self.table_identities.clone();
-        let left_state: DBState = Arc::new(Mutex::new(HashMap::new()));
-        let right_state: DBState = Arc::new(Mutex::new(HashMap::new()));
-
-        // Spawn a new thread and move `source` into it
-        let _ = timely::execute_from_args(std::env::args(), move |worker| {
-            let mut inputs: InputSessions = InputSessions::new(query.tables.clone());
-            let mut local_source = source.clone();
-            let table_name = table_name.clone();
-            let mut first_entry: Option<DBRecord> = None;
-            let left_key = query.joins.clone().get(0).unwrap().left.clone();
-            let schema = table_identities.get(&query.tables[1]).unwrap();
-            let mut right_column = schema
-                .iter()
-                .find(|key| match key.key_type {
-                    KeyType::PrimaryKey => true,
-                    _ => false,
-                })
+}
+
+// Build the operators of a view in a dataflow that never runs, describing what the planer
+// would build for it
+pub fn describe_operators(table_name: &str, plan: &LogicalPlan) -> OperatorNode {
+    let table_name = table_name.to_string();
+    let plan = plan.clone();
+    timely::execute_directly(move |worker| {
+        worker.dataflow::<usize, _, _>(|scope| {
+            let mut collections = HashMap::new();
+            for table in plan.source_tables() {
+                // The input is closed right away, so the dataflow completes without data
+                let (_, collection) = scope.new_collection::<DataflowData, isize>();
+                collections.insert(table, collection);
+            }
+            Renderer::new(collections).render_view(&plan, &table_name).1
+        })
+    })
+}
+
+impl QueryPlaner {
+    pub fn new() -> Self {
+        QueryPlaner {}
+    }
+
+    // Translate the bound query into an optimized logical plan
+    pub fn plan(&self, query: &Query) -> LogicalPlan {
+        let plan = optimize(LogicalPlan::from_query(query));
+        debug!("Logical plan: {:?}", plan);
+        plan
+    }
+
+    // Run one dataflow computing every view and writing it to its sink table. `progress`
+    // receives the changes written to all sinks.
+    pub async fn build_dataflow(
+        &self,
+        views: Vec<(String, LogicalPlan, SchemaChangePolicy)>,
+        source: Source,
+        progress: Arc<DataflowProgress>,
+        status: Arc<watch::Sender<StreamStatus>>,
+    ) -> Result<(), tokio_postgres::Error> {
+        let mut sinks = vec![];
+        for (table_name, _, _) in &views {
+            sinks.push(Sink::new(table_name.clone(), Arc::clone(&status)).await?);
+        }
+
+        // Spawn a new thread and move `source` into it. The dataflow runs on a single worker,
+        // which feeds every input from the one source.
+        let source = Mutex::new(Some(source));
+        let _ = timely::execute(timely::Config::thread(), move |worker| {
+            let mut tables: Vec<String> = vec![];
+            for (_, plan, _) in &views {
+                for table in plan.source_tables() {
+                    if !tables.contains(&table) {
+                        tables.push(table);
+                    }
+                }
+            }
+            let mut inputs: InputSessions = InputSessions::new(tables.clone());
+            let mut local_source = source
+                .lock()
                 .unwrap()
-                .column_name
-                .clone();
-            let mut right_key = format!("{}.{}", query.tables[1], right_column);
-            // access the state
-            let left_state_clone = left_state.clone();
-            let right_state_clone = right_state.clone();
+                .take()
+                .expect("The source is read by a single worker");
+            let mut flushes = FlushTracker::new(Arc::clone(&progress));
+            let mut rows = SourceRows::default();
 
             let probe = worker.dataflow(|scope| {
-                let l_state = left_state_clone.clone();
-                let r_state = right_state_clone.clone();
-                let mut sink = sink.clone();
                 // Create a new collection from our input.
                 let mut collections = HashMap::new();
-                for table in &query.tables.clone() {
+                for table in &tables {
                     let collection: Collection<_, DataflowData, isize> =
                         inputs.get(table).unwrap().to_collection(scope);
                     collections.insert(table.clone(), collection);
                 }
+                let mut renderer = Renderer::new(collections);
+                let mut probe = ProbeHandle::new();
 
-                let join_prepare_left = move |x: DataflowData| {
-                    let record = x.1 .1;
-                    l_state.lock().unwrap().insert(x.0, (x.1 .0, record));
-                    (x.0, x.1 .0)
-                };
-                let join_prepare_right = move |x: DataflowData| {
-                    let foreign_key = x.1 .0;
-                    let record = x.1 .1;
-                    r_state.lock().unwrap().insert(x.0, (x.1 .0, record));
-                    (foreign_key.unwrap_or(0), x.0)
-                };
-
-                // let output = {
-                // Join the collections
-                let left_table = String::from(query.tables.get(0).unwrap());
-                let right_table = String::from(query.tables.get(1).unwrap());
-
-                let output = {
-                    let left_collection = collections
-                        .get(&left_table.clone())
-                        .unwrap()
-                        .map(join_prepare_left)
-                        .consolidate();
-                    let right_collection = collections
-                        .get(&right_table.clone())
-                        .unwrap()
-                        .map(join_prepare_right)
-                        .consolidate();
-
-                    let output = left_collection.join(&right_collection);
-                    let output: Collection<
-                        Child<'_, TimelyWorker<Allocator>, usize>,
-                        (usize, (usize, DBRecord)),
-                    > = output
-                        .map(move |x| {
-                            let mut left = left_state_clone
-                                .lock()
-                                .unwrap()
-                                .get(&x.0)
-                                .unwrap()
-                                .1
-                                .clone()
-                                .prefix_keys(left_table.to_string());
-                            let right = right_state_clone
-                                .lock()
-                                .unwrap()
-                                .get(&x.1 .1)
-                                .unwrap()
-                                .1
-                                .clone()
-                                .prefix_keys(right_table.to_string());
-                            (x.0, (x.1 .1, left.merge(right)))
-                        })
-                        .inspect(|x| debug!("Mapped: {:?}", x));
-                    output
-                };
-
-                let output = if query.condition.is_some() {
-                    let condition = query.condition.clone().unwrap();
-                    let output = output.filter(move |x| {
-                        let record = &x.1 .1;
-
-                        let left = record.get(&condition.left.to_string());
-                        let right = condition.right.clone();
-                        match condition.op.as_str() {
-                            "=" => left == right,
-                            "!=" => left != right,
-                            ">" => left.as_f64().unwrap() > right.parse().unwrap(),
-                            "<" => left.as_f64().unwrap() < right.parse().unwrap(),
-                            ">=" => left.as_f64().unwrap() >= right.parse().unwrap(),
-                            "<=" => left.as_f64().unwrap() <= right.parse().unwrap(),
-                            _ => false,
-                        }
+                for ((table_name, plan, _), sink) in views.iter().zip(sinks.iter()) {
+                    let mut sink = sink.clone();
+                    let (output, _) = renderer.render_view(plan, table_name);
+                    let output = output.inspect(|x| debug!("Mapped: {:?}", x));
+                    let written = write_completed(&output, move |time, upto, updates| {
+                        sink.write(time, upto, updates)
                     });
-                    output
-                } else {
-                    output
-                };
-
-                output.inspect_batch(move |_t, batch| {
-                    let first = batch.first().unwrap();
-                    if first_entry.is_none() {
-                        first_entry = Some(first.0 .1 .1.clone()); // x is a tuple of (data, time, diff)
-                        sink.set_schema(first_entry.clone().unwrap().create_sql_schema());
-                        sink.set_columns(first_entry.clone().unwrap().get_sql_columns());
-                    }
-
-                    let batch = batch.to_vec();
-
-                    let mut values = batch
-                        .iter()
-                        .map(|((pk, (fk, record)), _, diff)| {
-                            let record_type = RecordType::from_value(*diff);
-                            record.to_sql_values(
-                                record_type.clone(),
-                                table_name.clone(),
-                                match record_type {
-                                    RecordType::Insert => None,
-                                    RecordType::Delete => {
-                                        let mut keys = Vec::new();
-                                        keys.push((left_key.to_string(), pk.clone()));
-                                        keys.push((right_key.as_str().to_string(), fk.clone()));
-                                        Some(keys)
-                                    }
-                                },
-                            )
-                        })
-                        .collect::<Vec<String>>();
-                    sink.insert(&mut values);
-                    sink.execute_transaction();
-                });
-
-                output.probe()
+                    // The probe follows the sinks, its frontier passes a time once the output
+                    // of that time is written
+                    written.probe_with(&mut probe);
+                }
+                probe
             });
 
-            // Process events until the source is done
+            // Process transactions until the source is done. All changes of a transaction
+            // are at its commit LSN, and a transaction closes its time since it arrives whole,
+            // so every output reflects whole transactions.
             inputs.advance_to(0);
-            let mut buffer = Buffer::new();
             while !local_source.done() {
-                if let Some(events) = local_source.fetch() {
-                    for event in events {
-                        let join_prop = query.joins.clone().get(0).unwrap().right.clone();
-                        let required_key = if event.0 == join_prop.table {
-                            Some(join_prop.row.to_string())
-                        } else {
-                            None
-                        };
-                        let parsed_event =
-                            DataflowInput::from_wal_event(event.1.clone(), required_key);
-                        for i in parsed_event.clone() {
-                            buffer.insert(event.0.clone(), i.element, i.time, i.change);
+                let mut frontier = inputs.time();
+                for transaction in local_source.fetch() {
+                    let mut fed_time = inputs.time();
+                    for event in transaction.changes.iter().cloned() {
+                        let table = event.table.clone();
+                        // Outputs of earlier transactions are written under the old schema
+                        if let WalData::Schema(change) = &event.data {
+                            let time = event.time().max(inputs.time());
+                            inputs.advance_to(time);
+                            inputs.flush();
+                            worker.step_while(|| probe.less_than(&time));
+                            for ((view, plan, policy), sink) in views.iter().zip(sinks.iter()) {
+                                apply_schema_change(view, plan, *policy, sink, &table, change);
+                            }
+                            rows.drop_columns(&table, change);
+                            continue;
                         }
+                        let time = rows.feed(event, inputs.time(), |table, data, time, diff| {
+                            inputs.update_at_for_table(table, data, time, diff)
+                        });
+                        fed_time = fed_time.max(time);
+                    }
+                    flushes.fed(transaction.lsn, fed_time);
+                    // The rows of a snapshot may come in several transactions at LSN 0
+                    if transaction.lsn > 0 {
+                        frontier = frontier.max(transaction.time() + 1);
                     }
+                }
+                if frontier > inputs.time() {
+                    inputs.advance_to(frontier);
+                    debug!("Advancing to time: {}", frontier);
+                }
+                inputs.flush();
+                worker.step_while(|| probe.less_than(&inputs.time()));
+                flushes.flushed(probe.with_frontier(|frontier| frontier.first().copied()));
+            }
+        });
+        Ok(())
+    }
+}
+
+/// The rows of the source tables as fed to the inputs, by table and key. Deletions only carry
+/// the key, and so do updates unless the table has REPLICA IDENTITY FULL.
+#[derive(Debug, Default)]
+struct SourceRows {
+    states: HashMap<String, HashMap<usize, (Option<usize>, DBRecord)>>,
+}
+
+impl SourceRows {
+    // Hand the updates of a row change or a truncation to `update`, at `now` or later. Returns
+    // the time of the updates.
+    fn feed<F>(&mut self, event: WalEvent, now: usize, mut update: F) -> usize
+    where
+        F: FnMut(&str, DataflowData, usize, isize),
+    {
+        let table = event.table.clone();
+        // Key of the row a deletion or update retracts
+        let key = match &event.data {
+            WalData::Update(Update {
+                old_key: Some(key), ..
+            }) => key.clone(),
+            _ => event.pkey.clone(),
+        };
+        let state = self.states.entry(table.clone()).or_default();
+        // A truncation retracts every live row of the table
+        if let WalData::Truncate = event.data {
+            let time = event.time().max(now);
+            debug!("Truncating {}, retracting {} rows", table, state.len());
+            for (key, record) in state.drain() {
+                update(&table, DataflowData(key, record), time, -1);
+            }
+            return time;
+        }
+        let mut fed_time = now;
+        // Row an update replaces, columns left out of the update keep its values
+        let mut replaced: Option<DBRecord> = None;
+        for input in DataflowInput::from_wal_event(vec![event], None) {
+            let DataflowInput {
+                element: mut data,
+                time,
+                change,
+            } = input;
+            // Snapshot rows have time 0, a table whose snapshot arrives after other changes
+            // were applied gets its rows at the current time
+            let time = time.max(now);
+            fed_time = fed_time.max(time);
+
+            // Handle deletions with only primary key
+            if change == -1 {
+                // An update of a table with REPLICA IDENTITY FULL carries the old row itself
+                let old_record = state.remove(&data.0).or_else(|| {
+                    let (_, old) = &data.1;
+                    (!old.0.is_empty()).then(|| data.1.clone())
+                });
+                if let Some(full_record) = old_record {
+                    // fill in the rest of the record
+                    update(
+                        &table,
+                        DataflowData(data.0, full_record.clone()), // Full key-value pair
+                        time,
+                        change,
+                    );
+                    replaced = Some(full_record.1);
                 } else {
-                    worker.step_while(|| probe.less_than(&inputs.time()));
+                    info!(
+                        "No row of {} with {} = {} to retract",
+                        table, key.col, key.val
+                    );
                 }
-                let popped = buffer.pop();
-                if let Some(popped) = popped {
-                    for event in popped.clone() {
-                        let (table, data, time, change) = event;
-
-                        // Handle deletions with only primary key
-                        if change == -1 {
-                            // Determine if it's from left or right
-                            let state = if table == query.tables[0] {
-                                &left_state
-                            } else {
-                                &right_state
-                            };
-
-                            if let Some(full_record) = state.lock().unwrap().remove(&data.0) {
-                                // fill in the rest of the record
-                                inputs.update_at_for_table(
-                                    &table,
-                                    DataflowData(data.0, full_record.clone()), // Full key-value pair
-                                    time,
-                                    change,
-                                );
-                            } else {
-                                info!("Delete event received for non-existent id: {:?}", data);
-                            }
-                        } else {
-                            // For insertions or updates, handle normally
-                            inputs.update_at_for_table(&table, data, time, change);
-                        }
+            } else {
+                // Unchanged TOAST values are left out of updates
+                if let Some(old) = replaced.take() {
+                    let (_, record) = &mut data.1;
+                    for (column, value) in old.0 {
+                        record.0.entry(column).or_insert(value);
                     }
-                    let time = popped.iter().map(|x| x.2).min().unwrap();
-                    let max_time = popped.iter().map(|x| x.2).max().unwrap();
-                    buffer.update_watermark(max_time);
-                    inputs.advance_to(time);
-                    inputs.flush();
-                    info!("Advancing to time: {} and Flushed", time);
                 }
-                if buffer.data.is_empty() && (inputs.time() < buffer.get_watermark()) {
-                    inputs.advance_to(buffer.get_watermark());
-                    inputs.flush();
-                    debug!(
-                        "Advancing to time: {} and Flushed from buffer",
-                        buffer.get_watermark()
-                    );
+                // For insertions or updates, handle normally
+                state.insert(data.0, data.1.clone());
+                update(&table, data, time, change);
+            }
+        }
+        fed_time
+    }
+
+    // Updates take their unchanged columns from the rows held here, which lose the columns a
+    // schema change dropped
+    fn drop_columns(&mut self, table: &str, change: &SchemaChange) {
+        if let Some(state) = self.states.get_mut(table) {
+            for (_, record) in state.values_mut() {
+                for column in &change.dropped {
+                    record.0.remove(column);
+                }
+            }
+        }
+    }
+}
+
+/// What a view does about a change of the columns of one of its source tables.
+#[derive(Debug, PartialEq)]
+enum SchemaAction {
+    // The view doesn't read the table, or ignores the change
+    Keep,
+    // The sink table follows the types of the next rows
+    Evolve,
+    Stop(String),
+}
+
+// React to a change of the columns of a source table according to the policy of a view. A
+// view reading a dropped column can't be computed anymore, whatever its policy.
+fn schema_action(
+    view: &str,
+    plan: &LogicalPlan,
+    policy: SchemaChangePolicy,
+    table: &str,
+    change: &SchemaChange,
+) -> SchemaAction {
+    if !plan.source_tables().iter().any(|source| source == table) {
+        return SchemaAction::Keep;
+    }
+    let read = plan.source_columns(table);
+    let reads = |column: &String| read.as_ref().is_none_or(|read| read.contains(column));
+    if let Some(column) = change.dropped.iter().find(|column| reads(column)) {
+        return SchemaAction::Stop(format!("column {} of {} was dropped", column, table));
+    }
+    match policy {
+        SchemaChangePolicy::Fail => SchemaAction::Stop(format!("{} changed: {}", table, change)),
+        SchemaChangePolicy::Evolve => {
+            info!("{} changed: {}, evolving view {}", table, change, view);
+            SchemaAction::Evolve
+        }
+        SchemaChangePolicy::Ignore => {
+            if change.retyped.iter().any(reads) {
+                warn!(
+                    "{} changed: {}, view {} reads retyped columns but ignores the change",
+                    table, change, view
+                );
+            } else {
+                info!("{} changed: {}, ignored by view {}", table, change, view);
+            }
+            SchemaAction::Keep
+        }
+    }
+}
+
+fn apply_schema_change(
+    view: &str,
+    plan: &LogicalPlan,
+    policy: SchemaChangePolicy,
+    sink: &Sink,
+    table: &str,
+    change: &SchemaChange,
+) {
+    if sink.stopped() {
+        return;
+    }
+    match schema_action(view, plan, policy, table, change) {
+        SchemaAction::Keep => {}
+        SchemaAction::Evolve => sink.evolve(),
+        SchemaAction::Stop(reason) => sink.stop(reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::core::parser::parse_query;
+    use crate::pg_client::data::{Insert, PKey};
+
+    fn row(id: u64, name: &str) -> BTreeMap<String, Value> {
+        let mut row = BTreeMap::new();
+        row.insert("id".to_string(), Value::from(id));
+        row.insert("name".to_string(), Value::from(name));
+        row
+    }
+
+    // An update of the row with key 1 in the transaction committed at `lsn`
+    fn update(lsn: u64, old: &str, new: &str) -> WalEvent {
+        WalEvent {
+            table: "users".to_string(),
+            timestamp: String::new(),
+            xid: 0,
+            lsn,
+            pkey: PKey {
+                col: "id".to_string(),
+                val: Value::from(1),
+            },
+            data: WalData::Update(Update {
+                values: row(1, new),
+                old_key: None,
+                old: Some(row(1, old)),
+            }),
+        }
+    }
+
+    // `SELECT users.id, users.name FROM users`
+    fn users_view() -> LogicalPlan {
+        LogicalPlan::Project {
+            input: Box::new(LogicalPlan::Get {
+                table: "users".to_string(),
+                columns: Some(vec!["id".to_string(), "name".to_string()]),
+            }),
+            columns: vec![
+                ("users.id".to_string(), "id".to_string()),
+                ("users.name".to_string(), "name".to_string()),
+            ],
+        }
+    }
+
+    fn change(added: &[&str], dropped: &[&str], retyped: &[&str]) -> SchemaChange {
+        let names = |columns: &[&str]| columns.iter().map(|column| column.to_string()).collect();
+        SchemaChange {
+            added: names(added),
+            dropped: names(dropped),
+            retyped: names(retyped),
+        }
+    }
+
+    fn action(policy: SchemaChangePolicy, table: &str, change: &SchemaChange) -> SchemaAction {
+        schema_action("v", &users_view(), policy, table, change)
+    }
+
+    #[test]
+    fn other_tables_dont_affect_a_view() {
+        let dropped = change(&[], &["id"], &[]);
+        assert_eq!(
+            action(SchemaChangePolicy::Fail, "orders", &dropped),
+            SchemaAction::Keep
+        );
+    }
+
+    #[test]
+    fn a_dropped_column_the_view_reads_stops_it_whatever_its_policy() {
+        let dropped = change(&[], &["name"], &[]);
+        for policy in [
+            SchemaChangePolicy::Fail,
+            SchemaChangePolicy::Evolve,
+            SchemaChangePolicy::Ignore,
+        ] {
+            assert_eq!(
+                action(policy, "users", &dropped),
+                SchemaAction::Stop("column name of users was dropped".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn other_changes_follow_the_policy() {
+        let added = change(&["email"], &["age"], &["name"]);
+        assert_eq!(
+            action(SchemaChangePolicy::Fail, "users", &added),
+            SchemaAction::Stop("users changed: added email; dropped age; retyped name".to_string())
+        );
+        assert_eq!(
+            action(SchemaChangePolicy::Evolve, "users", &added),
+            SchemaAction::Evolve
+        );
+        assert_eq!(
+            action(SchemaChangePolicy::Ignore, "users", &added),
+            SchemaAction::Keep
+        );
+    }
+
+    #[test]
+    fn a_view_reading_all_columns_reads_every_dropped_one() {
+        let plan = LogicalPlan::Get {
+            table: "users".to_string(),
+            columns: None,
+        };
+        let dropped = change(&[], &["age"], &[]);
+        assert_eq!(
+            schema_action("v", &plan, SchemaChangePolicy::Ignore, "users", &dropped),
+            SchemaAction::Stop("column age of users was dropped".to_string())
+        );
+    }
+
+    #[test]
+    fn a_row_updated_twice_in_a_transaction_keeps_the_last_values() {
+        let output = timely::execute_directly(|worker| {
+            let output = Rc::new(RefCell::new(vec![]));
+            let captured = Rc::clone(&output);
+            let mut probe = ProbeHandle::new();
+            let mut input = worker.dataflow::<usize, _, _>(|scope| {
+                let (input, rows) = scope.new_collection();
+                rows.consolidate()
+                    .inspect(move |update: &(DataflowData, usize, isize)| {
+                        captured.borrow_mut().push(update.clone())
+                    })
+                    .probe_with(&mut probe);
+                input
+            });
+            input.insert(DataflowData(1, (None, DBRecord(row(1, "ada")))));
+            for event in [update(40, "ada", "grace"), update(40, "grace", "hedy")] {
+                for update in DataflowInput::from_wal_event(vec![event], None) {
+                    input.update_at(update.element, update.time, update.change);
                 }
-                worker.step_while(|| probe.less_than(&inputs.time()));
             }
+            input.advance_to(41);
+            input.flush();
+            worker.step_while(|| probe.less_than(&41));
+            output.take()
+        });
+        let mut names = output
+            .iter()
+            .map(|(row, time, diff)| (row.1 .1.get("name"), *time, *diff))
+            .collect::<Vec<_>>();
+        names.sort_by_key(|(_, time, diff)| (*time, *diff));
+        assert_eq!(
+            names,
+            vec![
+                (Value::from("ada"), 0, 1),
+                (Value::from("ada"), 40, -1),
+                (Value::from("hedy"), 40, 1)
+            ]
+        );
+    }
+
+    // A change of the row with key `id` of `table`, in the transaction committed at `lsn`
+    fn change_of(table: &str, lsn: u64, id: u64, data: WalData) -> WalEvent {
+        WalEvent {
+            table: table.to_string(),
+            timestamp: String::new(),
+            xid: 0,
+            lsn,
+            pkey: PKey {
+                col: "id".to_string(),
+                val: Value::from(id),
+            },
+            data,
+        }
+    }
+
+    fn with_bio(mut row: BTreeMap<String, Value>, bio: &str) -> BTreeMap<String, Value> {
+        row.insert("bio".to_string(), Value::from(bio));
+        row
+    }
+
+    // The updates `rows` feeds for `event` as (table, key, row, time, diff)
+    fn feed(
+        rows: &mut SourceRows,
+        event: WalEvent,
+        now: usize,
+    ) -> Vec<(String, usize, DBRecord, usize, isize)> {
+        let mut fed = vec![];
+        rows.feed(
+            event,
+            now,
+            |table, DataflowData(key, (_, record)), time, diff| {
+                fed.push((table.to_string(), key, record, time, diff))
+            },
+        );
+        fed
+    }
+
+    #[test]
+    fn a_changed_key_retracts_the_row_held_under_the_old_key() {
+        let mut rows = SourceRows::default();
+        let insert = WalData::Insert(Insert(with_bio(row(1, "ada"), "long")));
+        let fed = feed(&mut rows, change_of("users", 0, 1, insert), 0);
+        let old_key = fed[0].1;
+
+        // The unchanged TOAST value of bio is left out of the update
+        let update = WalData::Update(Update {
+            values: row(2, "ada"),
+            old_key: Some(PKey {
+                col: "id".to_string(),
+                val: Value::from(1),
+            }),
+            old: None,
+        });
+        let fed = feed(&mut rows, change_of("users", 40, 2, update), 0);
+        assert_eq!(fed.len(), 2);
+        let (_, key, old, time, diff) = &fed[0];
+        assert_eq!(
+            (*key, old, *time, *diff),
+            (old_key, &DBRecord(with_bio(row(1, "ada"), "long")), 40, -1)
+        );
+        let (_, new_key, new, time, diff) = &fed[1];
+        assert_ne!(*new_key, old_key);
+        assert_eq!(
+            (new, *time, *diff),
+            (&DBRecord(with_bio(row(2, "ada"), "long")), 40, 1)
+        );
+
+        // Only the new key is held, a deletion of the old one retracts nothing
+        assert_eq!(
+            feed(&mut rows, change_of("users", 50, 1, WalData::Delete), 0),
+            vec![]
+        );
+        let fed = feed(&mut rows, change_of("users", 60, 2, WalData::Delete), 0);
+        assert_eq!(
+            fed,
+            vec![(
+                "users".to_string(),
+                *new_key,
+                DBRecord(with_bio(row(2, "ada"), "long")),
+                60,
+                -1
+            )]
+        );
+    }
+
+    #[test]
+    fn an_old_row_sent_with_replica_identity_full_is_retracted_and_fills_the_new_one() {
+        let mut rows = SourceRows::default();
+        // The row isn't held, e.g. the table was copied without it
+        let update = WalData::Update(Update {
+            values: row(1, "hedy"),
+            old_key: None,
+            old: Some(with_bio(row(1, "grace"), "short")),
+        });
+        let fed = feed(&mut rows, change_of("users", 40, 1, update), 0);
+        let updates = fed
+            .iter()
+            .map(|(_, _, record, time, diff)| (record.clone(), *time, *diff))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            updates,
+            vec![
+                (DBRecord(with_bio(row(1, "grace"), "short")), 40, -1),
+                (DBRecord(with_bio(row(1, "hedy"), "short")), 40, 1),
+            ]
+        );
+
+        // Without the old row, an update of a row that isn't held only inserts the new one
+        let update = WalData::Update(Update {
+            values: row(2, "ada"),
+            old_key: None,
+            old: None,
+        });
+        let fed = feed(&mut rows, change_of("users", 50, 2, update), 0);
+        assert_eq!(fed.len(), 1);
+        assert_eq!((&fed[0].2, fed[0].4), (&DBRecord(row(2, "ada")), 1));
+    }
+
+    #[test]
+    fn tables_hold_their_rows_apart() {
+        let mut rows = SourceRows::default();
+        // Snapshot rows copied after other changes were applied come at the current time
+        let users = WalData::Insert(Insert(with_bio(row(1, "ada"), "long")));
+        assert_eq!(
+            feed(&mut rows, change_of("users", 0, 1, users), 10)[0].3,
+            10
+        );
+        let orders = WalData::Insert(Insert(row(1, "book")));
+        feed(&mut rows, change_of("orders", 20, 1, orders), 10);
+
+        // A dropped column isn't taken from the rows held anymore
+        rows.drop_columns("users", &change(&[], &["bio"], &[]));
+        let fed = feed(&mut rows, change_of("users", 30, 0, WalData::Truncate), 10);
+        assert_eq!(
+            fed.into_iter()
+                .map(|(table, _, record, time, diff)| (table, record, time, diff))
+                .collect::<Vec<_>>(),
+            vec![("users".to_string(), DBRecord(row(1, "ada")), 30, -1)]
+        );
+        let fed = feed(&mut rows, change_of("orders", 40, 1, WalData::Delete), 10);
+        assert_eq!(
+            fed.into_iter()
+                .map(|(table, _, record, _, diff)| (table, record, diff))
+                .collect::<Vec<_>>(),
+            vec![("orders".to_string(), DBRecord(row(1, "book")), -1)]
+        );
+    }
+
+    // Descriptions of the operators a view reuses from views rendered before it
+    fn shared(operator: &OperatorNode) -> Vec<String> {
+        let mut shared = vec![];
+        if operator.shared {
+            shared.push(operator.description.clone());
+        }
+        for input in &operator.inputs {
+            shared.extend(self::shared(input));
+        }
+        shared
+    }
+
+    #[test]
+    fn a_view_and_its_readers_share_its_operators_in_one_dataflow() {
+        let big_orders = optimize(LogicalPlan::from_query(
+            &parse_query(
+                r#"SELECT "Order".id, "Order".total FROM "Order" WHERE "Order".total > 600"#,
+            )
+            .unwrap(),
+        ));
+        // `SELECT users.name, big_orders.total FROM users, big_orders`
+        let reader = LogicalPlan::Project {
+            input: Box::new(LogicalPlan::Join {
+                left: Box::new(users_view()),
+                right: Box::new(LogicalPlan::View {
+                    name: "big_orders".to_string(),
+                    input: Box::new(big_orders.clone()),
+                    columns: vec![("Order.total".to_string(), "big_orders.total".to_string())],
+                }),
+                conditions: vec![],
+            }),
+            columns: vec![
+                ("name".to_string(), "name".to_string()),
+                ("big_orders.total".to_string(), "total".to_string()),
+            ],
+        };
+        let (view, reader) = timely::execute_directly(move |worker| {
+            worker.dataflow::<usize, _, _>(|scope| {
+                let mut collections = HashMap::new();
+                for table in ["Order", "users"] {
+                    let (_, collection) = scope.new_collection::<DataflowData, isize>();
+                    collections.insert(table.to_string(), collection);
+                }
+                let mut renderer = Renderer::new(collections);
+                let view = renderer.render_view(&big_orders, "big_orders").1;
+                let reader = renderer.render_view(&reader, "reader").1;
+                (view, reader)
+            })
         });
+        assert!(shared(&view).is_empty());
+        assert_eq!(shared(&reader), vec!["map: project Order.id, Order.total"]);
     }
 }
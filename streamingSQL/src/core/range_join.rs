@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+use differential_dataflow::{AsCollection, Collection};
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::Capability;
+use timely::dataflow::Scope;
+
+use crate::core::parser::JoinCondition;
+use crate::core::types::dataflow_types::{compare_values, operator_holds, DBRecord, SortKey};
+
+type Updates = Vec<(DBRecord, isize)>;
+
+// Records of one join side, ordered by the column used in the indexed condition
+struct RangeIndex {
+    column: String,
+    entries: BTreeMap<SortKey, HashMap<DBRecord, isize>>,
+}
+
+impl RangeIndex {
+    fn new(column: String) -> Self {
+        RangeIndex {
+            column,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn update(&mut self, record: &DBRecord, diff: isize) {
+        // NULL never satisfies a comparison, so such records can't match anything
+        let key = match SortKey::from_value(&record.get(&self.column)) {
+            Some(key) => key,
+            None => return,
+        };
+        let bucket = self.entries.entry(key.clone()).or_default();
+        let count = bucket.entry(record.clone()).or_insert(0);
+        *count += diff;
+        if *count == 0 {
+            bucket.remove(record);
+        }
+        if bucket.is_empty() {
+            self.entries.remove(&key);
+        }
+    }
+
+    fn range(
+        &self,
+        bounds: (Bound<SortKey>, Bound<SortKey>),
+    ) -> impl Iterator<Item = (&DBRecord, &isize)> {
+        self.entries
+            .range(bounds)
+            .flat_map(|(_, records)| records.iter())
+    }
+}
+
+// Bounds of all values `x` for which `value <operator> x` holds
+fn bounds(operator: &str, value: SortKey) -> (Bound<SortKey>, Bound<SortKey>) {
+    match operator {
+        "<" => (Bound::Excluded(value), Bound::Unbounded),
+        "<=" => (Bound::Included(value), Bound::Unbounded),
+        ">" => (Bound::Unbounded, Bound::Excluded(value)),
+        ">=" => (Bound::Unbounded, Bound::Included(value)),
+        _ => (Bound::Included(value.clone()), Bound::Included(value)),
+    }
+}
+
+fn matches(conditions: &[JoinCondition], left: &DBRecord, right: &DBRecord) -> bool {
+    conditions.iter().all(|condition| {
+        compare_values(
+            &left.get(&condition.left.to_string()),
+            &right.get(&condition.right.to_string()),
+        )
+        .is_some_and(|ordering| operator_holds(&condition.operator, ordering))
+    })
+}
+
+/// Joins two collections on range conditions (`<`, `<=`, `>`, `>=`).
+///
+/// Both sides are kept in a `BTreeMap` ordered by the column of the first condition, so every
+/// incoming change only visits the records within its range instead of the whole other side.
+/// The remaining conditions are checked on each candidate pair. All conditions must already be
+/// oriented so that their left side refers to the left collection, and the records of both
+/// sides have their columns qualified with the table name.
+///
+/// Changes are applied per timestamp once both inputs have passed it, which yields the exact
+/// delta `dL x R + L' x dR`. Range conditions can't partition the records, so all of them are
+/// exchanged to the first worker.
+pub fn range_join<G>(
+    left: &Collection<G, DBRecord, isize>,
+    right: &Collection<G, DBRecord, isize>,
+    conditions: Vec<JoinCondition>,
+) -> Collection<G, DBRecord, isize>
+where
+    G: Scope<Timestamp = usize>,
+{
+    let index = conditions[0].clone();
+    left.inner
+        .binary_frontier(
+            &right.inner,
+            Exchange::new(|_: &(DBRecord, usize, isize)| 0),
+            Exchange::new(|_: &(DBRecord, usize, isize)| 0),
+            "RangeJoin",
+            move |_, _| {
+                let mut left_index = RangeIndex::new(index.left.to_string());
+                let mut right_index = RangeIndex::new(index.right.to_string());
+                let mut pending: BTreeMap<usize, (Capability<usize>, Updates, Updates)> =
+                    BTreeMap::new();
+                let mut buffer = Vec::new();
+
+                move |input1, input2, output| {
+                    input1.for_each(|cap, data| {
+                        data.swap(&mut buffer);
+                        for (record, time, diff) in buffer.drain(..) {
+                            pending
+                                .entry(time)
+                                .or_insert_with(|| (cap.delayed(&time), Vec::new(), Vec::new()))
+                                .1
+                                .push((record, diff));
+                        }
+                    });
+                    input2.for_each(|cap, data| {
+                        data.swap(&mut buffer);
+                        for (record, time, diff) in buffer.drain(..) {
+                            pending
+                                .entry(time)
+                                .or_insert_with(|| (cap.delayed(&time), Vec::new(), Vec::new()))
+                                .2
+                                .push((record, diff));
+                        }
+                    });
+
+                    // Only join a timestamp once no more changes can arrive for it on either side
+                    let ready = pending
+                        .keys()
+                        .take_while(|time| {
+                            !input1.frontier().less_equal(time)
+                                && !input2.frontier().less_equal(time)
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>();
+
+                    for time in ready {
+                        let (cap, lefts, rights) = pending.remove(&time).unwrap();
+                        let mut session = output.session(&cap);
+
+                        // Left changes against the right side as it was before this timestamp
+                        for (data, diff) in lefts.iter() {
+                            let value = match SortKey::from_value(&data.get(&left_index.column)) {
+                                Some(value) => value,
+                                None => continue,
+                            };
+                            for (record, count) in right_index.range(bounds(&index.operator, value))
+                            {
+                                if matches(&conditions, data, record) {
+                                    let merged = data.clone().merge(record.clone());
+                                    session.give((merged, time, diff * count));
+                                }
+                            }
+                        }
+                        for (data, diff) in lefts.iter() {
+                            left_index.update(data, *diff);
+                        }
+
+                        // Right changes against the left side including this timestamp
+                        let mirrored = index.flipped();
+                        for (data, diff) in rights.iter() {
+                            let value = match SortKey::from_value(&data.get(&right_index.column)) {
+                                Some(value) => value,
+                                None => continue,
+                            };
+                            for (record, count) in
+                                left_index.range(bounds(&mirrored.operator, value))
+                            {
+                                if matches(&conditions, record, data) {
+                                    let merged = record.clone().merge(data.clone());
+                                    session.give((merged, time, count * diff));
+                                }
+                            }
+                        }
+                        for (data, diff) in rights.iter() {
+                            right_index.update(data, *diff);
+                        }
+                    }
+                }
+            },
+        )
+        .as_collection()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use differential_dataflow::input::Input;
+    use differential_dataflow::operators::Consolidate;
+    use serde_json::Value;
+    use timely::dataflow::ProbeHandle;
+
+    use super::*;
+    use crate::core::parser::RowProperty;
+
+    fn record(table: &str, id: u64, value: u64) -> DBRecord {
+        let mut record = DBRecord::new();
+        record.0.insert(format!("{}.id", table), Value::from(id));
+        record
+            .0
+            .insert(format!("{}.value", table), Value::from(value));
+        record
+    }
+
+    fn less_than() -> Vec<JoinCondition> {
+        vec![JoinCondition {
+            left: RowProperty {
+                table: "l".to_string(),
+                row: "value".to_string(),
+            },
+            operator: "<".to_string(),
+            right: RowProperty {
+                table: "r".to_string(),
+                row: "value".to_string(),
+            },
+        }]
+    }
+
+    // Feed the updates of both sides at their times, all inputs are advanced to `until` at
+    // once so updates of later times arrive before those of earlier ones
+    fn join(
+        lefts: Vec<(DBRecord, usize, isize)>,
+        rights: Vec<(DBRecord, usize, isize)>,
+        until: usize,
+    ) -> Vec<(DBRecord, usize, isize)> {
+        timely::execute_directly(move |worker| {
+            let output = Rc::new(RefCell::new(vec![]));
+            let mut probe = ProbeHandle::new();
+            let captured = Rc::clone(&output);
+            let (mut left, mut right) = worker.dataflow::<usize, _, _>(|scope| {
+                let (left_input, left) = scope.new_collection();
+                let (right_input, right) = scope.new_collection();
+                range_join(&left, &right, less_than())
+                    .consolidate()
+                    .inspect(move |update| captured.borrow_mut().push(update.clone()))
+                    .probe_with(&mut probe);
+                (left_input, right_input)
+            });
+            for (record, time, diff) in lefts {
+                left.update_at(record, time, diff);
+            }
+            for (record, time, diff) in rights {
+                right.update_at(record, time, diff);
+            }
+            left.advance_to(until);
+            right.advance_to(until);
+            left.flush();
+            right.flush();
+            worker.step_while(|| probe.less_than(&until));
+            let mut output = output.borrow().clone();
+            output.sort_by(|left, right| (left.1, &left.0).cmp(&(right.1, &right.0)));
+            output
+        })
+    }
+
+    #[test]
+    fn joins_records_within_range() {
+        let output = join(
+            vec![(record("l", 1, 5), 0, 1)],
+            vec![(record("r", 1, 3), 0, 1), (record("r", 2, 7), 0, 1)],
+            1,
+        );
+        let expected = record("l", 1, 5).merge(record("r", 2, 7));
+        assert_eq!(output, vec![(expected, 0, 1)]);
+    }
+
+    #[test]
+    fn retracts_pairs_of_removed_records() {
+        let output = join(
+            vec![(record("l", 1, 5), 0, 1), (record("l", 1, 5), 2, -1)],
+            vec![(record("r", 1, 7), 1, 1)],
+            3,
+        );
+        let pair = record("l", 1, 5).merge(record("r", 1, 7));
+        assert_eq!(output, vec![(pair.clone(), 1, 1), (pair, 2, -1)]);
+    }
+
+    #[test]
+    fn applies_updates_in_time_order_when_they_arrive_out_of_order() {
+        // The right record is removed at 2 before it is inserted at 1
+        let output = join(
+            vec![(record("l", 1, 5), 0, 1), (record("l", 2, 1), 3, 1)],
+            vec![(record("r", 1, 7), 2, -1), (record("r", 1, 7), 1, 1)],
+            4,
+        );
+        let pair = record("l", 1, 5).merge(record("r", 1, 7));
+        assert_eq!(output, vec![(pair.clone(), 1, 1), (pair, 2, -1)]);
+    }
+
+    #[test]
+    fn changes_of_both_sides_at_the_same_time_are_joined_once() {
+        let output = join(
+            vec![(record("l", 1, 5), 1, 1)],
+            vec![(record("r", 1, 7), 1, 1)],
+            2,
+        );
+        let pair = record("l", 1, 5).merge(record("r", 1, 7));
+        assert_eq!(output, vec![(pair, 1, 1)]);
+    }
+}
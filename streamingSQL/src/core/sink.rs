@@ -1,56 +1,154 @@
 use std::{
-    env,
-    sync::Arc,
-    time::{Duration, Instant},
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use futures::FutureExt;
-use tokio_postgres::{Client, GenericClient};
-use tracing::{info, warn};
+use differential_dataflow::Collection;
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::Capability;
+use timely::dataflow::{Scope, Stream};
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tokio_postgres::Client;
+use tracing::{debug, error, info, warn};
 
-use crate::pg_client::replication;
+use crate::core::types::dataflow_types::{sql_type, DBRecord, RecordType};
+use crate::pg_client::catalog::{create_progress_table, read_progress, record_progress_sql};
+use crate::pg_client::replication::{self, StreamStatus};
+use crate::pg_client::schema::{db_config, quote_identifier};
 
+// Attempts to write a transaction to a sink table before the view is stopped, the delay
+// between them doubles with every failed attempt
+const WRITE_ATTEMPTS: u32 = 3;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+type Updates = Vec<(DBRecord, isize)>;
+
+/// The table a view is written to.
+///
+/// Dataflows don't keep their state across restarts, every run rebuilds its views from a
+/// snapshot of the source tables. The first write of a run replaces the rows of the table, so
+/// it never mixes the rows of two runs. The progress table tells up to which dataflow time the
+/// rows were written, also to readers of the table.
 #[derive(Debug, Clone)]
 pub struct Sink {
     pub client: Arc<Client>,
+    // Runtime of the client's connection, the dataflow worker blocks on it to write
+    runtime: Handle,
     pub table: String,
     columns: Vec<String>,
-    data: Vec<String>,
     table_created: bool,
+    // Whether this run wrote the table already
+    replaced: bool,
+    // Shared by the clones of the sink: why the view stopped, and whether the columns of the
+    // table may have to follow a schema change
+    stopped: Arc<Mutex<Option<String>>>,
+    evolve: Arc<AtomicBool>,
+    // Where the view reports why it stopped
+    status: Arc<watch::Sender<StreamStatus>>,
 }
 
 impl Sink {
-    pub async fn new(table: String) -> Self {
-        let ev = |name| env::var(name).unwrap();
-        let db_config = format!(
-            "user={} password={} host={} port={} dbname={}",
-            ev("DB_USER"),
-            ev("DB_PASSWORD"),
-            ev("DB_ADDR"),
-            ev("DB_PORT"),
-            "postgres"
-        );
-        let repl_client = Arc::new(
-            replication::DBClient::new(&format!("{}", db_config))
-                .await
-                .unwrap()
-                .client,
-        );
-        Sink {
+    pub async fn new(
+        table: String,
+        status: Arc<watch::Sender<StreamStatus>>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        let repl_client = Arc::new(replication::DBClient::new(&db_config()).await?.client);
+        if let Err(e) = create_progress_table(&repl_client).await {
+            warn!("Error creating progress table: {}", e);
+        }
+        let upto = match read_progress(&repl_client, &table).await {
+            Ok(upto) => upto,
+            Err(e) => {
+                warn!("Error reading the progress of {}: {}", table, e);
+                None
+            }
+        };
+        if let Some(upto) = upto {
+            info!(
+                "{} was written up to {} by an earlier run, rebuilding it",
+                table, upto
+            );
+        }
+        status.send_modify(|status| {
+            status.stopped_views.remove(&table);
+        });
+        Ok(Sink {
             client: repl_client,
+            runtime: Handle::current(),
             table,
             columns: vec![],
-            data: vec![],
-            table_created: false,
+            // The progress is recorded together with the rows, so the table exists
+            table_created: upto.is_some(),
+            replaced: false,
+            stopped: Arc::new(Mutex::new(None)),
+            evolve: Arc::new(AtomicBool::new(false)),
+            status,
+        })
+    }
+
+    // Stop writing the view, its table keeps the rows written so far
+    pub fn stop(&self, reason: String) {
+        error!("Stopping view {}: {}", self.table, reason);
+        *self.stopped.lock().unwrap() = Some(reason.clone());
+        self.status.send_modify(|status| {
+            status.stopped_views.insert(self.table.clone(), reason);
+        });
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped.lock().unwrap().is_some()
+    }
+
+    // Alter the table to the types of the next rows written
+    pub fn evolve(&self) {
+        self.evolve.store(true, Ordering::SeqCst);
+    }
+
+    // Add the columns of `record` missing from the table and retype the others to its values,
+    // if a schema change asked for it
+    pub fn evolve_schema(&mut self, record: &DBRecord) {
+        if !self.evolve.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let mut statements = vec![];
+        for (column, value) in record.0.iter().filter(|(_, value)| !value.is_null()) {
+            let Some(sql_type) = sql_type(value) else {
+                continue;
+            };
+            let column = quote_identifier(column);
+            statements.push(format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {};",
+                self.table, column, sql_type
+            ));
+            statements.push(format!(
+                "ALTER TABLE {} ALTER COLUMN {} TYPE {} USING {}::{};",
+                self.table, column, sql_type, column, sql_type
+            ));
         }
+        info!("Evolving table {}: {}", self.table, statements.join(" "));
+        if let Err(e) = self
+            .runtime
+            .block_on(self.client.batch_execute(&statements.join(" ")))
+        {
+            self.stop(format!("Failed to alter the table: {}", e));
+        }
+        self.set_columns(record.get_sql_columns());
     }
     pub fn set_schema(&mut self, schema: String) {
-        let run_time = tokio::runtime::Runtime::new().unwrap();
-        let mut sql = format!("CREATE TABLE IF NOT EXISTS {} ", self.table);
+        let sql = format!("CREATE TABLE IF NOT EXISTS {} ", self.table);
 
         let schema = sql + &schema;
 
-        match run_time.block_on(self.client.execute(schema.as_str(), &[])) {
+        match self
+            .runtime
+            .block_on(self.client.execute(schema.as_str(), &[]))
+        {
             Ok(_) => {
                 self.table_created = true;
             }
@@ -63,30 +161,221 @@ impl Sink {
         self.columns = columns;
     }
 
-    pub fn insert(&mut self, data: &mut Vec<String>) {
-        self.data.append(data);
+    // Write the updates of a completed time, after which the table reflects every time up to
+    // `upto`. A failed write is retried, then the view stops.
+    pub fn write(&mut self, time: usize, upto: usize, updates: &[(DBRecord, isize)]) {
+        if self.stopped() {
+            return;
+        }
+        if let Some((record, _)) = updates.iter().find(|(_, diff)| *diff > 0) {
+            if !self.table_created {
+                self.set_schema(record.create_sql_schema());
+                self.set_columns(record.get_sql_columns());
+            }
+            self.evolve_schema(record);
+        }
+        // Without rows the view has no table to write to yet
+        if !self.table_created {
+            return;
+        }
+        let mut statements = vec![];
+        if !self.replaced {
+            statements.push(format!("DELETE FROM {};", self.table));
+        }
+        for (record, diff) in updates {
+            let statement =
+                record.to_sql_values(RecordType::from_value(diff.signum()), self.table.clone());
+            // Duplicate rows are written once per multiplicity
+            statements.extend(std::iter::repeat_n(statement, diff.unsigned_abs()));
+        }
+        let upto = upto.max(time);
+        let mut delay = WRITE_RETRY_DELAY;
+        for attempt in 1..=WRITE_ATTEMPTS {
+            match self.execute_transaction(&statements, upto) {
+                Ok(()) => {
+                    self.replaced = true;
+                    return;
+                }
+                Err(e) if attempt == WRITE_ATTEMPTS => {
+                    self.stop(format!("Failed to write time {}: {}", time, e));
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to write {} (attempt {}), retrying in {:?}: {}",
+                        self.table, attempt, delay, e
+                    );
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                }
+            }
+        }
+    }
+
+    // Write the statements and the progress of the table in one transaction, so the progress
+    // always matches the rows
+    fn execute_transaction(
+        &self,
+        statements: &[String],
+        upto: usize,
+    ) -> Result<(), tokio_postgres::Error> {
+        let mut sql = vec!["BEGIN TRANSACTION;".to_string()];
+        sql.extend(statements.iter().cloned());
+        sql.push(record_progress_sql(&self.table, upto));
+        sql.push("COMMIT TRANSACTION;".to_string());
+        let sql = sql.join(" ");
+        debug!("Executing query: {}", sql);
+        let result = self
+            .runtime
+            .block_on(self.client.batch_execute(sql.as_str()));
+        if result.is_err() {
+            let _ = self.runtime.block_on(self.client.batch_execute("ROLLBACK"));
+        }
+        result
     }
+}
 
-    fn save(&mut self) {
-        let run_time = tokio::runtime::Runtime::new().unwrap();
+/// Hands the updates of `collection` to `write` one time at a time, once the frontier passed
+/// that time so no more updates can arrive for it. Times are written in order, and `write`
+/// also receives the time up to which everything is written after it. Time 0 is written even
+/// without updates, the first write of a run replaces what an earlier run wrote. The
+/// returned stream has no data, its frontier passes a time once that time is written.
+pub fn write_completed<G, W>(
+    collection: &Collection<G, DBRecord, isize>,
+    mut write: W,
+) -> Stream<G, ()>
+where
+    G: Scope<Timestamp = usize>,
+    W: FnMut(usize, usize, &[(DBRecord, isize)]) + 'static,
+{
+    collection
+        .inner
+        .unary_frontier(Pipeline, "WriteCompleted", move |capability, _| {
+            let mut pending: BTreeMap<usize, (Capability<usize>, Updates)> = BTreeMap::new();
+            pending.insert(0, (capability, vec![]));
+            let mut buffer = Vec::new();
+
+            move |input, _output| {
+                input.for_each(|cap, data| {
+                    data.swap(&mut buffer);
+                    for (record, time, diff) in buffer.drain(..) {
+                        pending
+                            .entry(time)
+                            .or_insert_with(|| (cap.delayed(&time), vec![]))
+                            .1
+                            .push((record, diff));
+                    }
+                });
+                while let Some(time) = pending
+                    .keys()
+                    .next()
+                    .copied()
+                    .filter(|time| !input.frontier().less_equal(time))
+                {
+                    let (_capability, updates) = pending.remove(&time).unwrap();
+                    // Written up to the next time that may still change
+                    let next = pending
+                        .keys()
+                        .next()
+                        .copied()
+                        .into_iter()
+                        .chain(input.frontier().frontier().iter().copied())
+                        .min();
+                    let upto = next.map_or(time, |next| next - 1);
+                    write(time, upto, &updates);
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use differential_dataflow::input::Input;
+    use serde_json::Value;
+    use timely::dataflow::operators::Probe;
+    use timely::dataflow::ProbeHandle;
+
+    use super::*;
+
+    type Write = (usize, usize, Vec<(DBRecord, isize)>);
+
+    fn record(id: u64) -> DBRecord {
+        let mut record = DBRecord::new();
+        record.0.insert("id".to_string(), Value::from(id));
+        record
+    }
+
+    #[test]
+    fn writes_a_time_once_all_its_updates_arrived() {
+        timely::execute_directly(|worker| {
+            let writes: Rc<RefCell<Vec<Write>>> = Rc::new(RefCell::new(vec![]));
+            let captured = Rc::clone(&writes);
+            let mut probe = ProbeHandle::new();
+            let mut input = worker.dataflow::<usize, _, _>(|scope| {
+                let (input, collection) = scope.new_collection();
+                write_completed(&collection, move |time, upto, updates| {
+                    let mut updates = updates.to_vec();
+                    updates.sort();
+                    captured.borrow_mut().push((time, upto, updates))
+                })
+                .probe_with(&mut probe);
+                input
+            });
+            // The updates of time 5 arrive in separate messages
+            input.advance_to(1);
+            input.update_at(record(1), 5, 1);
+            input.flush();
+            worker.step_while(|| probe.less_than(&1));
+            assert_eq!(*writes.borrow(), vec![(0, 0, vec![])]);
+            input.update_at(record(2), 5, 1);
+            input.flush();
+            for _ in 0..10 {
+                worker.step();
+            }
+            assert_eq!(writes.borrow().len(), 1);
 
-        let mut sql = "".to_string();
-        let values = self.data.drain(..).collect::<Vec<String>>().join(" ");
-        sql.push_str(&values);
-        info!("Executed query: {}", sql);
-        run_time
-            .block_on(self.client.batch_execute(sql.as_str()))
-            .unwrap();
+            input.advance_to(10);
+            input.flush();
+            worker.step_while(|| probe.less_than(&10));
+            assert_eq!(
+                writes.borrow()[1],
+                (5, 9, vec![(record(1), 1), (record(2), 1)])
+            );
+        });
     }
 
-    pub fn execute_transaction(&mut self) {
-        let run_time = tokio::runtime::Runtime::new().unwrap();
-        run_time
-            .block_on(self.client.execute("BEGIN TRANSACTION", &[]))
-            .unwrap();
-        self.save();
-        run_time
-            .block_on(self.client.execute("COMMIT TRANSACTION", &[]))
-            .unwrap();
+    #[test]
+    fn writes_times_in_order() {
+        timely::execute_directly(|worker| {
+            let writes: Rc<RefCell<Vec<Write>>> = Rc::new(RefCell::new(vec![]));
+            let captured = Rc::clone(&writes);
+            let mut probe = ProbeHandle::new();
+            let mut input = worker.dataflow::<usize, _, _>(|scope| {
+                let (input, collection) = scope.new_collection();
+                write_completed(&collection, move |time, upto, updates| {
+                    let mut updates = updates.to_vec();
+                    updates.sort();
+                    captured.borrow_mut().push((time, upto, updates))
+                })
+                .probe_with(&mut probe);
+                input
+            });
+            input.update_at(record(1), 7, 1);
+            input.update_at(record(2), 3, 1);
+            input.update_at(record(2), 7, -1);
+            // Once the input is closed, the last time is written up to itself
+            drop(input);
+            worker.step_while(|| !probe.done());
+            assert_eq!(
+                *writes.borrow(),
+                vec![
+                    (0, 2, vec![]),
+                    (3, 6, vec![(record(2), 1)]),
+                    (7, 7, vec![(record(1), 1), (record(2), -1)]),
+                ]
+            );
+        });
     }
 }
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use crate::core::binder::Binder;
+use crate::core::error::QueryError;
+use crate::core::parser::{stable_hash, Query};
+use crate::core::plan::LogicalPlan;
+use crate::pg_client::schema::TableSchema;
+
+/// A view query with `$n` placeholders, e.g. a per tenant threshold.
+///
+/// The template is bound and planned once. Every instance substitutes its arguments into that
+/// plan and writes to its own sink table.
+#[derive(Debug, Clone)]
+pub struct ViewTemplate {
+    pub name: String,
+    pub query: Query,
+    pub plan: LogicalPlan,
+    // Catalog the template was bound against, arguments are checked with it
+    schemas: HashMap<String, TableSchema>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ViewInstance {
+    pub table_name: String,
+    // The template query with the arguments substituted
+    pub query: Query,
+    pub plan: LogicalPlan,
+}
+
+impl ViewTemplate {
+    pub fn new(
+        name: String,
+        query: Query,
+        plan: LogicalPlan,
+        schemas: HashMap<String, TableSchema>,
+    ) -> Self {
+        ViewTemplate {
+            name,
+            query,
+            plan,
+            schemas,
+        }
+    }
+
+    pub fn instantiate(&self, arguments: &[String]) -> Result<ViewInstance, QueryError> {
+        let parameters = self.query.parameters();
+        if arguments.len() != parameters {
+            return Err(QueryError::Unsupported {
+                message: format!(
+                    "Template {} expects {} arguments, got {}",
+                    self.name,
+                    parameters,
+                    arguments.len()
+                ),
+                span: None,
+            });
+        }
+        // Binding the substituted query applies the same type checks as for literals
+        let query = Binder::new(self.schemas.clone()).bind(self.query.with_arguments(arguments))?;
+
+        // Arguments can't contain a NUL, so every list of them is joined differently
+        let hash = stable_hash(&arguments.join("\0"));
+        Ok(ViewInstance {
+            table_name: format!("{}_{:016x}", self.name, hash),
+            query,
+            plan: self.plan.with_arguments(arguments),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::explain::explain_plan;
+    use crate::core::optimizer::optimize;
+    use crate::core::parser::parse_query;
+    use crate::pg_client::schema::{Column, Key, KeyType};
+
+    fn template(sql: &str) -> ViewTemplate {
+        let column = |name: &str, data_type: &str| Column {
+            name: name.to_string(),
+            data_type: data_type.to_string(),
+        };
+        let orders = TableSchema {
+            columns: vec![
+                column("id", "integer"),
+                column("status", "text"),
+                column("total", "numeric"),
+            ],
+            keys: vec![Key {
+                column_name: "id".to_string(),
+                key_type: KeyType::PrimaryKey,
+            }],
+            indexed_columns: vec![],
+        };
+        let schemas = HashMap::from([("Order".to_string(), orders)]);
+        let query = Binder::new(schemas.clone())
+            .bind(parse_query(sql).unwrap())
+            .unwrap();
+        let plan = optimize(LogicalPlan::from_query(&query));
+        ViewTemplate::new("orders_over".to_string(), query, plan, schemas)
+    }
+
+    fn arguments(arguments: &[&str]) -> Vec<String> {
+        arguments
+            .iter()
+            .map(|argument| argument.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn instances_need_an_argument_per_placeholder() {
+        let template = template(r#"SELECT id FROM "Order" WHERE total > $1 AND status = $2"#);
+        for wrong in [arguments(&["600"]), arguments(&["600", "paid", "x"])] {
+            match template.instantiate(&wrong) {
+                Err(QueryError::Unsupported { message, .. }) => assert_eq!(
+                    message,
+                    format!(
+                        "Template orders_over expects 2 arguments, got {}",
+                        wrong.len()
+                    )
+                ),
+                result => panic!("Expected an arity error, got {:?}", result),
+            }
+        }
+        assert!(template.instantiate(&arguments(&["600", "paid"])).is_ok());
+    }
+
+    #[test]
+    fn arguments_are_type_checked_like_literals() {
+        let template = template(r#"SELECT id FROM "Order" WHERE total > $1"#);
+        assert!(matches!(
+            template.instantiate(&arguments(&["many"])),
+            Err(QueryError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn instances_are_named_after_the_template_and_the_hash_of_their_arguments() {
+        let template = template(r#"SELECT id FROM "Order" WHERE total > $1 AND status = $2"#);
+        let name = |values: &[&str]| template.instantiate(&arguments(values)).unwrap().table_name;
+
+        assert_eq!(
+            name(&["600", "paid"]),
+            format!("orders_over_{:016x}", stable_hash("600\0paid"))
+        );
+        assert_eq!(name(&["600", "paid"]), name(&["600", "paid"]));
+        assert_ne!(name(&["600", "paid"]), name(&["700", "paid"]));
+        // The separator keeps arguments from running into each other
+        assert_ne!(name(&["60", "0paid"]), name(&["600", "paid"]));
+    }
+
+    #[test]
+    fn instances_substitute_their_arguments_into_the_plan() {
+        let template = template(r#"SELECT id FROM "Order" WHERE total > $1"#);
+        let instance = template.instantiate(&arguments(&["600"])).unwrap();
+        assert_eq!(
+            explain_plan(&instance.plan),
+            "Logical plan
+  Project Order.id
+    Filter Order.total > '600'
+      Get Order (id, total)"
+        );
+        assert_eq!(
+            instance.query.conditions[0].to_string(),
+            "Order.total > '600'"
+        );
+    }
+}
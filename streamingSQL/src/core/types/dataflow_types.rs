@@ -1,32 +1,14 @@
 use abomonation::{unsafe_abomonate, Abomonation};
-use arrayvec::ArrayString;
 use core::hash::Hash;
 use core::{fmt::Debug, panic};
-use serde_json::{Number, Value};
-use std::collections::hash_map::DefaultHasher;
-use std::collections::{BTreeMap, HashMap};
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::hash::Hasher;
-use std::sync::{Arc, Mutex};
-use tracing::{debug, warn};
+use tracing::debug;
 
-use crate::pg_client::data::{Insert, Update, WalData, WalEvent};
-
-unsafe_abomonate!(AbomonationWrapper<ArrayString<25>>);
-unsafe_abomonate!(AbomonationWrapper<ArrayString<40>>);
-unsafe_abomonate!(AbomonationWrapper<ArrayString<128>>);
-
-#[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Copy, Debug, Hash, Default)]
-pub struct AbomonationWrapper<T> {
-    pub element: T,
-}
-
-use ::std::ops::Deref;
-impl<T> Deref for AbomonationWrapper<T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        &self.element
-    }
-}
+use crate::core::parser::stable_hash;
+use crate::pg_client::data::{Insert, WalData, WalEvent};
+use crate::pg_client::schema::quote_identifier;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct DataflowInput {
@@ -43,38 +25,34 @@ impl DataflowInput {
                 WalData::Insert(insert) => {
                     let keys = get_required_key(required_key.clone(), change_event.clone());
                     input.push(DataflowInput {
-                        element: DataflowData(
-                            keys.primary,
-                            match insert {
-                                Insert(data) => (keys.foreign, DBRecord(data)),
-                                _ => (keys.foreign, DBRecord::new()),
-                            },
-                        ),
-                        time: usize::try_from(change_event.xid.clone())
-                            .expect("Failed to convert time"),
+                        element: DataflowData(keys.primary, (keys.foreign, DBRecord(insert.0))),
+                        time: change_event.time(),
                         change: 1,
                     })
                 }
                 WalData::Update(update) => {
                     let keys = get_required_key(required_key.clone(), change_event.clone());
+                    // The old row is retracted under its own key, which the update may have
+                    // changed. Without the old row the inputs retract the row they hold for
+                    // that key.
+                    let old_key = update
+                        .old_key
+                        .as_ref()
+                        .map_or(keys.primary, |key| key_to_usize(key.val.clone()));
+                    let old = update.old.clone().map_or_else(DBRecord::new, DBRecord);
+                    // The old row is retracted at the commit too, an update of a row updated
+                    // before in the same transaction cancels that earlier new row
                     input.push(DataflowInput {
-                        element: DataflowData(
-                            keys.clone().primary,
-                            (keys.foreign, DBRecord::new()),
-                        ),
-                        time: usize::try_from(change_event.xid.clone() - 1)
-                            .expect("Failed to convert time"),
+                        element: DataflowData(old_key, (keys.foreign, old)),
+                        time: change_event.time(),
                         change: -1,
                     });
                     input.push(DataflowInput {
                         element: DataflowData(
                             keys.primary,
-                            match update {
-                                Update(data) => (keys.foreign, DBRecord(data)),
-                                _ => (keys.foreign, DBRecord::new()),
-                            },
+                            (keys.foreign, DBRecord(update.values)),
                         ),
-                        time: usize::try_from(change_event.xid).expect("Failed to convert time"),
+                        time: change_event.time(),
                         change: 1,
                     });
                     debug!("Update event: {:?}", input);
@@ -85,14 +63,16 @@ impl DataflowInput {
 
                     input.push(DataflowInput {
                         element: DataflowData(keys.primary, (keys.foreign, DBRecord::new())),
-                        time: usize::try_from(change_event.xid.clone())
-                            .expect("Failed to convert time"),
+                        time: change_event.time(),
                         change: -1,
                     })
                 }
+                // The rows to retract are only known to the state of the inputs, schema
+                // changes are handled by the dataflow
+                WalData::Truncate | WalData::Schema(_) => {}
             }
         }
-        return input;
+        input
     }
 }
 fn get_required_key(required_key: Option<String>, change_event: WalEvent) -> Keys {
@@ -100,15 +80,9 @@ fn get_required_key(required_key: Option<String>, change_event: WalEvent) -> Key
     let foreign_key_val = match required_key {
         Some(key) => {
             let data = match change_event.data {
-                WalData::Insert(insert) => match insert {
-                    Insert(data) => data,
-                    _ => panic!("Failed to parse insert data"),
-                },
-                WalData::Update(update) => match update {
-                    Update(data) => data,
-                    _ => panic!("Failed to parse update data"),
-                },
-                WalData::Delete => BTreeMap::new(),
+                WalData::Insert(Insert(data)) => data,
+                WalData::Update(update) => update.values,
+                WalData::Delete | WalData::Truncate | WalData::Schema(_) => BTreeMap::new(),
             };
             let key = match data.get(&key) {
                 Some(val) => val.clone(),
@@ -127,22 +101,23 @@ fn get_required_key(required_key: Option<String>, change_event: WalEvent) -> Key
     }
 }
 
+// Rows are identified by the stable hash of their key, so keys of any type work and a key
+// maps to the same row in every run. Equal numbers give the same key whatever their type.
 fn key_to_usize(value: Value) -> usize {
-    match value {
-        Value::Number(num) => usize::try_from(num.as_u64().unwrap()).unwrap(),
-        Value::String(str) => {
-            let mut hasher = DefaultHasher::new();
-            str.hash(&mut hasher);
-            let hash = hasher.finish();
-            usize::try_from(hash).unwrap()
-        }
-        _ => {
-            panic!("Failed to parse primary key")
+    let text = match (value.as_i64(), value.as_f64()) {
+        (Some(num), _) => num.to_string(),
+        (None, Some(num)) if num.fract() == 0.0 && num.abs() < i64::MAX as f64 => {
+            (num as i64).to_string()
         }
-    }
+        _ => json_text(&value),
+    };
+    stable_hash(&text) as usize
 }
 
-#[derive(Clone, Debug)]
+// A row of a source table under its key. Rows compare by their values too, so the old and the
+// new row of an update are different updates even at the same time and with the same key.
+unsafe_abomonate!(DataflowData);
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct DataflowData(pub usize, pub (Option<usize>, DBRecord));
 
 impl IntoIterator for DataflowData {
@@ -150,11 +125,9 @@ impl IntoIterator for DataflowData {
     type IntoIter = std::vec::IntoIter<usize>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let mut vec = Vec::new();
-        vec.push(self.0);
-        match self.1 {
-            (Some(foreign), _) => vec.push(foreign),
-            _ => {}
+        let mut vec = vec![self.0];
+        if let (Some(foreign), _) = self.1 {
+            vec.push(foreign)
         }
         vec.into_iter()
     }
@@ -167,16 +140,6 @@ pub struct Keys {
     pub foreign: Option<usize>,
 }
 
-impl Keys {
-    pub fn swap(self) -> Self {
-        let mut clone = self.clone();
-        let temp = clone.primary;
-        clone.primary = clone.foreign.unwrap();
-        clone.foreign = Some(temp);
-        clone
-    }
-}
-
 #[derive(Clone, Debug)]
 pub enum RecordType {
     Insert = 1,
@@ -207,85 +170,52 @@ impl DBRecord {
             None => Value::Null,
         }
     }
-    pub fn get_key_for_value(&self, value: usize) -> Option<String> {
-        for (key, val) in self.0.iter() {
-            if val == &value {
-                return Some(key.clone());
-            }
-        }
-        None
-    }
-    pub fn as_raw_pointer(&self) -> usize {
-        let raw = Box::new(self.clone());
-        Box::into_raw(raw) as *const DBRecord as usize
-    }
-    pub fn to_sql_values(
-        &self,
-        record_type: RecordType,
-        table: String,
-        keys: Option<Vec<(String, usize)>>,
-    ) -> String {
+    pub fn to_sql_values(&self, record_type: RecordType, table: String) -> String {
         match record_type {
             RecordType::Insert => {
                 if self.0.is_empty() {
                     return "".to_string();
                 }
-                let mut sql_string = format!("INSERT INTO {} VALUES (", table);
-                for (_, value) in self.0.iter() {
-                    match value {
-                        Value::Number(num) => {
-                            sql_string.push_str(&format!(" {},", num));
-                        }
-                        Value::String(str) => {
-                            sql_string.push_str(&format!(" '{}',", str));
-                        }
-                        Value::Bool(bool) => {
-                            sql_string.push_str(&format!(" {},", bool));
-                        }
-                        _ => {}
-                    }
-                }
-                sql_string.pop();
-                sql_string.push_str(");");
-                return sql_string;
+                format!(
+                    "INSERT INTO {} ({}) VALUES ({});",
+                    table,
+                    self.get_sql_columns().join(", "),
+                    self.0
+                        .values()
+                        .map(sql_literal)
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                )
             }
             RecordType::Delete => {
-                let mut sql_string = format!("DELETE FROM {} WHERE", table);
-                let condition = keys
-                    .unwrap()
+                // Views have no key, so a single row with exactly these values is removed
+                let condition = self
+                    .0
                     .iter()
-                    .map(|(key, value)| format!(" \"{}\" = {}", key, value))
+                    .map(|(key, value)| match value {
+                        Value::Null => format!("{} IS NULL", quote_identifier(key)),
+                        value => format!("{} = {}", quote_identifier(key), sql_literal(value)),
+                    })
                     .collect::<Vec<String>>()
                     .join(" AND ");
-                sql_string.push_str(&condition);
-                sql_string.push_str(";");
-                return sql_string;
+                format!(
+                    "DELETE FROM {} WHERE ctid IN (SELECT ctid FROM {} WHERE {} LIMIT 1);",
+                    table, table, condition
+                )
             }
         }
     }
     pub fn get_sql_columns(&self) -> Vec<String> {
-        let mut columns = self
-            .0
+        self.0
             .keys()
-            .into_iter()
-            .map(|key| format!("\"{}\"", key))
-            .collect::<Vec<String>>();
-        columns
+            .map(|key| quote_identifier(key))
+            .collect::<Vec<String>>()
     }
     pub fn create_sql_schema(&self) -> String {
         let mut sql = "(".to_string();
         for (key, value) in self.0.iter() {
-            match value {
-                Value::Number(_) => {
-                    sql.push_str(&format!("\"{}\" INT, ", key));
-                }
-                Value::String(_) => {
-                    sql.push_str(&format!("\"{}\" TEXT, ", key));
-                }
-                Value::Bool(_) => {
-                    sql.push_str(&format!("\"{}\" BOOLEAN, ", key));
-                }
-                _ => {}
+            if let Some(sql_type) = sql_type(value) {
+                sql.push_str(&format!("{} {}, ", quote_identifier(key), sql_type));
             }
         }
         sql.pop();
@@ -293,25 +223,10 @@ impl DBRecord {
         sql.push_str(" );");
         sql
     }
-    pub fn from_raw_pointer(raw: usize) -> Self {
-        // Convert usize back to raw pointer
-        let pointer = raw as *const DBRecord;
-
-        // Use unsafe block for dereferencing and cloning
-        unsafe {
-            // Check for null pointer
-            if pointer.is_null() {
-                panic!("Attempted to dereference a null pointer");
-            }
-
-            // Dereference the pointer and clone the value
-            (*pointer).clone()
-        }
-    }
 
     pub fn merge(&mut self, mut other: DBRecord) -> DBRecord {
         self.0.append(&mut other.0);
-        return self.clone();
+        self.clone()
     }
     pub fn prefix_keys(&self, prefix: String) -> DBRecord {
         let mut record = BTreeMap::new();
@@ -321,42 +236,278 @@ impl DBRecord {
         DBRecord(record)
     }
 
+    // Select `(column, name)` pairs, renaming each column to its name
+    pub fn project(&self, columns: &[(String, String)]) -> DBRecord {
+        DBRecord(
+            columns
+                .iter()
+                .map(|(column, name)| (name.clone(), self.get(column)))
+                .collect(),
+        )
+    }
+
     pub fn pick(&self, keys: Vec<String>) -> DBRecord {
         let mut record = BTreeMap::new();
         for key in keys {
-            match self.0.get(&key) {
-                Some(value) => {
-                    record.insert(key, value.clone());
-                }
-                None => {}
+            if let Some(value) = self.0.get(&key) {
+                record.insert(key, value.clone());
             }
         }
         DBRecord(record)
     }
 }
 
-impl PartialEq for DataflowData {
+// Column type of a sink table holding the value. The type of a NULL is unknown, text accepts
+// any other value later on.
+pub fn sql_type(value: &Value) -> Option<&'static str> {
+    match value {
+        Value::Number(_) => Some("INT"),
+        Value::String(_) | Value::Null => Some("TEXT"),
+        Value::Bool(_) => Some("BOOLEAN"),
+        _ => None,
+    }
+}
+
+fn sql_literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Bool(bool) => bool.to_string(),
+        Value::Number(num) => num.to_string(),
+        Value::String(str) => format!("'{}'", str.replace('\'', "''")),
+        value => format!("'{}'", json_text(value).replace('\'', "''")),
+    }
+}
+
+fn json_text(value: &Value) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+fn value_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+// Total order on column values, so records can be used as data of a collection
+fn cmp_json(left: &Value, right: &Value) -> std::cmp::Ordering {
+    match (left, right) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .unwrap_or(0.0)
+            .total_cmp(&b.as_f64().unwrap_or(0.0)),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(_), Value::Array(_)) | (Value::Object(_), Value::Object(_)) => {
+            json_text(left).cmp(&json_text(right))
+        }
+        _ => value_rank(left).cmp(&value_rank(right)),
+    }
+}
+
+// Consistent with `cmp_json`, equal values have equal hashes
+fn hash_json<H: Hasher>(value: &Value, state: &mut H) {
+    value_rank(value).hash(state);
+    match value {
+        Value::Null => {}
+        Value::Bool(b) => b.hash(state),
+        Value::Number(num) => num.as_f64().unwrap_or(0.0).to_bits().hash(state),
+        Value::String(str) => str.hash(state),
+        value => json_text(value).hash(state),
+    }
+}
+
+impl PartialEq for DBRecord {
     fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for DBRecord {}
+
+impl PartialOrd for DBRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DBRecord {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let mut left = self.0.iter();
+        let mut right = other.0.iter();
+        loop {
+            let ordering = match (left.next(), right.next()) {
+                (None, None) => return std::cmp::Ordering::Equal,
+                (None, Some(_)) => return std::cmp::Ordering::Less,
+                (Some(_), None) => return std::cmp::Ordering::Greater,
+                (Some((left_key, left_value)), Some((right_key, right_value))) => left_key
+                    .cmp(right_key)
+                    .then_with(|| cmp_json(left_value, right_value)),
+            };
+            if ordering != std::cmp::Ordering::Equal {
+                return ordering;
+            }
+        }
+    }
+}
+
+impl Hash for DBRecord {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for (key, value) in self.0.iter() {
+            key.hash(state);
+            hash_json(value, state);
+        }
+    }
+}
+
+// Totally ordered view on a column value so records can be indexed for range lookups
+#[derive(Clone, Debug)]
+pub enum SortKey {
+    Bool(bool),
+    Number(f64),
+    Text(String),
+}
+
+impl SortKey {
+    pub fn from_value(value: &Value) -> Option<SortKey> {
+        match value {
+            Value::Bool(b) => Some(SortKey::Bool(*b)),
+            Value::Number(num) => num.as_f64().map(SortKey::Number),
+            Value::String(str) => Some(SortKey::Text(str.clone())),
+            _ => None,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            SortKey::Bool(_) => 0,
+            SortKey::Number(_) => 1,
+            SortKey::Text(_) => 2,
+        }
+    }
+}
+
+impl Hash for SortKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match self {
+            SortKey::Bool(b) => b.hash(state),
+            SortKey::Number(num) => num.to_bits().hash(state),
+            SortKey::Text(str) => str.hash(state),
+        }
     }
 }
 
-impl Hash for DataflowData {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.hash(state);
+impl PartialEq for SortKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
     }
 }
 
-impl Eq for DataflowData {}
+impl Eq for SortKey {}
 
-impl PartialOrd for DataflowData {
+impl PartialOrd for SortKey {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
-impl Ord for DataflowData {
+impl Ord for SortKey {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0.cmp(&other.0)
+        match (self, other) {
+            (SortKey::Bool(a), SortKey::Bool(b)) => a.cmp(b),
+            (SortKey::Number(a), SortKey::Number(b)) => a.total_cmp(b),
+            (SortKey::Text(a), SortKey::Text(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+// Compare two column values, `None` if either is NULL or their types differ
+pub fn compare_values(left: &Value, right: &Value) -> Option<std::cmp::Ordering> {
+    let left = SortKey::from_value(left)?;
+    let right = SortKey::from_value(right)?;
+    if left.rank() != right.rank() {
+        return None;
+    }
+    Some(left.cmp(&right))
+}
+
+// Check whether a comparison operator holds for the ordering of its operands
+pub fn operator_holds(operator: &str, ordering: std::cmp::Ordering) -> bool {
+    use std::cmp::Ordering::{Equal, Greater, Less};
+    match operator {
+        "=" => ordering == Equal,
+        "!=" | "<>" => ordering != Equal,
+        "<" => ordering == Less,
+        "<=" => ordering != Greater,
+        ">" => ordering == Greater,
+        ">=" => ordering != Less,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pg_client::data::{PKey, Update};
+
+    #[test]
+    fn keys_of_any_type_identify_rows() {
+        assert_eq!(key_to_usize(Value::from(7)), key_to_usize(Value::from(7.0)));
+        assert_eq!(key_to_usize(Value::from(-7)), key_to_usize(Value::from(-7)));
+        assert_ne!(key_to_usize(Value::from(7)), key_to_usize(Value::from(-7)));
+        assert_ne!(key_to_usize(Value::from(7)), key_to_usize(Value::from(7.5)));
+        assert_ne!(key_to_usize(Value::from(7)), key_to_usize(Value::from("7")));
+        assert_eq!(
+            key_to_usize(Value::from("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11")),
+            key_to_usize(Value::from("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11"))
+        );
+        key_to_usize(Value::Null);
+        key_to_usize(Value::Bool(true));
+    }
+
+    fn row(id: u64, name: &str) -> BTreeMap<String, Value> {
+        let mut row = BTreeMap::new();
+        row.insert("id".to_string(), Value::from(id));
+        row.insert("name".to_string(), Value::from(name));
+        row
+    }
+
+    fn update(lsn: u64, old: &str, new: &str) -> WalEvent {
+        WalEvent {
+            table: "users".to_string(),
+            timestamp: String::new(),
+            xid: 0,
+            lsn,
+            pkey: PKey {
+                col: "id".to_string(),
+                val: Value::from(1),
+            },
+            data: WalData::Update(Update {
+                values: row(1, new),
+                old_key: None,
+                old: Some(row(1, old)),
+            }),
+        }
+    }
+
+    #[test]
+    fn updates_retract_the_old_row_at_the_commit() {
+        let inputs = DataflowInput::from_wal_event(vec![update(40, "ada", "grace")], None);
+        let updates = inputs
+            .iter()
+            .map(|input| (input.element.1 .1.get("name"), input.time, input.change))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            updates,
+            vec![(Value::from("ada"), 40, -1), (Value::from("grace"), 40, 1)]
+        );
+        // Same key, different rows
+        assert_ne!(inputs[0].element, inputs[1].element);
     }
 }
@@ -0,0 +1,37 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::pg_client::progress::DataflowProgress;
+
+/// Follows the source transactions through the dataflow, and reports the ones all sinks have
+/// written.
+pub struct FlushTracker {
+    progress: Arc<DataflowProgress>,
+    // Transactions in the dataflow, as (lsn, time they were fed at)
+    fed: VecDeque<(u64, usize)>,
+}
+
+impl FlushTracker {
+    pub fn new(progress: Arc<DataflowProgress>) -> Self {
+        FlushTracker {
+            progress,
+            fed: VecDeque::new(),
+        }
+    }
+
+    pub fn fed(&mut self, lsn: u64, time: usize) {
+        self.fed.push_back((lsn, time));
+    }
+
+    // Report the transactions before the frontier of the sinks, `None` once the dataflow is
+    // done
+    pub fn flushed(&mut self, frontier: Option<usize>) {
+        while let Some((lsn, time)) = self.fed.front() {
+            if frontier.is_some_and(|frontier| *time >= frontier) {
+                break;
+            }
+            self.progress.flush(*lsn);
+            self.fed.pop_front();
+        }
+    }
+}
@@ -1,8 +1,6 @@
 use std::collections::HashMap;
 
-use differential_dataflow::{input::InputSession, Collection};
-use timely::worker::Worker;
-use tracing::info;
+use differential_dataflow::input::InputSession;
 
 use super::dataflow_types::DataflowData;
 
@@ -15,11 +13,7 @@ impl InputSessions {
             let input: InputSession<usize, DataflowData, isize> = InputSession::new();
             inputs.insert(table, input);
         }
-        return InputSessions(inputs);
-    }
-
-    pub fn insert(&mut self, key: String, value: InputSession<usize, DataflowData, isize>) {
-        self.0.insert(key, value);
+        InputSessions(inputs)
     }
 
     pub fn get(&mut self, key: &str) -> Option<&mut InputSession<usize, DataflowData, isize>> {
@@ -34,7 +28,7 @@ impl InputSessions {
     }
 
     pub fn time(&mut self) -> usize {
-        return *self.0.values_mut().last().unwrap().time();
+        *self.0.values_mut().last().unwrap().time()
     }
 
     pub fn flush(&mut self) {
@@ -55,5 +49,3 @@ impl InputSessions {
         }
     }
 }
-
-struct Arrangements(HashMap<String, InputSession<usize, DataflowData, isize>>);
@@ -1,4 +1,4 @@
-pub mod buffer;
 pub mod dataflow_types;
+pub mod flush;
 pub mod inputs;
 pub mod source;
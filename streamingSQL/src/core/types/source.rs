@@ -1,79 +1,46 @@
-use futures::task::noop_waker_ref;
-use std::collections::HashMap;
-use std::pin::Pin;
-use std::task::Context;
-use std::task::Poll;
-use tokio::sync::broadcast;
-use tracing::info;
+use tokio::sync::mpsc::{self, error::TryRecvError};
 use tracing::warn;
 
-use crate::pg_client::data::WalEvent;
+use crate::pg_client::data::Transaction;
 
+/// Input of a dataflow: the source transactions changing its tables, in commit order.
+///
+/// Every committed transaction is received, restricted to the tables of the dataflow, so a
+/// transaction at `lsn` also tells that no change up to `lsn` is outstanding. The channel is
+/// bounded, the stream waits for a source that falls behind rather than dropping changes.
 #[derive(Debug)]
 pub struct Source {
-    receivers: HashMap<String, tokio::sync::broadcast::Receiver<Vec<WalEvent>>>,
+    receiver: mpsc::Receiver<Transaction>,
     done: bool,
-    watermark: usize,
-}
-
-impl Clone for Source {
-    fn clone(&self) -> Self {
-        Self {
-            receivers: self
-                .receivers
-                .iter()
-                .map(|(k, v)| (k.clone(), v.resubscribe()))
-                .collect(),
-            done: self.done,
-            watermark: self.watermark,
-        }
-    }
 }
 
 impl Source {
-    pub fn new() -> Self {
+    pub fn new(receiver: mpsc::Receiver<Transaction>) -> Self {
         Self {
-            receivers: HashMap::new(),
+            receiver,
             done: false,
-            watermark: 0,
         }
     }
 
-    pub fn insert(&mut self, table: String, rx: broadcast::Receiver<Vec<WalEvent>>) {
-        self.receivers.insert(table, rx);
-    }
-
-    // Check if all receivers are done
+    // Check if the stream ended
     pub fn done(&self) -> bool {
         self.done
     }
 
-    // Fetch a bounded amount of input changes from receivers
-    pub fn fetch(&mut self) -> Option<Vec<(String, Vec<WalEvent>)>> {
-        let mut results = Vec::new();
-
-        // Iterate over each receiver
-        for (table, rx) in self.receivers.iter_mut() {
-            match rx.try_recv() {
-                Ok(events) => {
-                    results.push((table.clone(), events));
-                }
-                Err(broadcast::error::TryRecvError::Empty) => {
-                    // No more events to receive
-                    continue;
-                }
-                Err(broadcast::error::TryRecvError::Closed) => {
-                    warn!("Receiver for table: {} is closed", table);
+    // Fetch the transactions received so far
+    pub fn fetch(&mut self) -> Vec<Transaction> {
+        let mut transactions = Vec::new();
+        loop {
+            match self.receiver.try_recv() {
+                Ok(transaction) => transactions.push(transaction),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    warn!("Source stream is closed");
                     self.done = true;
-                }
-                Err(broadcast::error::TryRecvError::Lagged(_)) => {
-                    warn!("Receiver for table: {} is lagged", table);
+                    break;
                 }
             }
         }
-        if results.is_empty() {
-            return None;
-        }
-        return Some(results);
+        transactions
     }
 }
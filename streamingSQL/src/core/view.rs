@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+
+use crate::core::error::QueryError;
+use crate::core::parser::Query;
+use crate::core::plan::LogicalPlan;
+use crate::pg_client::schema::TableSchema;
+
+/// How a running view reacts when a source table it reads changes its columns. A view always
+/// stops when a column it reads is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchemaChangePolicy {
+    // Stop the view on any change of its source tables
+    #[default]
+    Fail,
+    // Keep running and alter the sink table to the types of the new rows
+    Evolve,
+    // Keep running, the view doesn't read added columns anyway
+    Ignore,
+}
+
+impl SchemaChangePolicy {
+    // The `on_schema_change` option of `CREATE VIEW name WITH (...)`, other options are
+    // rejected
+    pub fn from_options(options: &[(String, String)]) -> Result<Self, QueryError> {
+        let mut policy = SchemaChangePolicy::default();
+        for (name, value) in options {
+            if name != "on_schema_change" {
+                return Err(QueryError::Unsupported {
+                    message: format!("Unknown view option: {}", name),
+                    span: None,
+                });
+            }
+            policy = match value.to_lowercase().as_str() {
+                "fail" => SchemaChangePolicy::Fail,
+                "evolve" => SchemaChangePolicy::Evolve,
+                "ignore" => SchemaChangePolicy::Ignore,
+                _ => {
+                    return Err(QueryError::Unsupported {
+                        message: format!(
+                            "on_schema_change is one of fail, evolve or ignore, not {}",
+                            value
+                        ),
+                        span: None,
+                    })
+                }
+            };
+        }
+        Ok(policy)
+    }
+}
+
+/// A view created with `CREATE [MATERIALIZED] VIEW`, which later views can read like a table.
+///
+/// Readers don't go through the sink table. Their plans embed the view's plan, which the
+/// dataflow renders once, so every reader consumes the view's collection directly.
+#[derive(Debug, Clone)]
+pub struct ViewDefinition {
+    pub name: String,
+    // Sink table of a materialized view
+    pub table_name: Option<String>,
+    pub plan: LogicalPlan,
+    // Pairs of the plan's output column and the name readers use for it
+    pub columns: Vec<(String, String)>,
+    // Columns of the view as seen by the binder
+    pub schema: TableSchema,
+    pub on_schema_change: SchemaChangePolicy,
+}
+
+impl ViewDefinition {
+    // `names` are the column names of `CREATE VIEW name (names)`, without them a column is
+    // named like the selected column
+    pub fn new(
+        name: String,
+        table_name: Option<String>,
+        names: Vec<String>,
+        query: &Query,
+        plan: LogicalPlan,
+        schemas: &HashMap<String, TableSchema>,
+        on_schema_change: SchemaChangePolicy,
+    ) -> Result<Self, QueryError> {
+        let mut columns = query
+            .rows
+            .iter()
+            .map(|row| {
+                let column = schemas[&row.table].column(&row.row).unwrap();
+                (row.to_string(), column.clone())
+            })
+            .collect::<Vec<_>>();
+        if !names.is_empty() {
+            if names.len() != columns.len() {
+                return Err(QueryError::Unsupported {
+                    message: format!(
+                        "View {} names {} columns, its query has {}",
+                        name,
+                        names.len(),
+                        columns.len()
+                    ),
+                    span: None,
+                });
+            }
+            for ((_, column), name) in columns.iter_mut().zip(names) {
+                column.name = name;
+            }
+        }
+        for (i, (_, column)) in columns.iter().enumerate() {
+            if columns[..i]
+                .iter()
+                .any(|(_, other)| other.name == column.name)
+            {
+                return Err(QueryError::Unsupported {
+                    message: format!(
+                        "View {} has two columns named {}, name them with CREATE VIEW {} (...)",
+                        name, column.name, name
+                    ),
+                    span: None,
+                });
+            }
+        }
+
+        let schema = TableSchema {
+            columns: columns.iter().map(|(_, column)| column.clone()).collect(),
+            keys: vec![],
+            // Readers arrange the view inside the dataflow, so any column can be joined on
+            indexed_columns: columns
+                .iter()
+                .map(|(_, column)| column.name.clone())
+                .collect(),
+        };
+        Ok(ViewDefinition {
+            columns: columns
+                .into_iter()
+                .map(|(output, column)| (output, format!("{}.{}", name, column.name)))
+                .collect(),
+            table_name,
+            name,
+            plan,
+            schema,
+            on_schema_change,
+        })
+    }
+
+    // The view as input of another plan, restricted to the `columns` read from it
+    fn read(&self, columns: &Option<Vec<String>>) -> LogicalPlan {
+        let prefix = format!("{}.", self.name);
+        LogicalPlan::View {
+            name: self.name.clone(),
+            input: Box::new(self.plan.clone()),
+            columns: self
+                .columns
+                .iter()
+                .filter(|(_, column)| match columns {
+                    Some(columns) => columns
+                        .iter()
+                        .any(|read| column.strip_prefix(&prefix) == Some(read.as_str())),
+                    None => true,
+                })
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+// Replace the reads of views in an optimized plan by the plans of these views
+pub fn inline_views(plan: LogicalPlan, views: &HashMap<String, ViewDefinition>) -> LogicalPlan {
+    let inline = |input: Box<LogicalPlan>| Box::new(inline_views(*input, views));
+    match plan {
+        LogicalPlan::Get { table, columns } => match views.get(&table) {
+            Some(view) => view.read(&columns),
+            None => LogicalPlan::Get { table, columns },
+        },
+        LogicalPlan::Filter { input, predicates } => LogicalPlan::Filter {
+            input: inline(input),
+            predicates,
+        },
+        LogicalPlan::Project { input, columns } => LogicalPlan::Project {
+            input: inline(input),
+            columns,
+        },
+        LogicalPlan::Join {
+            left,
+            right,
+            conditions,
+        } => LogicalPlan::Join {
+            left: inline(left),
+            right: inline(right),
+            conditions,
+        },
+        // Already inlined views were planned with the views they read
+        plan @ LogicalPlan::View { .. } => plan,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::binder::Binder;
+    use crate::core::explain::explain_plan;
+    use crate::core::optimizer::optimize;
+    use crate::core::parser::parse_query;
+    use crate::pg_client::schema::{Column, Key, KeyType};
+
+    fn table(columns: &[(&str, &str)]) -> TableSchema {
+        TableSchema {
+            columns: columns
+                .iter()
+                .map(|(name, data_type)| Column {
+                    name: name.to_string(),
+                    data_type: data_type.to_string(),
+                })
+                .collect(),
+            keys: vec![Key {
+                column_name: columns[0].0.to_string(),
+                key_type: KeyType::PrimaryKey,
+            }],
+            indexed_columns: vec![],
+        }
+    }
+
+    fn schemas() -> HashMap<String, TableSchema> {
+        HashMap::from([
+            (
+                "User".to_string(),
+                table(&[("id", "integer"), ("name", "text")]),
+            ),
+            (
+                "Order".to_string(),
+                table(&[
+                    ("id", "integer"),
+                    ("buyerId", "integer"),
+                    ("total", "numeric"),
+                ]),
+            ),
+        ])
+    }
+
+    // Bind and plan the query against the tables and the views, like the coordinator does
+    fn plan(
+        sql: &str,
+        views: &HashMap<String, ViewDefinition>,
+    ) -> (Query, LogicalPlan, HashMap<String, TableSchema>) {
+        let mut schemas = schemas();
+        for view in views.values() {
+            schemas.insert(view.name.clone(), view.schema.clone());
+        }
+        let query = Binder::new(schemas.clone())
+            .bind(parse_query(sql).unwrap())
+            .unwrap();
+        let plan = inline_views(optimize(LogicalPlan::from_query(&query)), views);
+        (query, plan, schemas)
+    }
+
+    fn big_orders(names: Vec<String>) -> Result<ViewDefinition, QueryError> {
+        let (query, plan, schemas) = plan(
+            r#"SELECT "Order".id, "Order"."buyerId", "Order".total FROM "Order"
+            WHERE "Order".total > 600"#,
+            &HashMap::new(),
+        );
+        ViewDefinition::new(
+            "big_orders".to_string(),
+            Some("big_orders".to_string()),
+            names,
+            &query,
+            plan,
+            &schemas,
+            SchemaChangePolicy::default(),
+        )
+    }
+
+    #[test]
+    fn views_are_read_through_their_plan() {
+        let views = HashMap::from([("big_orders".to_string(), big_orders(vec![]).unwrap())]);
+        let (_, plan, _) = plan(
+            r#"SELECT "User".name, big_orders.total FROM big_orders
+            JOIN "User" ON "User".id = big_orders."buyerId""#,
+            &views,
+        );
+        assert_eq!(
+            explain_plan(&plan),
+            "Logical plan
+  Project User.name, big_orders.total
+    Join on big_orders.buyerId = User.id
+      View big_orders (Order.buyerId AS big_orders.buyerId, Order.total AS big_orders.total)
+        Project Order.id, Order.buyerId, Order.total
+          Filter Order.total > '600'
+            Get Order (buyerId, id, total)
+      Get User (id, name)"
+        );
+        // The source table of the view is replicated for the reader, the view itself isn't
+        assert_eq!(plan.tables(), vec!["big_orders", "User"]);
+        assert_eq!(plan.source_tables(), vec!["Order", "User"]);
+    }
+
+    #[test]
+    fn view_columns_can_be_renamed() {
+        let view = big_orders(vec!["id".into(), "buyer".into(), "amount".into()]).unwrap();
+        assert_eq!(
+            view.columns,
+            vec![
+                ("Order.id".to_string(), "big_orders.id".to_string()),
+                ("Order.buyerId".to_string(), "big_orders.buyer".to_string()),
+                ("Order.total".to_string(), "big_orders.amount".to_string()),
+            ]
+        );
+        assert!(view.schema.column("amount").is_some());
+        assert!(view.schema.is_keyed_or_indexed("buyer"));
+    }
+
+    #[test]
+    fn views_need_a_name_for_every_column() {
+        assert!(matches!(
+            big_orders(vec!["id".into()]),
+            Err(QueryError::Unsupported { message, .. }) if message.contains("names 1 columns")
+        ));
+        assert!(matches!(
+            big_orders(vec!["id".into(), "id".into(), "total".into()]),
+            Err(QueryError::Unsupported { message, .. }) if message.contains("two columns named id")
+        ));
+    }
+}
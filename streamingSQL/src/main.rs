@@ -1,10 +1,12 @@
 use tracing::level_filters::LevelFilter;
+use tracing::{error, info};
 use tracing_subscriber::filter::EnvFilter;
 use tracing_subscriber::fmt::{self};
 
 mod core;
 mod pg_client;
 use core::coordinator::Coordinator;
+use core::explain::parse_explain;
 
 // #[get("/view")]
 // async fn create_view() -> impl Responder {
@@ -25,8 +27,59 @@ async fn main() -> Result<(), tokio_postgres::Error> {
     dotenv::dotenv().ok();
     init_logger();
     let mut coordinator = Coordinator::new();
-    let query = r#"SELECT "User".id, "User".name, "Order".total FROM "User" JOIN "Order" ON "User".id = "Order"."buyerId" WHERE "Order".total > 600"#;
-    coordinator.process_view_query(query).await.unwrap();
+    // `--teardown` drops the replication slot and publication left by earlier runs
+    if std::env::args().any(|arg| arg == "--teardown") {
+        return coordinator.teardown().await;
+    }
+    // `CREATE VIEW` statements only define views, materialized ones are started together
+    // afterwards
+    let queries = [
+        r#"CREATE MATERIALIZED VIEW big_orders AS SELECT "Order".id, "Order"."buyerId", "Order".total FROM "Order" WHERE "Order".total > 600"#,
+        r#"CREATE MATERIALIZED VIEW user_big_orders AS SELECT "User".name, big_orders.total FROM big_orders JOIN "User" ON "User".id = big_orders."buyerId""#,
+    ];
+    for query in queries {
+        // `EXPLAIN [PHYSICAL] <query>` only prints how the view would be built
+        let (query, result) = match parse_explain(query) {
+            Some((stage, query)) => (
+                query,
+                coordinator
+                    .explain(stage, query)
+                    .await
+                    .map(|explained| println!("{}", explained)),
+            ),
+            None => (query, coordinator.process_view_query(query).await),
+        };
+        if let Err(e) = result {
+            error!("Failed to create view:\n{}", e.report(query));
+        }
+    }
+    let mut status = coordinator.status();
+    tokio::spawn(async move {
+        while status.changed().await.is_ok() {
+            info!("Status of the views: {:?}", *status.borrow());
+        }
+    });
+    // `--orders-over <total>...` starts a view of the orders over each total, all instances
+    // of one template, instead of the views defined above
+    let totals = std::env::args()
+        .skip_while(|arg| arg != "--orders-over")
+        .skip(1)
+        .map(|total| vec![total])
+        .collect::<Vec<_>>();
+    if !totals.is_empty() {
+        let template = r#"SELECT "Order".id, "Order".total FROM "Order" WHERE "Order".total > $1"#;
+        let result = match coordinator.register_template("orders_over", template).await {
+            Ok(()) => coordinator.instantiate_template("orders_over", totals).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Failed to start views:\n{}", e.report(template));
+        }
+        return Ok(());
+    }
+    if let Err(e) = coordinator.start_defined_views().await {
+        error!("Failed to start views: {}", e);
+    }
     Ok(())
 }
 
@@ -0,0 +1,117 @@
+use tokio_postgres::{Client, Error};
+use tracing::debug;
+
+use super::schema::connect;
+
+// Table in the source database recording which sink table each view writes to
+const CATALOG_TABLE: &str = "streaming_sql_views";
+// Table recording up to which dataflow time, the commit LSN of the source transactions, each
+// sink table is written. Updated in the same transaction as the sink table.
+const PROGRESS_TABLE: &str = "streaming_sql_progress";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogEntry {
+    pub name: String,
+    pub table_name: String,
+    // Stable hash of the normalized query, tells whether a view was created from another query
+    pub query_hash: String,
+    pub query: String,
+}
+
+async fn catalog_client() -> Result<Client, Error> {
+    let client = connect().await?;
+    client
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                name TEXT PRIMARY KEY,
+                table_name TEXT NOT NULL,
+                query_hash TEXT NOT NULL,
+                query TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+            CATALOG_TABLE
+        ))
+        .await?;
+    Ok(client)
+}
+
+pub async fn find_view(name: &str) -> Result<Option<CatalogEntry>, Error> {
+    let client = catalog_client().await?;
+    let row = client
+        .query_opt(
+            &format!(
+                "SELECT name, table_name, query_hash, query FROM {} WHERE name = $1",
+                CATALOG_TABLE
+            ),
+            &[&name],
+        )
+        .await?;
+    Ok(row.map(|row| CatalogEntry {
+        name: row.get(0),
+        table_name: row.get(1),
+        query_hash: row.get(2),
+        query: row.get(3),
+    }))
+}
+
+// Insert the entry, or update it when a view of that name was recorded before
+pub async fn record_view(entry: &CatalogEntry) -> Result<(), Error> {
+    let client = catalog_client().await?;
+    client
+        .execute(
+            &format!(
+                "INSERT INTO {} (name, table_name, query_hash, query) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (name) DO UPDATE
+                SET table_name = $2, query_hash = $3, query = $4",
+                CATALOG_TABLE
+            ),
+            &[
+                &entry.name,
+                &entry.table_name,
+                &entry.query_hash,
+                &entry.query,
+            ],
+        )
+        .await?;
+    debug!("Recorded view {} in the catalog", entry.name);
+    Ok(())
+}
+
+pub async fn create_progress_table(client: &Client) -> Result<(), Error> {
+    client
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                table_name TEXT PRIMARY KEY,
+                frontier BIGINT NOT NULL,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+            PROGRESS_TABLE
+        ))
+        .await
+}
+
+// Dataflow time up to which an earlier run wrote `table_name`, `None` if it never wrote it
+pub async fn read_progress(client: &Client, table_name: &str) -> Result<Option<usize>, Error> {
+    let row = client
+        .query_opt(
+            &format!(
+                "SELECT frontier FROM {} WHERE table_name = $1",
+                PROGRESS_TABLE
+            ),
+            &[&table_name],
+        )
+        .await?;
+    Ok(row.map(|row| row.get::<_, i64>(0) as usize))
+}
+
+// Statement recording that `table_name` is written up to `frontier`, for the transaction
+// writing the table
+pub fn record_progress_sql(table_name: &str, frontier: usize) -> String {
+    format!(
+        "INSERT INTO {} (table_name, frontier) VALUES ('{}', {})
+        ON CONFLICT (table_name) DO UPDATE SET frontier = EXCLUDED.frontier, updated_at = now();",
+        PROGRESS_TABLE,
+        table_name.replace('\'', "''"),
+        frontier
+    )
+}
@@ -1,74 +1,216 @@
 use std::collections::BTreeMap;
+use std::fmt;
 
 use serde_json::Value;
 
+use super::replication::ReplicationError;
+use super::schema::QualifiedName;
+
 #[derive(Debug, Clone)]
 pub struct WalEvent {
+    // Table of the change, named like in queries
+    pub table: String,
     pub timestamp: String,
     pub xid: i64,
+    // End of the commit record of the transaction, set when it's replicated. 0 for the rows
+    // of a snapshot.
+    pub lsn: u64,
     pub pkey: PKey,
     pub data: WalData,
 }
 
+/// A committed source transaction, restricted to the tables of one subscriber.
+///
+/// Transactions without changes for the subscriber still tell how far the stream got.
+/// Snapshot rows come in transactions at LSN 0.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub lsn: u64,
+    pub changes: Vec<WalEvent>,
+}
+
+impl Transaction {
+    pub fn time(&self) -> usize {
+        usize::try_from(self.lsn).expect("Failed to convert time")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PKey {
     pub col: String,
     pub val: Value,
 }
 
-impl WalEvent {
-    pub fn from_wal_json(value: Value) -> Self {
-        WalEvent {
-            timestamp: value["timestamp"].as_str().unwrap().to_string(),
-            xid: value["xid"].as_i64().unwrap(),
-            pkey: retrieve_pkey(value.clone()),
-            data: WalData::from_wal_json(value.clone()),
+impl PKey {
+    // Key of changes that don't refer to a single row
+    pub fn none() -> Self {
+        PKey {
+            col: String::new(),
+            val: Value::Null,
         }
     }
 }
-fn retrieve_pkey(value: Value) -> PKey {
-    if value["identity"].is_null() {
-        let key_col = value["pk"].as_array().unwrap()[0].as_object().unwrap();
-        let key_val = value["columns"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .find(|col| col["name"] == key_col["name"].as_str().unwrap())
-            .unwrap()
-            .as_object()
-            .unwrap();
-        return PKey {
-            col: key_col["name"].as_str().unwrap().to_string(),
-            val: key_val["value"].clone(),
+
+impl WalEvent {
+    // Dataflow time of the change. Commit LSNs order transactions like they committed, over
+    // all tables, so the dataflow sees a consistent prefix of the database at every time.
+    pub fn time(&self) -> usize {
+        usize::try_from(self.lsn).expect("Failed to convert time")
+    }
+
+    // The change of a wal2json record, an error if the record isn't a change it can read
+    pub fn from_wal_json(value: Value) -> Result<Self, ReplicationError> {
+        let name = QualifiedName {
+            schema: value["schema"].as_str().unwrap_or("public").to_string(),
+            table: value["table"]
+                .as_str()
+                .ok_or_else(|| decode_error("No table", &value))?
+                .to_string(),
         };
-    } else {
-        match value["identity"].as_array() {
-            Some(obj) => {
-                let identity = obj[0].as_object().unwrap();
-                return PKey {
-                    col: identity["name"].as_str().unwrap().to_string(),
-                    val: identity["value"].clone(),
-                };
-            }
-            None => panic!("No primary key found"),
+        let data = WalData::from_wal_json(&value)?;
+        // Deletes only carry the old key, inserts and updates are keyed by their new row
+        let pkey = match data {
+            WalData::Truncate | WalData::Schema(_) => Some(PKey::none()),
+            WalData::Delete => retrieve_pkey(&value, "identity"),
+            _ => retrieve_pkey(&value, "columns"),
         }
+        .ok_or_else(|| decode_error("No key", &value))?;
+        Ok(WalEvent {
+            table: name.query_name(),
+            // Format version 2 only sends them with the begin and commit records
+            timestamp: value["timestamp"].as_str().unwrap_or_default().to_string(),
+            xid: value["xid"].as_i64().unwrap_or_default(),
+            lsn: 0,
+            pkey,
+            data,
+        })
     }
 }
 
+fn decode_error(reason: &str, value: &Value) -> ReplicationError {
+    ReplicationError::Decode(format!("{} in {}", reason, value))
+}
+
+// Values of the columns in `field` of a wal2json change
+fn wal_json_values(
+    value: &Value,
+    field: &str,
+) -> Result<BTreeMap<String, Value>, ReplicationError> {
+    let columns = value[field]
+        .as_array()
+        .ok_or_else(|| decode_error(&format!("No {}", field), value))?;
+    columns
+        .iter()
+        .map(|column| {
+            let name = column["name"]
+                .as_str()
+                .ok_or_else(|| decode_error("Unnamed column", value))?;
+            Ok((name.to_string(), column["value"].clone()))
+        })
+        .collect()
+}
+
+// Key of the row in `field` of a wal2json change, `columns` for the new row and `identity`
+// for the old one. With REPLICA IDENTITY FULL the identity is the whole old row.
+fn retrieve_pkey(value: &Value, field: &str) -> Option<PKey> {
+    let columns = value[field].as_array()?;
+    let key_name = value["pk"][0]["name"].as_str();
+    let column = columns
+        .iter()
+        .find(|col| key_name.is_some_and(|name| col["name"] == name))
+        .or(columns.first())?;
+    Some(PKey {
+        col: column["name"].as_str()?.to_string(),
+        val: column["value"].clone(),
+    })
+}
+
 #[derive(Debug, Clone)]
 pub enum WalData {
     Insert(Insert),
     Update(Update),
     Delete,
+    // Removes every row of the table
+    Truncate,
+    // The columns of the table changed, following changes have the new columns
+    Schema(SchemaChange),
+}
+
+/// Columns of a table that changed between two of its changes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SchemaChange {
+    pub added: Vec<String>,
+    pub dropped: Vec<String>,
+    pub retyped: Vec<String>,
+}
+
+impl SchemaChange {
+    // Compare the columns of a table, as pairs of name and type, `None` if they're the same
+    pub fn between(before: &[(String, String)], after: &[(String, String)]) -> Option<Self> {
+        let find = |columns: &[(String, String)], name: &str| {
+            columns
+                .iter()
+                .find(|(column, _)| column == name)
+                .map(|(_, data_type)| data_type.clone())
+        };
+        let mut change = SchemaChange::default();
+        for (name, data_type) in after {
+            match find(before, name) {
+                None => change.added.push(name.clone()),
+                Some(before_type) if &before_type != data_type => change.retyped.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        for (name, _) in before {
+            if find(after, name).is_none() {
+                change.dropped.push(name.clone());
+            }
+        }
+        (change != SchemaChange::default()).then_some(change)
+    }
+}
+
+impl fmt::Display for SchemaChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            ("added", &self.added),
+            ("dropped", &self.dropped),
+            ("retyped", &self.retyped),
+        ]
+        .iter()
+        .filter(|(_, columns)| !columns.is_empty())
+        .map(|(kind, columns)| format!("{} {}", kind, columns.join(", ")))
+        .collect::<Vec<_>>();
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+// Name and type of the columns of a wal2json insert or update
+pub fn wal_json_columns(value: &Value) -> Vec<(String, String)> {
+    value["columns"]
+        .as_array()
+        .map(|columns| {
+            columns
+                .iter()
+                .map(|column| {
+                    (
+                        column["name"].as_str().unwrap_or("").to_string(),
+                        column["type"].as_str().unwrap_or("").to_string(),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 impl WalData {
-    pub fn from_wal_json(value: Value) -> Self {
-        match value["action"].as_str().unwrap() {
-            "I" => WalData::Insert(Insert::from_wal_json(value)),
-            "U" => WalData::Update(Update::from_wal_json(value)),
-            "D" => WalData::Delete,
-            _ => panic!("Invalid WAL data kind"),
+    pub fn from_wal_json(value: &Value) -> Result<Self, ReplicationError> {
+        match value["action"].as_str() {
+            Some("I") => Ok(WalData::Insert(Insert::from_wal_json(value)?)),
+            Some("U") => Ok(WalData::Update(Update::from_wal_json(value)?)),
+            Some("D") => Ok(WalData::Delete),
+            Some("T") => Ok(WalData::Truncate),
+            _ => Err(decode_error("No change", value)),
         }
     }
 }
@@ -77,28 +219,147 @@ impl WalData {
 pub struct Insert(pub BTreeMap<String, Value>);
 
 impl Insert {
-    pub fn from_wal_json(value: Value) -> Self {
-        let mut values = BTreeMap::new();
-        for col in value["columns"].as_array().unwrap() {
-            let key = col["name"].as_str().unwrap().to_string();
-            let value = col["value"].clone();
-            values.insert(key, value);
-        }
-        Insert(values)
+    pub fn from_wal_json(value: &Value) -> Result<Self, ReplicationError> {
+        Ok(Insert(wal_json_values(value, "columns")?))
     }
 }
 
+/// An updated row: its new values and what the change tells about the old row, which is
+/// retracted by the update.
 #[derive(Debug, Clone)]
-pub struct Update(pub BTreeMap<String, Value>);
+pub struct Update {
+    // Unchanged TOAST values are left out, they're taken from the old row
+    pub values: BTreeMap<String, Value>,
+    // Key of the old row, sent when the update changed the key or with REPLICA IDENTITY FULL
+    pub old_key: Option<PKey>,
+    // The whole old row, with REPLICA IDENTITY FULL
+    pub old: Option<BTreeMap<String, Value>>,
+}
 
 impl Update {
-    pub fn from_wal_json(value: Value) -> Self {
-        let mut values = BTreeMap::new();
-        for col in value["columns"].as_array().unwrap() {
-            let key = col["name"].as_str().unwrap().to_string();
-            let value = col["value"].clone();
-            values.insert(key, value);
+    pub fn from_wal_json(value: &Value) -> Result<Self, ReplicationError> {
+        let values = wal_json_values(value, "columns")?;
+        // The identity is the whole old row if it has columns outside of the key
+        let key_columns = value["pk"]
+            .as_array()
+            .map(|pk| {
+                pk.iter()
+                    .filter_map(|col| col["name"].as_str())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let full_identity = value["identity"].as_array().is_some_and(|identity| {
+            identity
+                .iter()
+                .any(|col| !key_columns.contains(&col["name"].as_str().unwrap_or("")))
+        });
+        let old = match full_identity {
+            true => Some(wal_json_values(value, "identity")?),
+            false => None,
+        };
+        Ok(Update {
+            values,
+            old_key: retrieve_pkey(value, "identity"),
+            old,
+        })
+    }
+
+    // Fill in the columns left out of the new values from the old row
+    pub fn merge_unchanged(&mut self, old: &BTreeMap<String, Value>) {
+        for (column, value) in old {
+            if !self.values.contains_key(column) {
+                self.values.insert(column.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(text: &str) -> Value {
+        serde_json::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn reads_changes_without_timestamp_or_xid() {
+        let record = record(
+            r#"{
+            "action": "I",
+            "schema": "public",
+            "table": "users",
+            "columns": [
+                {"name": "id", "type": "integer", "value": 7},
+                {"name": "name", "type": "text", "value": "ada"}
+            ],
+            "pk": [{"name": "id", "type": "integer"}]
+        }"#,
+        );
+        let event = WalEvent::from_wal_json(record).unwrap();
+        assert_eq!(event.table, "users");
+        assert_eq!(event.pkey.val, Value::from(7));
+        assert_eq!(event.xid, 0);
+        assert!(matches!(event.data, WalData::Insert(Insert(values)) if values["name"] == "ada"));
+    }
+
+    #[test]
+    fn reads_the_old_row_of_an_update_with_full_identity() {
+        let record = record(
+            r#"{
+            "action": "U",
+            "schema": "shop",
+            "table": "orders",
+            "columns": [
+                {"name": "id", "type": "integer", "value": 8},
+                {"name": "total", "type": "integer", "value": 10}
+            ],
+            "identity": [
+                {"name": "id", "type": "integer", "value": 7},
+                {"name": "total", "type": "integer", "value": 5}
+            ],
+            "pk": [{"name": "id", "type": "integer"}]
+        }"#,
+        );
+        let event = WalEvent::from_wal_json(record).unwrap();
+        assert_eq!(event.table, "shop.orders");
+        match event.data {
+            WalData::Update(update) => {
+                assert_eq!(update.old_key.map(|key| key.val), Some(Value::from(7)));
+                assert_eq!(update.old.unwrap()["total"], Value::from(5));
+            }
+            data => panic!("Expected an update, got {:?}", data),
         }
-        Update(values)
+    }
+
+    #[test]
+    fn rejects_records_that_are_no_changes() {
+        let unknown = record(r#"{"action": "M", "table": "users"}"#);
+        assert!(WalEvent::from_wal_json(unknown).is_err());
+        let no_columns = record(r#"{"action": "I", "table": "users"}"#);
+        assert!(WalEvent::from_wal_json(no_columns).is_err());
+        let no_key = record(r#"{"action": "D", "table": "users", "identity": []}"#);
+        assert!(WalEvent::from_wal_json(no_key).is_err());
+        let unnamed = record(r#"{"action": "I", "table": "users", "columns": [{"value": 1}]}"#);
+        assert!(WalEvent::from_wal_json(unnamed).is_err());
+    }
+
+    fn typed(columns: &[(&str, &str)]) -> Vec<(String, String)> {
+        columns
+            .iter()
+            .map(|(name, data_type)| (name.to_string(), data_type.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn compares_the_columns_of_a_table() {
+        let before = typed(&[("id", "int4"), ("name", "text"), ("age", "int4")]);
+        assert_eq!(SchemaChange::between(&before, &before), None);
+        let after = typed(&[("id", "int8"), ("email", "text"), ("name", "text")]);
+        let change = SchemaChange::between(&before, &after).unwrap();
+        assert_eq!(change.added, vec!["email"]);
+        assert_eq!(change.dropped, vec!["age"]);
+        assert_eq!(change.retyped, vec!["id"]);
+        assert_eq!(change.to_string(), "added email; dropped age; retyped id");
     }
 }
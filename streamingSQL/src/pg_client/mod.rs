@@ -1,5 +1,9 @@
+pub mod catalog;
 pub mod data;
+pub mod pgoutput;
+pub mod progress;
 pub mod publication;
 pub mod replication;
 pub mod schema;
+pub mod snapshot;
 pub mod stream;
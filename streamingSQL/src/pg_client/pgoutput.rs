@@ -0,0 +1,711 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{Number, Value};
+use tokio_postgres::types::PgLsn;
+use tracing::{debug, warn};
+
+use super::data::{Insert, PKey, SchemaChange, Update, WalData, WalEvent};
+use super::replication::ReplicationError;
+use super::schema::QualifiedName;
+
+// Type oids of the builtin types whose values aren't kept as text
+const BOOL_OID: u32 = 16;
+const INTEGER_OIDS: [u32; 4] = [20, 21, 23, 26];
+const FLOAT_OIDS: [u32; 3] = [700, 701, 1700];
+
+#[derive(Debug, Clone)]
+pub struct RelationColumn {
+    pub name: String,
+    pub type_oid: u32,
+    // Part of the replica identity, usually the primary key
+    pub key: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Relation {
+    pub schema: String,
+    pub table: String,
+    pub columns: Vec<RelationColumn>,
+}
+
+impl Relation {
+    // Name and type oid of every column
+    fn typed_columns(&self) -> Vec<(String, String)> {
+        self.columns
+            .iter()
+            .map(|column| (column.name.clone(), column.type_oid.to_string()))
+            .collect()
+    }
+}
+
+/// Messages of the `pgoutput` plugin that matter to the replicator, with the tuples already
+/// turned into `WalEvent`s.
+#[derive(Debug, Clone)]
+pub enum PgOutputMessage {
+    Begin { final_lsn: PgLsn, xid: i64 },
+    Commit { end_lsn: PgLsn },
+    Change(Box<WalEvent>),
+    // One change per truncated table
+    Truncate(Vec<WalEvent>),
+}
+
+/// Columns the publication sends of each table, by their name in queries. `None` for tables
+/// published with all columns.
+pub type PublishedColumns = Arc<Mutex<HashMap<String, Option<Vec<String>>>>>;
+
+/// Decoder of the binary logical replication protocol, version 1.
+///
+/// Relation and Type messages precede the changes referring to them and are only kept for
+/// decoding those, see https://www.postgresql.org/docs/current/protocol-logicalrep-message-formats.html
+#[derive(Debug, Default)]
+pub struct PgOutputDecoder {
+    relations: HashMap<u32, Relation>,
+    // A relation only has the published columns, so a changed column list changes them too
+    published: PublishedColumns,
+    types: HashMap<u32, String>,
+    xid: i64,
+    timestamp: String,
+}
+
+// Cursor over a message, every read fails on truncated input
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let end = self.data.iter().position(|byte| *byte == 0)?;
+        let text = String::from_utf8_lossy(&self.data[..end]).to_string();
+        self.data = &self.data[end + 1..];
+        Some(text)
+    }
+}
+
+impl PgOutputDecoder {
+    pub fn new(published: PublishedColumns) -> Self {
+        PgOutputDecoder {
+            published,
+            ..Self::default()
+        }
+    }
+
+    // Decode the payload of an XLogData message, `None` for messages without a change. A
+    // message that should carry one but can't be read, like a change of a relation that
+    // wasn't described, is an error.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Option<PgOutputMessage>, ReplicationError> {
+        let mut reader = Reader { data };
+        let message = self.decode_message(&mut reader);
+        match data.first() {
+            Some(tag) if message.is_none() && b"BCIUDT".contains(tag) => Err(
+                ReplicationError::Decode(format!("{} message {:?}", *tag as char, data)),
+            ),
+            _ => Ok(message),
+        }
+    }
+
+    fn decode_message(&mut self, reader: &mut Reader) -> Option<PgOutputMessage> {
+        match reader.u8()? {
+            b'B' => {
+                let final_lsn = PgLsn::from(reader.u64()?);
+                self.timestamp = (reader.u64()? as i64).to_string();
+                self.xid = reader.u32()? as i64;
+                Some(PgOutputMessage::Begin {
+                    final_lsn,
+                    xid: self.xid,
+                })
+            }
+            b'C' => {
+                reader.u8()?;
+                reader.u64()?;
+                let end_lsn = PgLsn::from(reader.u64()?);
+                Some(PgOutputMessage::Commit { end_lsn })
+            }
+            b'R' => {
+                let oid = reader.u32()?;
+                let schema = reader.string()?;
+                let table = reader.string()?;
+                reader.u8()?;
+                let mut columns = vec![];
+                for _ in 0..reader.u16()? {
+                    let flags = reader.u8()?;
+                    let name = reader.string()?;
+                    let type_oid = reader.u32()?;
+                    reader.u32()?;
+                    columns.push(RelationColumn {
+                        name,
+                        type_oid,
+                        key: flags & 1 == 1,
+                    });
+                }
+                debug!("Relation {}.{} has oid {}", schema, table, oid);
+                let relation = Relation {
+                    schema,
+                    table,
+                    columns,
+                };
+                // A relation is sent again before the first change after it was altered, or
+                // after its column list changed
+                let change = self.relations.get(&oid).and_then(|previous| {
+                    let change = SchemaChange::between(
+                        &previous.typed_columns(),
+                        &relation.typed_columns(),
+                    )?;
+                    self.published_change(&relation, change)
+                });
+                self.relations.insert(oid, relation.clone());
+                change.map(|change| self.change(&relation, PKey::none(), WalData::Schema(change)))
+            }
+            b'Y' => {
+                let oid = reader.u32()?;
+                let schema = reader.string()?;
+                let name = reader.string()?;
+                self.types.insert(oid, format!("{}.{}", schema, name));
+                None
+            }
+            b'I' => {
+                let relation = self.relation(reader.u32()?)?;
+                reader.u8()?;
+                let values = self.tuple(reader, &relation)?;
+                let pkey = key_of(&relation, &values);
+                Some(self.change(&relation, pkey, WalData::Insert(Insert(values))))
+            }
+            b'U' => {
+                let relation = self.relation(reader.u32()?)?;
+                let mut kind = reader.u8()?;
+                // The old key or row is only sent when the key changed or with REPLICA
+                // IDENTITY FULL
+                let mut old_key = None;
+                let mut old = None;
+                if kind == b'K' || kind == b'O' {
+                    let tuple = self.tuple(reader, &relation)?;
+                    old_key = Some(key_of(&relation, &tuple));
+                    if kind == b'O' {
+                        old = Some(tuple);
+                    }
+                    kind = reader.u8()?;
+                }
+                if kind != b'N' {
+                    return None;
+                }
+                let mut update = Update {
+                    values: self.tuple(reader, &relation)?,
+                    old_key,
+                    old: None,
+                };
+                if let Some(old) = old {
+                    update.merge_unchanged(&old);
+                    update.old = Some(old);
+                }
+                let pkey = key_of(&relation, &update.values);
+                Some(self.change(&relation, pkey, WalData::Update(update)))
+            }
+            b'D' => {
+                let relation = self.relation(reader.u32()?)?;
+                reader.u8()?;
+                let values = self.tuple(reader, &relation)?;
+                let pkey = key_of(&relation, &values);
+                Some(self.change(&relation, pkey, WalData::Delete))
+            }
+            b'T' => {
+                let count = reader.u32()?;
+                reader.u8()?;
+                let mut changes = vec![];
+                for _ in 0..count {
+                    let relation = self.relation(reader.u32()?)?;
+                    if let PgOutputMessage::Change(event) =
+                        self.change(&relation, PKey::none(), WalData::Truncate)
+                    {
+                        changes.push(*event);
+                    }
+                }
+                Some(PgOutputMessage::Truncate(changes))
+            }
+            // Origin and logical decoding messages
+            _ => None,
+        }
+    }
+
+    // The part of a change of the columns of a relation that its column list doesn't explain.
+    // Columns added to the list only show up in the relation, and those removed from it are
+    // left out. A published column that's missing was dropped from the table.
+    fn published_change(
+        &self,
+        relation: &Relation,
+        mut change: SchemaChange,
+    ) -> Option<SchemaChange> {
+        let name = QualifiedName {
+            schema: relation.schema.clone(),
+            table: relation.table.clone(),
+        };
+        if let Some(Some(columns)) = self.published.lock().unwrap().get(&name.query_name()) {
+            change.added.retain(|column| !columns.contains(column));
+            change.dropped.retain(|column| columns.contains(column));
+        }
+        (change != SchemaChange::default()).then_some(change)
+    }
+
+    fn relation(&self, oid: u32) -> Option<Relation> {
+        let relation = self.relations.get(&oid).cloned();
+        if relation.is_none() {
+            warn!("Change for unknown relation {}", oid);
+        }
+        relation
+    }
+
+    fn change(&self, relation: &Relation, pkey: PKey, data: WalData) -> PgOutputMessage {
+        let name = QualifiedName {
+            schema: relation.schema.clone(),
+            table: relation.table.clone(),
+        };
+        PgOutputMessage::Change(Box::new(WalEvent {
+            table: name.query_name(),
+            timestamp: self.timestamp.clone(),
+            xid: self.xid,
+            lsn: 0,
+            pkey,
+            data,
+        }))
+    }
+
+    // Column values of a tuple, unchanged TOAST values are left out
+    fn tuple(&self, reader: &mut Reader, relation: &Relation) -> Option<BTreeMap<String, Value>> {
+        let mut values = BTreeMap::new();
+        for i in 0..reader.u16()? as usize {
+            let column = relation.columns.get(i)?;
+            match reader.u8()? {
+                b'n' => {
+                    values.insert(column.name.clone(), Value::Null);
+                }
+                b'u' => {}
+                b't' | b'b' => {
+                    let len = reader.u32()? as usize;
+                    let text = String::from_utf8_lossy(reader.bytes(len)?).to_string();
+                    values.insert(column.name.clone(), self.value(column, text));
+                }
+                _ => return None,
+            }
+        }
+        Some(values)
+    }
+
+    // Values get the JSON types wal2json uses for them
+    fn value(&self, column: &RelationColumn, text: String) -> Value {
+        if column.type_oid == BOOL_OID {
+            return Value::Bool(text == "t");
+        }
+        if INTEGER_OIDS.contains(&column.type_oid) {
+            if let Ok(number) = text.parse::<i64>() {
+                return Value::from(number);
+            }
+        }
+        if FLOAT_OIDS.contains(&column.type_oid) {
+            if let Some(number) = text.parse::<f64>().ok().and_then(Number::from_f64) {
+                return Value::Number(number);
+            }
+        }
+        if let Some(name) = self.types.get(&column.type_oid) {
+            debug!("Reading {} of type {} as text", column.name, name);
+        }
+        Value::String(text)
+    }
+}
+
+// The first key column, like wal2json's `pk`
+fn key_of(relation: &Relation, values: &BTreeMap<String, Value>) -> PKey {
+    let column = relation
+        .columns
+        .iter()
+        .find(|column| column.key)
+        .or(relation.columns.first());
+    let col = column.map_or("".to_string(), |column| column.name.clone());
+    PKey {
+        val: values.get(&col).cloned().unwrap_or(Value::Null),
+        col,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT_OID: u32 = 25;
+
+    // Messages as the server sends them, built field by field
+    struct Message(Vec<u8>);
+
+    impl Message {
+        fn new(tag: u8) -> Self {
+            Message(vec![tag])
+        }
+
+        fn u8(mut self, value: u8) -> Self {
+            self.0.push(value);
+            self
+        }
+
+        fn u16(mut self, value: u16) -> Self {
+            self.0.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+
+        fn u32(mut self, value: u32) -> Self {
+            self.0.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+
+        fn u64(mut self, value: u64) -> Self {
+            self.0.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+
+        fn string(mut self, value: &str) -> Self {
+            self.0.extend_from_slice(value.as_bytes());
+            self.0.push(0);
+            self
+        }
+
+        // A tuple of text values, `None` is NULL and `Some("\u{0}")` an unchanged TOAST value
+        fn tuple(mut self, values: &[Option<&str>]) -> Self {
+            self = self.u16(values.len() as u16);
+            for value in values {
+                self = match value {
+                    None => self.u8(b'n'),
+                    Some("\u{0}") => self.u8(b'u'),
+                    Some(text) => {
+                        let mut message = self.u8(b't').u32(text.len() as u32);
+                        message.0.extend_from_slice(text.as_bytes());
+                        message
+                    }
+                };
+            }
+            self
+        }
+    }
+
+    // `id bigint` as key, `name text` and `active boolean`
+    fn users(oid: u32) -> Vec<u8> {
+        Message::new(b'R')
+            .u32(oid)
+            .string("public")
+            .string("users")
+            .u8(b'd')
+            .u16(3)
+            .u8(1)
+            .string("id")
+            .u32(20)
+            .u32(0)
+            .u8(0)
+            .string("name")
+            .u32(TEXT_OID)
+            .u32(0)
+            .u8(0)
+            .string("active")
+            .u32(BOOL_OID)
+            .u32(0)
+            .0
+    }
+
+    fn decoder() -> PgOutputDecoder {
+        let mut decoder = PgOutputDecoder::default();
+        assert!(decoder.decode(&users(16384)).unwrap().is_none());
+        decoder
+    }
+
+    fn change(message: Result<Option<PgOutputMessage>, ReplicationError>) -> WalEvent {
+        match message {
+            Ok(Some(PgOutputMessage::Change(event))) => *event,
+            message => panic!("Expected a change, got {:?}", message),
+        }
+    }
+
+    fn row(values: &[(&str, Value)]) -> BTreeMap<String, Value> {
+        values
+            .iter()
+            .map(|(column, value)| (column.to_string(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_begin_and_commit() {
+        let mut decoder = PgOutputDecoder::default();
+        let begin = Message::new(b'B').u64(0x16b3748).u64(0).u32(731).0;
+        match decoder.decode(&begin) {
+            Ok(Some(PgOutputMessage::Begin { final_lsn, xid })) => {
+                assert_eq!(u64::from(final_lsn), 0x16b3748);
+                assert_eq!(xid, 731);
+            }
+            message => panic!("Expected a begin, got {:?}", message),
+        }
+        let commit = Message::new(b'C')
+            .u8(0)
+            .u64(0x16b3748)
+            .u64(0x16b3778)
+            .u64(0)
+            .0;
+        match decoder.decode(&commit) {
+            Ok(Some(PgOutputMessage::Commit { end_lsn })) => {
+                assert_eq!(u64::from(end_lsn), 0x16b3778)
+            }
+            message => panic!("Expected a commit, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn decodes_an_insert_with_typed_values() {
+        let mut decoder = decoder();
+        let insert = Message::new(b'I')
+            .u32(16384)
+            .u8(b'N')
+            .tuple(&[Some("7"), None, Some("t")])
+            .0;
+        let event = change(decoder.decode(&insert));
+        assert_eq!(event.table, "users");
+        assert_eq!(event.pkey.col, "id");
+        assert_eq!(event.pkey.val, Value::from(7));
+        match event.data {
+            WalData::Insert(Insert(values)) => assert_eq!(
+                values,
+                row(&[
+                    ("id", Value::from(7)),
+                    ("name", Value::Null),
+                    ("active", Value::Bool(true))
+                ])
+            ),
+            data => panic!("Expected an insert, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn decodes_an_update_of_the_key() {
+        let mut decoder = decoder();
+        let update = Message::new(b'U')
+            .u32(16384)
+            .u8(b'K')
+            .tuple(&[Some("7"), None, None])
+            .u8(b'N')
+            .tuple(&[Some("8"), Some("ada"), Some("f")])
+            .0;
+        let event = change(decoder.decode(&update));
+        assert_eq!(event.pkey.val, Value::from(8));
+        match event.data {
+            WalData::Update(update) => {
+                assert_eq!(update.old_key.map(|key| key.val), Some(Value::from(7)));
+                assert!(update.old.is_none());
+                assert_eq!(update.values["name"], Value::from("ada"));
+                assert_eq!(update.values["active"], Value::Bool(false));
+            }
+            data => panic!("Expected an update, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn leaves_unchanged_toast_values_out_of_an_update() {
+        let mut decoder = decoder();
+        let update = Message::new(b'U')
+            .u32(16384)
+            .u8(b'N')
+            .tuple(&[Some("7"), Some("\u{0}"), Some("t")])
+            .0;
+        match change(decoder.decode(&update)).data {
+            WalData::Update(update) => {
+                assert!(update.old_key.is_none());
+                assert_eq!(
+                    update.values,
+                    row(&[("id", Value::from(7)), ("active", Value::Bool(true))])
+                );
+            }
+            data => panic!("Expected an update, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn takes_unchanged_toast_values_from_the_old_row() {
+        let mut decoder = decoder();
+        let update = Message::new(b'U')
+            .u32(16384)
+            .u8(b'O')
+            .tuple(&[Some("7"), Some("ada"), Some("f")])
+            .u8(b'N')
+            .tuple(&[Some("7"), Some("\u{0}"), Some("t")])
+            .0;
+        match change(decoder.decode(&update)).data {
+            WalData::Update(update) => {
+                assert_eq!(
+                    update.values,
+                    row(&[
+                        ("id", Value::from(7)),
+                        ("name", Value::from("ada")),
+                        ("active", Value::Bool(true))
+                    ])
+                );
+                assert_eq!(update.old.unwrap()["active"], Value::Bool(false));
+            }
+            data => panic!("Expected an update, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn decodes_a_delete_by_its_key() {
+        let mut decoder = decoder();
+        let delete = Message::new(b'D')
+            .u32(16384)
+            .u8(b'K')
+            .tuple(&[Some("7"), None, None])
+            .0;
+        let event = change(decoder.decode(&delete));
+        assert!(matches!(event.data, WalData::Delete));
+        assert_eq!(event.pkey.col, "id");
+        assert_eq!(event.pkey.val, Value::from(7));
+    }
+
+    #[test]
+    fn decodes_a_truncate_of_several_tables() {
+        let mut decoder = decoder();
+        let orders = Message::new(b'R')
+            .u32(16390)
+            .string("shop")
+            .string("orders")
+            .u8(b'd')
+            .u16(1)
+            .u8(1)
+            .string("id")
+            .u32(23)
+            .u32(0)
+            .0;
+        assert!(decoder.decode(&orders).unwrap().is_none());
+        let truncate = Message::new(b'T').u32(2).u8(0).u32(16384).u32(16390).0;
+        match decoder.decode(&truncate) {
+            Ok(Some(PgOutputMessage::Truncate(events))) => {
+                let tables = events
+                    .iter()
+                    .map(|event| event.table.as_str())
+                    .collect::<Vec<_>>();
+                assert_eq!(tables, vec!["users", "shop.orders"]);
+                assert!(events
+                    .iter()
+                    .all(|event| matches!(event.data, WalData::Truncate)));
+            }
+            message => panic!("Expected a truncate, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn reports_changed_columns_of_a_resent_relation() {
+        let mut decoder = decoder();
+        let altered = Message::new(b'R')
+            .u32(16384)
+            .string("public")
+            .string("users")
+            .u8(b'd')
+            .u16(2)
+            .u8(1)
+            .string("id")
+            .u32(20)
+            .u32(0)
+            .u8(0)
+            .string("email")
+            .u32(TEXT_OID)
+            .u32(0)
+            .0;
+        match change(decoder.decode(&altered)).data {
+            WalData::Schema(change) => {
+                assert_eq!(change.added, vec!["email"]);
+                assert_eq!(change.dropped, vec!["name", "active"]);
+            }
+            data => panic!("Expected a schema change, got {:?}", data),
+        }
+        // An unchanged relation is no schema change
+        assert!(decoder.decode(&altered).unwrap().is_none());
+    }
+
+    // `public.users` with the columns given as name and type oid, the first one is the key
+    fn users_with(columns: &[(&str, u32)]) -> Vec<u8> {
+        let mut message = Message::new(b'R')
+            .u32(16384)
+            .string("public")
+            .string("users")
+            .u8(b'd')
+            .u16(columns.len() as u16);
+        for (i, (name, type_oid)) in columns.iter().enumerate() {
+            message = message
+                .u8((i == 0) as u8)
+                .string(name)
+                .u32(*type_oid)
+                .u32(0);
+        }
+        message.0
+    }
+
+    #[test]
+    fn tells_a_changed_column_list_from_a_schema_change() {
+        let published = PublishedColumns::default();
+        let publish = |columns: &[&str]| {
+            let columns = columns.iter().map(|column| column.to_string()).collect();
+            published
+                .lock()
+                .unwrap()
+                .insert("users".to_string(), Some(columns));
+        };
+        let mut decoder = PgOutputDecoder::new(Arc::clone(&published));
+        publish(&["id", "name"]);
+        let relation = users_with(&[("id", 20), ("name", TEXT_OID)]);
+        assert!(decoder.decode(&relation).unwrap().is_none());
+
+        // A wider column list only adds columns to the relation
+        publish(&["active", "id", "name"]);
+        let widened = users_with(&[("id", 20), ("name", TEXT_OID), ("active", BOOL_OID)]);
+        assert!(decoder.decode(&widened).unwrap().is_none());
+
+        // A published column that's gone was dropped, retyped columns are retyped
+        let altered = users_with(&[("id", 23), ("active", BOOL_OID)]);
+        match change(decoder.decode(&altered)).data {
+            WalData::Schema(change) => {
+                assert!(change.added.is_empty());
+                assert_eq!(change.dropped, vec!["name"]);
+                assert_eq!(change.retyped, vec!["id"]);
+            }
+            data => panic!("Expected a schema change, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_messages_and_unknown_relations() {
+        let mut decoder = decoder();
+        let insert = Message::new(b'I')
+            .u32(16384)
+            .u8(b'N')
+            .tuple(&[Some("7"), None, Some("t")])
+            .0;
+        assert!(decoder.decode(&insert[..insert.len() - 1]).is_err());
+        let unknown = Message::new(b'I').u32(1).u8(b'N').tuple(&[Some("7")]).0;
+        assert!(decoder.decode(&unknown).is_err());
+        // Messages without a change are left out
+        assert!(decoder.decode(b"O").unwrap().is_none());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Position up to which a dataflow wrote the source transactions to its sinks.
+#[derive(Debug)]
+pub struct DataflowProgress {
+    flushed: AtomicU64,
+}
+
+impl DataflowProgress {
+    // Every transaction up to `lsn` was written to the sinks
+    pub fn flush(&self, lsn: u64) {
+        self.flushed.fetch_max(lsn, Ordering::SeqCst);
+    }
+
+    fn covers(&self, lsn: u64) -> bool {
+        self.flushed.load(Ordering::SeqCst) >= lsn
+    }
+}
+
+/// Feedback of the running dataflows to the replicator, which only confirms a transaction to
+/// the server once every running dataflow wrote it to the sinks.
+#[derive(Debug, Default)]
+pub struct Progress {
+    dataflows: Mutex<Vec<Weak<DataflowProgress>>>,
+    // End of the last transaction sent to the subscribers
+    received: AtomicU64,
+}
+
+impl Progress {
+    // Track a dataflow until the returned handle is dropped. Transactions sent before don't
+    // reach the dataflow, so they count as written for it.
+    pub fn track(&self) -> Arc<DataflowProgress> {
+        let progress = Arc::new(DataflowProgress {
+            flushed: AtomicU64::new(self.received.load(Ordering::SeqCst)),
+        });
+        self.dataflows
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&progress));
+        progress
+    }
+
+    pub fn received(&self, lsn: u64) {
+        self.received.fetch_max(lsn, Ordering::SeqCst);
+    }
+
+    // Whether the transaction ending at `lsn` is in the sinks of every running dataflow. A
+    // stopped dataflow doesn't wait for its transactions anymore, so without a running one
+    // every transaction counts as written.
+    pub fn flushed(&self, lsn: u64) -> bool {
+        let mut dataflows = self.dataflows.lock().unwrap();
+        dataflows.retain(|dataflow| dataflow.strong_count() > 0);
+        dataflows
+            .iter()
+            .filter_map(|dataflow| dataflow.upgrade())
+            .all(|dataflow| dataflow.covers(lsn))
+    }
+}
+
+/// Transactions sent to the subscribers but not confirmed to the server yet, in commit order.
+#[derive(Debug)]
+pub struct Unconfirmed {
+    // End of each transaction and whether any subscriber read it
+    pending: VecDeque<(u64, bool)>,
+    // Position confirmed to the server, everything before it is in the sinks
+    confirmed: u64,
+}
+
+impl Unconfirmed {
+    pub fn new(confirmed: u64) -> Self {
+        Unconfirmed {
+            pending: VecDeque::new(),
+            confirmed,
+        }
+    }
+
+    pub fn confirmed(&self) -> u64 {
+        self.confirmed
+    }
+
+    // End of the last transaction sent to the subscribers
+    pub fn last(&self) -> u64 {
+        self.pending.back().map_or(self.confirmed, |(lsn, _)| *lsn)
+    }
+
+    // Queue the transaction ending at `lsn`. A reconnect streams the transactions after the
+    // confirmed position again, those queued already are left out.
+    pub fn push(&mut self, lsn: u64, read: bool) {
+        if lsn > self.last() {
+            self.pending.push_back((lsn, read));
+        }
+    }
+
+    // Confirm the transactions up to the first one a subscriber read and a running dataflow
+    // has yet to write. Returns whether the confirmed position moved.
+    pub fn confirm(&mut self, progress: &Progress) -> bool {
+        let confirmed = self.confirmed;
+        while let Some(&(lsn, read)) = self.pending.front() {
+            if read && !progress.flushed(lsn) {
+                break;
+            }
+            self.confirmed = lsn;
+            self.pending.pop_front();
+        }
+        self.confirmed != confirmed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn everything_is_flushed_without_a_running_dataflow() {
+        let progress = Progress::default();
+        assert!(progress.flushed(10));
+        let dataflow = progress.track();
+        assert!(!progress.flushed(10));
+        dataflow.flush(10);
+        assert!(progress.flushed(10));
+        drop(dataflow);
+        assert!(progress.flushed(20));
+    }
+
+    #[test]
+    fn waits_for_the_slowest_dataflow() {
+        let progress = Progress::default();
+        let fast = progress.track();
+        let slow = progress.track();
+        fast.flush(20);
+        slow.flush(10);
+        assert!(progress.flushed(10));
+        assert!(!progress.flushed(20));
+        slow.flush(20);
+        assert!(progress.flushed(20));
+    }
+
+    #[test]
+    fn a_new_dataflow_starts_at_the_received_transactions() {
+        let progress = Progress::default();
+        progress.received(30);
+        let dataflow = progress.track();
+        assert!(progress.flushed(30));
+        assert!(!progress.flushed(40));
+        drop(dataflow);
+    }
+
+    #[test]
+    fn confirms_in_commit_order() {
+        let progress = Progress::default();
+        let dataflow = progress.track();
+        let mut unconfirmed = Unconfirmed::new(5);
+        unconfirmed.push(10, true);
+        unconfirmed.push(20, true);
+        assert!(!unconfirmed.confirm(&progress));
+        dataflow.flush(20);
+        assert!(unconfirmed.confirm(&progress));
+        assert_eq!(unconfirmed.confirmed(), 20);
+        assert_eq!(unconfirmed.last(), 20);
+    }
+
+    #[test]
+    fn confirms_transactions_no_subscriber_read() {
+        let progress = Progress::default();
+        let dataflow = progress.track();
+        let mut unconfirmed = Unconfirmed::new(0);
+        unconfirmed.push(10, false);
+        unconfirmed.push(20, true);
+        unconfirmed.push(30, false);
+        assert!(unconfirmed.confirm(&progress));
+        assert_eq!(unconfirmed.confirmed(), 10);
+        dataflow.flush(20);
+        unconfirmed.confirm(&progress);
+        assert_eq!(unconfirmed.confirmed(), 30);
+    }
+
+    #[test]
+    fn transactions_streamed_again_are_queued_once() {
+        let progress = Progress::default();
+        let dataflow = progress.track();
+        let mut unconfirmed = Unconfirmed::new(0);
+        unconfirmed.push(10, true);
+        unconfirmed.push(20, true);
+        // A reconnect resumes at the confirmed position
+        unconfirmed.push(10, true);
+        unconfirmed.push(20, true);
+        unconfirmed.push(30, true);
+        assert_eq!(unconfirmed.pending.len(), 3);
+        dataflow.flush(30);
+        unconfirmed.confirm(&progress);
+        assert!(unconfirmed.pending.is_empty());
+        assert_eq!(unconfirmed.confirmed(), 30);
+    }
+
+    #[test]
+    fn nothing_piles_up_without_a_subscriber() {
+        let progress = Progress::default();
+        let mut unconfirmed = Unconfirmed::new(0);
+        for lsn in 1..=100 {
+            unconfirmed.push(lsn, false);
+            unconfirmed.confirm(&progress);
+        }
+        assert!(unconfirmed.pending.is_empty());
+        assert_eq!(unconfirmed.confirmed(), 100);
+    }
+}
@@ -2,70 +2,241 @@ use std::sync::Arc;
 use tokio_postgres::SimpleQueryMessage;
 use tracing::{debug, info, warn};
 
+use super::schema::{quote_identifier, QualifiedName};
+
+/// Part of a table the subscribers read. With Postgres 15 or later the publication only sends
+/// these rows and columns. Only `pgoutput` streams the publication, wal2json sends all rows
+/// and columns of every table, and the dataflows filter them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRead {
+    // Named like in queries
+    pub table: String,
+    // SQL conditions on the columns of the table, a row is read if any of them holds. `None`
+    // reads every row.
+    pub row_filter: Option<Vec<String>>,
+    // Columns referenced by the row filter
+    pub filter_columns: Vec<String>,
+    // `None` reads every column
+    pub columns: Option<Vec<String>>,
+}
+
+impl TableRead {
+    pub fn all(table: &str) -> Self {
+        TableRead {
+            table: table.to_string(),
+            row_filter: None,
+            filter_columns: vec![],
+            columns: None,
+        }
+    }
+
+    // The rows and columns read by either of both
+    pub fn union(&self, other: &TableRead) -> TableRead {
+        let merge = |left: &[String], right: &[String]| {
+            let mut merged = left.iter().chain(right).cloned().collect::<Vec<_>>();
+            merged.sort();
+            merged.dedup();
+            merged
+        };
+        let row_filter = match (&self.row_filter, &other.row_filter) {
+            (Some(left), Some(right)) => Some(merge(left, right)),
+            _ => None,
+        };
+        let columns = match (&self.columns, &other.columns) {
+            (Some(left), Some(right)) => Some(merge(left, right)),
+            _ => None,
+        };
+        TableRead {
+            table: self.table.clone(),
+            filter_columns: match row_filter {
+                Some(_) => merge(&self.filter_columns, &other.filter_columns),
+                None => vec![],
+            },
+            row_filter,
+            columns,
+        }
+    }
+}
+
+// One publication for all replicated tables, tables are added while views read them
 pub struct Publication {
     pub client: Arc<tokio_postgres::Client>,
-    pub schema_name: String,
-    pub table_name: String,
+    pub name: String,
 }
 
 impl Publication {
-    pub fn new(
-        client: Arc<tokio_postgres::Client>,
-        schema_name: &String,
-        table_name: &String,
-    ) -> Self {
+    pub fn new(client: Arc<tokio_postgres::Client>, name: &str) -> Self {
         Self {
-            client: client,
-            schema_name: schema_name.clone(),
-            table_name: table_name.clone(),
+            client,
+            name: name.to_string(),
         }
     }
 
     #[inline]
     pub fn pub_name(&self) -> String {
-        format!("pub_{}", self.table_name.to_lowercase().trim_matches('"'))
+        self.name.clone()
+    }
+
+    async fn rows(&self, query: &str) -> Result<usize, tokio_postgres::Error> {
+        let result = self.client.simple_query(query).await?;
+        Ok(result
+            .into_iter()
+            .filter(|msg| matches!(msg, SimpleQueryMessage::Row(_)))
+            .count())
+    }
+
+    // Row filters and column lists of publications need Postgres 15
+    pub async fn supports_restrictions(&self) -> Result<bool, tokio_postgres::Error> {
+        let result = self.client.simple_query("SHOW server_version_num").await?;
+        let version = result.iter().find_map(|msg| match msg {
+            SimpleQueryMessage::Row(row) => row.get(0).and_then(|v| v.parse::<u32>().ok()),
+            _ => None,
+        });
+        Ok(version.is_some_and(|version| version >= 150000))
+    }
+
+    // Replica identity of a table, `d` (the primary key), `i` (an index), `f` (the whole
+    // row) or `n` (nothing), and the columns of the key or index
+    async fn replica_identity(
+        &self,
+        table: &QualifiedName,
+    ) -> Result<(String, Vec<String>), tokio_postgres::Error> {
+        let query = format!(
+            "SELECT c.relreplident, a.attname FROM pg_class c
+                LEFT JOIN pg_index i ON i.indrelid = c.oid
+                    AND (i.indisreplident OR (c.relreplident = 'd' AND i.indisprimary))
+                LEFT JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum = ANY(i.indkey)
+                WHERE c.oid = '{}'::regclass",
+            table.quoted().replace('\'', "''")
+        );
+        let mut identity = String::new();
+        let mut columns = vec![];
+        for msg in self.client.simple_query(&query).await? {
+            if let SimpleQueryMessage::Row(row) = msg {
+                identity = row.get(0).unwrap_or("").to_string();
+                if let Some(column) = row.get(1) {
+                    columns.push(column.to_string());
+                }
+            }
+        }
+        Ok((identity, columns))
     }
 
     pub async fn check_exists(&self) -> Result<bool, tokio_postgres::Error> {
-        let pub_name = self.pub_name();
         let query = format!(
-            "SELECT schemaname, tablename
-                FROM pg_publication p
-                JOIN pg_publication_tables pt ON p.pubname = pt.pubname
-                WHERE p.pubname = '{}'",
-            pub_name
+            "SELECT pubname FROM pg_publication WHERE pubname = '{}'",
+            self.pub_name()
         );
-        let result = self.client.simple_query(&query).await?;
-        let rows = result
-            .into_iter()
-            .filter_map(|msg| match msg {
-                SimpleQueryMessage::Row(row) => Some(row),
-                _ => None,
-            })
-            .collect::<Vec<_>>();
-
-        if let Some(publication) = rows.first() {
-            let schema_name = publication.get("schemaname").unwrap().to_string();
-            let table_name = publication.get("tablename").unwrap().to_string();
-            debug!(
-                "Found publication {:?}/{:?}, ready to start replication",
-                schema_name, table_name
-            );
-            return Ok(true);
-        } else {
-            return Ok(false);
+        let exists = self.rows(&query).await? > 0;
+        if exists {
+            debug!("Found publication {:?}", self.pub_name());
         }
+        Ok(exists)
     }
 
     pub async fn create(&self) -> Result<u64, tokio_postgres::Error> {
-        debug!("Publication for: {} {}", self.pub_name(), self.table_name);
+        let query = format!("CREATE PUBLICATION {}", self.pub_name());
+        let result = self.client.execute(&query, &[]).await?;
+        debug!("Created publication: {:?}", self.pub_name());
+        Ok(result)
+    }
+
+    pub async fn contains(&self, table: &QualifiedName) -> Result<bool, tokio_postgres::Error> {
         let query = format!(
-            "CREATE PUBLICATION {} FOR TABLE {}",
+            "SELECT tablename FROM pg_publication_tables
+                WHERE pubname = '{}' AND schemaname = '{}' AND tablename = '{}'",
             self.pub_name(),
-            self.table_name
+            table.schema.replace('\'', "''"),
+            table.table.replace('\'', "''")
         );
-        let result = self.client.execute(&query, &[]).await.unwrap();
-        debug!("Created publication: {:?}", result);
-        Ok(result)
+        Ok(self.rows(&query).await? > 0)
+    }
+
+    // Publish the part of the table that is read, replacing what was published of it before.
+    // Without `restrict` the whole table is published. Returns the published columns, `None`
+    // if all of them are.
+    pub async fn set_table(
+        &self,
+        read: &TableRead,
+        restrict: bool,
+    ) -> Result<Option<Vec<String>>, tokio_postgres::Error> {
+        let table = QualifiedName::parse(&read.table);
+        let mut target = table.quoted();
+        let mut published = None;
+        if restrict {
+            // Updates and deletes of the table fail unless the column list covers the replica
+            // identity and the row filter only uses its columns
+            let (identity, key) = self.replica_identity(&table).await?;
+            let full = identity == "f";
+            if let (Some(columns), false) = (&read.columns, full) {
+                let mut columns = columns.iter().chain(&key).cloned().collect::<Vec<_>>();
+                columns.sort();
+                columns.dedup();
+                let quoted = columns
+                    .iter()
+                    .map(|column| quote_identifier(column))
+                    .collect::<Vec<_>>();
+                target.push_str(&format!(" ({})", quoted.join(", ")));
+                published = Some(columns);
+            }
+            if let Some(row_filter) = &read.row_filter {
+                if full
+                    || read
+                        .filter_columns
+                        .iter()
+                        .all(|column| key.contains(column))
+                {
+                    target.push_str(&format!(" WHERE ({})", row_filter.join(" OR ")));
+                } else {
+                    warn!(
+                        "Publishing all rows of {}, its filter uses columns outside of the replica identity",
+                        table.quoted()
+                    );
+                }
+            }
+        }
+        // The statements of one query run in one transaction
+        let mut statements = vec![];
+        if self.contains(&table).await? {
+            statements.push(format!(
+                "ALTER PUBLICATION {} DROP TABLE {}",
+                self.pub_name(),
+                table.quoted()
+            ));
+        }
+        statements.push(format!(
+            "ALTER PUBLICATION {} ADD TABLE {}",
+            self.pub_name(),
+            target
+        ));
+        self.client.batch_execute(&statements.join("; ")).await?;
+        info!("Published {} in {}", target, self.pub_name());
+        Ok(published)
+    }
+
+    pub async fn drop_table(&self, table: &QualifiedName) -> Result<(), tokio_postgres::Error> {
+        if !self.contains(table).await? {
+            warn!("{} is not part of {}", table.quoted(), self.pub_name());
+            return Ok(());
+        }
+        let query = format!(
+            "ALTER PUBLICATION {} DROP TABLE {}",
+            self.pub_name(),
+            table.quoted()
+        );
+        self.client.execute(&query, &[]).await?;
+        info!(
+            "Dropped {} from publication {}",
+            table.quoted(),
+            self.pub_name()
+        );
+        Ok(())
+    }
+
+    pub async fn drop(&self) -> Result<(), tokio_postgres::Error> {
+        let query = format!("DROP PUBLICATION IF EXISTS {}", self.pub_name());
+        self.client.execute(&query, &[]).await?;
+        info!("Dropped publication {}", self.pub_name());
+        Ok(())
     }
 }
@@ -1,41 +1,196 @@
-use futures::{
-    future::{self},
-    ready, Sink, StreamExt,
-};
+use futures::{SinkExt, StreamExt};
 use std::{
-    task::Poll,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::HashMap,
+    env, fmt,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tracing::{debug, info, warn};
+use tracing::{debug, error, warn};
 
 use bytes::Bytes;
 
 use serde_json::Value;
 use std::pin::Pin;
 use std::sync::Arc;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tokio::sync::watch;
 use tokio_postgres::{
     types::{self, PgLsn},
     CopyBothDuplex, NoTls, SimpleQueryMessage,
 };
 
-use crate::pg_client::data::WalEvent;
+use crate::pg_client::data::{
+    wal_json_columns, PKey, SchemaChange, Transaction, WalData, WalEvent,
+};
 
-use super::publication::Publication;
+use super::pgoutput::{PgOutputDecoder, PgOutputMessage, PublishedColumns};
+use super::progress::{Progress, Unconfirmed};
+use super::schema::connect;
+use super::snapshot::copy_tables;
 
 const SECONDS_FROM_UNIX_EPOCH_TO_2000: u128 = 946684800;
+// How often the position written to the sinks is confirmed to the server
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(1);
+// Delay before reconnecting a failed stream, doubled with every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum ReplicationError {
+    Connection(tokio_postgres::Error),
+    // A message of the stream that can't be decoded
+    Decode(String),
+    // The server ended the stream
+    Closed,
+}
+
+impl fmt::Display for ReplicationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicationError::Connection(e) => write!(f, "{}", e),
+            ReplicationError::Decode(message) => write!(f, "Failed to decode message: {}", message),
+            ReplicationError::Closed => write!(f, "Stream closed"),
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for ReplicationError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        ReplicationError::Connection(e)
+    }
+}
+
+/// Condition of the input of the views, as reported by the replicator.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum StreamHealth {
+    // Not connected yet, or copying the snapshot
+    #[default]
+    Starting,
+    Streaming,
+    // The stream failed and is retried after a backoff, the views don't advance meanwhile
+    Reconnecting {
+        attempt: u32,
+        error: String,
+    },
+}
+
+/// What the stream and the views fed by it report: the condition of the stream, how often
+/// it held back for a dataflow that didn't keep up, the changes it had to skip, and the views
+/// that stopped writing their sink table.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamStatus {
+    pub health: StreamHealth,
+    // Times the stream waited for a full subscriber, and whether it waits right now
+    pub waits: u64,
+    pub waiting: bool,
+    // Changes that couldn't be decoded, which the views miss, and why the last one couldn't
+    pub skipped_changes: u64,
+    pub last_skipped: Option<String>,
+    // Why each stopped view stopped, by sink table
+    pub stopped_views: HashMap<String, String>,
+}
+
+/// A dataflow receiving the transactions of the stream, restricted to the tables it reads.
+#[derive(Debug)]
+pub struct Subscriber {
+    pub id: usize,
+    // Tables by their name in queries
+    pub tables: Vec<String>,
+    pub sender: Sender<Transaction>,
+    // Position of the snapshot its tables were copied at, `None` until they are. Only the
+    // transactions ending after it are sent to the subscriber.
+    pub snapshot_lsn: Option<PgLsn>,
+}
+
+impl Subscriber {
+    // The part of a transaction changing the tables of the subscriber
+    pub fn transaction(&self, lsn: u64, changes: &[WalEvent]) -> Transaction {
+        let changes = changes
+            .iter()
+            .filter(|change| self.tables.contains(&change.table))
+            .cloned()
+            .collect();
+        Transaction { lsn, changes }
+    }
+}
+
+/// Subscribers of the stream, shared by the stream and the replicator demultiplexing its
+/// changes.
+pub type Subscribers = Arc<Mutex<Vec<Subscriber>>>;
+
+// A transaction for every subscriber whose snapshot doesn't contain it yet, with the channel
+// to send it on. Sending happens outside of the lock, since it waits while a subscriber is
+// full.
+pub fn outgoing(
+    subscribers: &Subscribers,
+    lsn: u64,
+    changes: &[WalEvent],
+) -> Vec<(Sender<Transaction>, Transaction)> {
+    subscribers
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|subscriber| {
+            subscriber
+                .snapshot_lsn
+                .is_some_and(|snapshot| lsn > u64::from(snapshot))
+        })
+        .map(|subscriber| {
+            (
+                subscriber.sender.clone(),
+                subscriber.transaction(lsn, changes),
+            )
+        })
+        .collect()
+}
+
+// Logical decoding output plugin of the slots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputPlugin {
+    // Third party plugin sending JSON
+    Wal2Json,
+    // Binary protocol built into Postgres, available on managed services
+    PgOutput,
+}
+
+impl OutputPlugin {
+    // `REPLICATION_DECODER` is `wal2json` (the default) or `pgoutput`
+    pub fn from_env() -> Self {
+        match env::var("REPLICATION_DECODER").as_deref() {
+            Ok("pgoutput") => OutputPlugin::PgOutput,
+            Ok("wal2json") | Err(_) => OutputPlugin::Wal2Json,
+            Ok(other) => {
+                warn!("Unknown replication decoder {}, using wal2json", other);
+                OutputPlugin::Wal2Json
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputPlugin::Wal2Json => "wal2json",
+            OutputPlugin::PgOutput => "pgoutput",
+        }
+    }
+}
 
 pub struct Slot {
     client: Arc<tokio_postgres::Client>,
     name: String,
+    plugin: OutputPlugin,
     pub lsn: Option<PgLsn>,
+    // Snapshot exported when the slot was created, valid until the next replication command
+    pub snapshot: Option<String>,
 }
 
 impl Slot {
-    pub fn new(client: Arc<tokio_postgres::Client>, slot_name: &String) -> Self {
+    pub fn new(client: Arc<tokio_postgres::Client>, slot_name: &str, plugin: OutputPlugin) -> Self {
         Self {
-            client: client,
-            name: slot_name.clone(),
+            client,
+            name: slot_name.to_string(),
+            plugin,
             lsn: None,
+            snapshot: None,
         }
     }
 
@@ -61,27 +216,38 @@ impl Slot {
         Ok(())
     }
 
-    pub async fn create(&mut self) -> Result<(), tokio_postgres::Error> {
+    // A permanent slot keeps its position across restarts, a temporary one is dropped with
+    // the connection
+    pub async fn create(&mut self, temporary: bool) -> Result<(), tokio_postgres::Error> {
         let slot_query = format!(
-            "CREATE_REPLICATION_SLOT {} TEMPORARY LOGICAL \"wal2json\"",
-            self.name
+            "CREATE_REPLICATION_SLOT {}{} LOGICAL \"{}\" EXPORT_SNAPSHOT",
+            self.name,
+            if temporary { " TEMPORARY" } else { "" },
+            self.plugin.name()
         );
         let result = self.client.simple_query(&slot_query).await?;
 
-        let lsn = result
+        let rows = result
             .into_iter()
             .filter_map(|msg| match msg {
                 SimpleQueryMessage::Row(row) => Some(row),
                 _ => None,
             })
-            .collect::<Vec<_>>()
-            .first()
-            .unwrap()
-            .get("consistent_point")
-            .unwrap()
-            .to_owned();
+            .collect::<Vec<_>>();
+        let row = rows.first().unwrap();
+        let lsn = row.get("consistent_point").unwrap().to_owned();
         debug!("Created replication slot: {:?}", lsn);
         self.lsn = Some(lsn.parse::<PgLsn>().unwrap());
+        self.snapshot = row.get("snapshot_name").map(|name| name.to_string());
+        Ok(())
+    }
+
+    // Waits until the slot is no longer in use
+    pub async fn drop_slot(&self) -> Result<(), tokio_postgres::Error> {
+        self.client
+            .simple_query(&format!("DROP_REPLICATION_SLOT {} WAIT", self.name))
+            .await?;
+        debug!("Dropped replication slot {}", self.name);
         Ok(())
     }
 }
@@ -93,24 +259,57 @@ pub struct DBClient {
 impl DBClient {
     pub async fn new(db_config: &str) -> Result<Self, tokio_postgres::Error> {
         let (client, connection) = tokio_postgres::connect(db_config, NoTls).await?;
-        tokio::spawn(async move { connection.await });
+        tokio::spawn(connection);
         Ok(Self { client })
     }
 }
 
 pub struct Replicator {
+    // Connection string of the replication connection, to reconnect with
+    config: String,
+    status: Arc<watch::Sender<StreamStatus>>,
     commit_lsn: types::PgLsn,
+    // Between the begin and the commit of a transaction
+    in_transaction: bool,
+    // Transactions ending at or before this point were sent before a reconnect already
+    skip_until: Option<PgLsn>,
+    // Transactions sent to the dataflows but not confirmed yet
+    unconfirmed: Unconfirmed,
+    progress: Arc<Progress>,
     slot_name: String,
-    schema_name: String,
-    table_name: String,
+    plugin: OutputPlugin,
+    decoder: PgOutputDecoder,
+    publication_name: String,
     client: Arc<tokio_postgres::Client>,
-    records: Vec<Value>,
+    records: Vec<WalEvent>,
+    // Columns of every table as of its last change, wal2json doesn't report schema changes
+    columns: HashMap<String, Vec<(String, String)>>,
     stream: Option<Pin<Box<CopyBothDuplex<Bytes>>>>,
-    sender: tokio::sync::broadcast::Sender<Vec<WalEvent>>,
+    subscribers: Subscribers,
 }
 
-fn prepare_ssu(write_lsn: PgLsn) -> Bytes {
+// Record the columns of a table as of its insert or update `event`, and tell how they changed
+// since its last change. Updates leave out unchanged TOAST values, so only inserts tell that a
+// column was dropped.
+fn track_columns(
+    tables: &mut HashMap<String, Vec<(String, String)>>,
+    event: &WalEvent,
+    mut columns: Vec<(String, String)>,
+) -> Option<SchemaChange> {
+    if let (WalData::Update(_), Some(previous)) = (&event.data, tables.get(&event.table)) {
+        for column in previous {
+            if !columns.iter().any(|(name, _)| name == &column.0) {
+                columns.push(column.clone());
+            }
+        }
+    }
+    let previous = tables.insert(event.table.clone(), columns.clone())?;
+    SchemaChange::between(&previous, &columns)
+}
+
+fn prepare_ssu(write_lsn: PgLsn, flush_lsn: PgLsn) -> Bytes {
     let write_lsn_bytes = u64::from(write_lsn).to_be_bytes();
+    let flush_lsn_bytes = u64::from(flush_lsn).to_be_bytes();
     let time_since_2000: u64 = (SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
@@ -128,10 +327,10 @@ fn prepare_ssu(write_lsn: PgLsn) -> Bytes {
     data_to_send.extend_from_slice(write_lsn_bytes.as_ref());
 
     // The location of the last WAL byte + 1 flushed to disk in the standby.
-    data_to_send.extend_from_slice(write_lsn_bytes.as_ref());
+    data_to_send.extend_from_slice(flush_lsn_bytes.as_ref());
 
     // The location of the last WAL byte + 1 applied in the standby.
-    data_to_send.extend_from_slice(write_lsn_bytes.as_ref());
+    data_to_send.extend_from_slice(flush_lsn_bytes.as_ref());
 
     // The client's system clock at the time of transmission, as microseconds since midnight on 2000-01-01.
     //0, 0, 0, 0, 0, 0, 0, 0,
@@ -143,170 +342,530 @@ fn prepare_ssu(write_lsn: PgLsn) -> Bytes {
 }
 
 impl Replicator {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         client: Arc<tokio_postgres::Client>,
+        config: String,
         slot: Slot,
-        publication: Publication,
-        sender: tokio::sync::broadcast::Sender<Vec<WalEvent>>,
+        publication_name: String,
+        published: PublishedColumns,
+        subscribers: Subscribers,
+        progress: Arc<Progress>,
+        status: Arc<watch::Sender<StreamStatus>>,
     ) -> Self {
         Self {
-            schema_name: publication.schema_name.clone(),
-            table_name: publication.table_name.clone(),
+            config,
+            status,
+            publication_name,
+            plugin: slot.plugin,
+            decoder: PgOutputDecoder::new(published),
             // lsn must be assigned at this point else we panic
-            commit_lsn: slot.lsn.unwrap().clone(),
+            commit_lsn: slot.lsn.unwrap(),
+            in_transaction: false,
+            skip_until: None,
+            unconfirmed: Unconfirmed::new(u64::from(slot.lsn.unwrap())),
+            progress,
             slot_name: slot.name.clone(),
             client,
             records: vec![],
+            columns: HashMap::new(),
             stream: None,
-            sender,
+            subscribers,
         }
     }
 
-    async fn commit(&mut self) {
-        let buf = prepare_ssu(self.commit_lsn);
-        self.send_ssu(buf).await;
-
+    async fn commit(&mut self) -> Result<(), ReplicationError> {
         self.records.clear();
+        self.in_transaction = false;
+        self.acknowledge(false).await?;
+        self.backfill().await
     }
 
-    async fn send_ssu(&mut self, buf: Bytes) {
-        let mut next_step = 1;
-        future::poll_fn(|cx| loop {
-            match next_step {
-                1 => {
-                    ready!(self.stream.as_mut().unwrap().as_mut().poll_ready(cx)).unwrap();
-                }
-                2 => {
-                    self.stream
-                        .as_mut()
-                        .unwrap()
-                        .as_mut()
-                        .start_send(buf.clone())
-                        .unwrap();
-                }
-                3 => {
-                    ready!(self.stream.as_mut().unwrap().as_mut().poll_flush(cx)).unwrap();
-                }
-                4 => return Poll::Ready(()),
-                _ => panic!(),
+    // Copy the tables of the subscribers that have no snapshot yet. Runs between
+    // transactions, so the copied rows reach the subscribers ahead of the transactions after
+    // the snapshot. The other subscribers wait meanwhile.
+    //
+    // A temporary slot exports the snapshot. The transactions this stream has yet to send up
+    // to its position are in the snapshot, so they are left out for the new subscribers.
+    async fn backfill(&mut self) -> Result<(), ReplicationError> {
+        let ids = self
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|subscriber| subscriber.snapshot_lsn.is_none())
+            .map(|subscriber| subscriber.id)
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let client = Arc::new(DBClient::new(&self.config).await?.client);
+        let name = format!("{}_snapshot", self.slot_name);
+        let mut slot = Slot::new(client, &name, self.plugin);
+        slot.create(true).await?;
+        let (Some(snapshot), Some(lsn)) = (slot.snapshot.take(), slot.lsn) else {
+            return Err(ReplicationError::Decode(format!(
+                "Slot {} exported no snapshot",
+                name
+            )));
+        };
+        debug!("Copying the tables of subscribers {:?} at {}", ids, lsn);
+
+        let copy_client = connect().await?;
+        let subscribers = Arc::clone(&self.subscribers);
+        let copy = copy_tables(&copy_client, &subscribers, &ids, &snapshot, lsn);
+        tokio::pin!(copy);
+        // Status updates keep the connection alive during the copy
+        let mut feedback = tokio::time::interval(FEEDBACK_INTERVAL);
+        let copied = loop {
+            tokio::select! {
+                copied = &mut copy => break copied,
+                _ = feedback.tick() => self.acknowledge(true).await?,
             }
-            next_step += 1;
-        })
-        .await;
+        };
+        // Rows sent already can't be taken back, so a failed copy isn't repeated
+        if let Err(e) = copied {
+            error!("Failed to copy the tables of subscribers {:?}: {}", ids, e);
+        }
+        for subscriber in self.subscribers.lock().unwrap().iter_mut() {
+            if ids.contains(&subscriber.id) {
+                subscriber.snapshot_lsn = Some(lsn);
+            }
+        }
+        if let Err(e) = slot.drop_slot().await {
+            warn!("Failed to drop the temporary slot {}: {}", name, e);
+        }
+        Ok(())
     }
 
-    async fn replicate(&self) {
-        if self.records.len() >= 1 {
-            let wal_events = self
-                .records
-                .iter()
-                .map(|e| {
-                    return WalEvent::from_wal_json(e.clone());
-                })
-                .collect::<Vec<_>>();
-            self.sender.send(wal_events).unwrap();
+    // Confirm the transactions the dataflows wrote to their sinks. The server may then
+    // discard their WAL, so a transaction that's only been sent to the dataflows isn't
+    // confirmed yet. Transactions no subscriber read are confirmed right away.
+    async fn acknowledge(&mut self, reply: bool) -> Result<(), tokio_postgres::Error> {
+        if self.unconfirmed.confirm(&self.progress) || reply {
+            let flushed_lsn = PgLsn::from(self.unconfirmed.confirmed());
+            let buf = prepare_ssu(self.commit_lsn, flushed_lsn);
+            self.send_ssu(buf).await?;
         }
+        Ok(())
     }
 
-    async fn process_record(&mut self, record: Value) {
-        match record["action"].as_str().unwrap() {
+    async fn send_ssu(&mut self, buf: Bytes) -> Result<(), tokio_postgres::Error> {
+        match self.stream.as_mut() {
+            Some(stream) => stream.send(buf).await,
+            None => Ok(()),
+        }
+    }
+
+    // End of the last transaction sent to the subscribers
+    fn replicated_lsn(&self) -> PgLsn {
+        PgLsn::from(self.unconfirmed.last())
+    }
+
+    // Send the transaction to every subscriber, with the changes of the tables it reads.
+    // Subscribers receive transactions without such changes too, which tells them that no
+    // change up to the transaction's LSN is outstanding.
+    async fn replicate(&mut self) {
+        let lsn = u64::from(self.commit_lsn);
+        if self.skip_until.is_some_and(|skip| self.commit_lsn <= skip) {
+            debug!("Skipping transaction ending at {}", self.commit_lsn);
+            return;
+        }
+        if let Some(event) = self.records.first() {
+            debug!(
+                "Replicating transaction {} committed at {} with {} changes",
+                event.xid,
+                event.timestamp,
+                self.records.len()
+            );
+        }
+        for event in self.records.iter_mut() {
+            event.lsn = lsn;
+        }
+        let outgoing = outgoing(&self.subscribers, lsn, &self.records);
+        let read = !outgoing.is_empty();
+        for (sender, transaction) in outgoing {
+            self.deliver(sender, transaction).await;
+        }
+        self.progress.received(lsn);
+        self.unconfirmed.push(lsn, read);
+    }
+
+    // Send a transaction to a subscriber, waiting while its channel is full. Dropping it
+    // would lose the changes for good, so a slow dataflow holds up the stream instead, and
+    // the server retains the WAL. Status updates keep the connection alive meanwhile.
+    async fn deliver(&mut self, sender: Sender<Transaction>, transaction: Transaction) {
+        let transaction = match sender.try_send(transaction) {
+            Ok(()) => return,
+            Err(TrySendError::Closed(_)) => {
+                debug!("Subscriber is gone, dropping transaction");
+                return;
+            }
+            Err(TrySendError::Full(transaction)) => transaction,
+        };
+        let mut waits = 0;
+        self.status.send_modify(|status| {
+            status.waits += 1;
+            status.waiting = true;
+            waits = status.waits;
+        });
+        warn!(
+            "Subscriber is full, waiting before sending more changes ({} waits)",
+            waits
+        );
+        let send = sender.send(transaction);
+        tokio::pin!(send);
+        let mut feedback = tokio::time::interval(FEEDBACK_INTERVAL);
+        loop {
+            tokio::select! {
+                result = &mut send => {
+                    if result.is_err() {
+                        debug!("Subscriber is gone, dropping transaction");
+                    }
+                    self.status.send_modify(|status| status.waiting = false);
+                    return;
+                }
+                _ = feedback.tick() => {
+                    if let Err(e) = self.acknowledge(true).await {
+                        warn!("Failed to send status update: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn process_record(&mut self, record: Value) -> Result<(), ReplicationError> {
+        let decode_error = || ReplicationError::Decode(record.to_string());
+        match record["action"].as_str().ok_or_else(decode_error)? {
             // Begin of transaction
             "B" => {
-                let lsn_str = record["nextlsn"].as_str().unwrap();
-                self.commit_lsn = lsn_str.parse::<PgLsn>().unwrap();
+                self.in_transaction = true;
+                let lsn_str = record["nextlsn"].as_str().ok_or_else(decode_error)?;
+                self.commit_lsn = lsn_str.parse::<PgLsn>().map_err(|_| decode_error())?;
             }
             // Commit of transaction
             "C" => {
-                // (todo): handle error
                 self.replicate().await;
-                self.commit().await;
-            }
-            // Insert
-            "I" => {
-                self.records.push(record);
+                self.commit().await?;
             }
-            // Update
-            "U" => {
-                self.records.push(record);
-            }
-            // Delete
-            "D" => {
-                self.records.push(record);
+            // Insert or update
+            "I" | "U" => {
+                if let Err(e) = self.push_row(record) {
+                    self.skip_change(e);
+                }
             }
+            // Delete, or truncate with one record per table
+            "D" | "T" => match WalEvent::from_wal_json(record) {
+                Ok(event) => self.records.push(event),
+                Err(e) => self.skip_change(e),
+            },
             _ => {
                 debug!("unknown message");
             }
         }
+        Ok(())
+    }
+
+    // Queue an insert or update, preceded by a schema change if its columns differ from the
+    // last change of the table
+    fn push_row(&mut self, record: Value) -> Result<(), ReplicationError> {
+        let columns = wal_json_columns(&record);
+        let event = WalEvent::from_wal_json(record)?;
+        if let Some(change) = track_columns(&mut self.columns, &event, columns) {
+            self.records.push(WalEvent {
+                pkey: PKey::none(),
+                data: WalData::Schema(change),
+                ..event.clone()
+            });
+        }
+        self.records.push(event);
+        Ok(())
+    }
+
+    // Leave out a change that can't be decoded. Streaming again would fail on it again, so
+    // the views go on without it, and the status tells they miss a change.
+    fn skip_change(&self, error: ReplicationError) {
+        warn!("Skipping a change: {}", error);
+        self.status.send_modify(|status| {
+            status.skipped_changes += 1;
+            status.last_skipped = Some(error.to_string());
+        });
     }
 
-    async fn process_txn(&mut self, event: &[u8]) {
-        match event[0] {
-            b'w' => {
-                // first 24 bytes are metadata
-                let json: Value = serde_json::from_slice(&event[25..]).unwrap();
-                self.process_record(json).await;
+    async fn process_message(&mut self, message: PgOutputMessage) -> Result<(), ReplicationError> {
+        match message {
+            PgOutputMessage::Begin { final_lsn, xid } => {
+                debug!("Transaction {} commits at {}", xid, final_lsn);
+                self.in_transaction = true;
+            }
+            PgOutputMessage::Commit { end_lsn } => {
+                self.commit_lsn = end_lsn;
+                self.replicate().await;
+                self.commit().await?;
             }
-            b'k' => {
-                let last_byte = event.last().unwrap();
-                let timeout_imminent = last_byte == &1;
-                if timeout_imminent {
-                    let buf = prepare_ssu(self.commit_lsn);
-                    self.send_ssu(buf).await;
+            PgOutputMessage::Change(event) => {
+                self.records.push(*event);
+            }
+            PgOutputMessage::Truncate(mut events) => {
+                self.records.append(&mut events);
+            }
+        }
+        Ok(())
+    }
+
+    async fn process_txn(&mut self, event: &[u8]) -> Result<(), ReplicationError> {
+        match event.first() {
+            // first 24 bytes are metadata
+            Some(b'w') if event.len() > 25 => match self.plugin {
+                OutputPlugin::Wal2Json => {
+                    let json: Value = serde_json::from_slice(&event[25..])
+                        .map_err(|e| ReplicationError::Decode(e.to_string()))?;
+                    self.process_record(json).await?;
                 }
+                OutputPlugin::PgOutput => match self.decoder.decode(&event[25..]) {
+                    Ok(Some(message)) => self.process_message(message).await?,
+                    Ok(None) => {}
+                    Err(e) => self.skip_change(e),
+                },
+            },
+            Some(b'k') => {
+                let timeout_imminent = event.last() == Some(&1);
+                self.acknowledge(timeout_imminent).await?;
             }
             _ => (),
         }
+        Ok(())
     }
 
+    // Stream the slot until the process ends. A failed stream is reconnected after a
+    // backoff, the server streams again from the confirmed position.
     pub async fn start_replication(&mut self) {
-        let full_table_name = format!("{}.{}", self.schema_name, self.table_name);
-        let options = vec![
-            ("pretty-print", "false"),
-            ("include-transaction", "true"),
-            ("include-lsn", "true"),
-            ("include-timestamp", "true"),
-            ("include-pk", "true"),
-            ("format-version", "2"),
-            ("include-xids", "true"),
-            ("add-tables", &full_table_name),
-        ];
-        let start_lsn = self.commit_lsn.to_string();
+        let mut attempt = 0;
+        loop {
+            let position = self.replicated_lsn();
+            let error = match self.stream_changes().await {
+                Ok(()) => ReplicationError::Closed,
+                Err(e) => e,
+            };
+            // Only consecutive failures back off further
+            if self.replicated_lsn() > position {
+                attempt = 0;
+            }
+            attempt += 1;
+            let delay = RECONNECT_DELAY
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(MAX_RECONNECT_DELAY);
+            warn!(
+                "Replication stream failed: {}, reconnecting in {:?} (attempt {})",
+                error, delay, attempt
+            );
+            self.status.send_modify(|status| {
+                status.health = StreamHealth::Reconnecting {
+                    attempt,
+                    error: error.to_string(),
+                }
+            });
+            tokio::time::sleep(delay).await;
+            if let Err(e) = self.reconnect().await {
+                warn!("Failed to reconnect: {}", e);
+            }
+        }
+    }
+
+    // Open a new connection, dropping the state of the failed one. Transactions after the
+    // confirmed position are streamed again, those the dataflows received already are
+    // skipped.
+    async fn reconnect(&mut self) -> Result<(), tokio_postgres::Error> {
+        self.stream = None;
+        self.records.clear();
+        self.in_transaction = false;
+        self.skip_until = self.skip_until.max(Some(self.replicated_lsn()));
+        self.client = Arc::new(DBClient::new(&self.config).await?.client);
+        Ok(())
+    }
+
+    async fn stream_changes(&mut self) -> Result<(), ReplicationError> {
+        // wal2json decodes every table, the changes are filtered by the subscriptions when
+        // they're replicated. Tables can be subscribed while the slot is streaming that way.
+        // It doesn't read the publication, so it sends the rows and columns outside of the
+        // reads too.
+        let options = match self.plugin {
+            OutputPlugin::Wal2Json => vec![
+                ("pretty-print", "false"),
+                ("include-transaction", "true"),
+                ("include-lsn", "true"),
+                ("include-timestamp", "true"),
+                ("include-pk", "true"),
+                ("format-version", "2"),
+                ("include-xids", "true"),
+            ],
+            // The publication restricts the changes to the subscribed tables
+            OutputPlugin::PgOutput => vec![
+                ("proto_version", "1"),
+                ("publication_names", &self.publication_name),
+            ],
+        };
+        let start_lsn = PgLsn::from(self.unconfirmed.confirmed()).to_string();
         let query = format!(
             "START_REPLICATION SLOT {} LOGICAL {} ({})",
             self.slot_name,
             start_lsn,
-            // specify table for replication
             options
                 .iter()
-                .map(|(k, v)| format!("\"{}\" '{}'", k, v))
+                .map(|(k, v)| format!("\"{}\" '{}'", k, v.replace('\'', "''")))
                 .collect::<Vec<_>>()
                 .join(", ")
         );
-        let duplex_stream = self
-            .client
-            .copy_both_simple::<bytes::Bytes>(&query)
-            .await
-            .unwrap();
+        let duplex_stream = self.client.copy_both_simple::<bytes::Bytes>(&query).await?;
 
         // Pin the stream
         self.stream = Some(Box::pin(duplex_stream));
-        debug!("Replication started for table: {}", full_table_name);
-        // listen
+        debug!("Replication started for slot: {}", self.slot_name);
+        self.status
+            .send_modify(|status| status.health = StreamHealth::Streaming);
+        // listen, and confirm what the dataflows wrote in between
+        let mut feedback = tokio::time::interval(FEEDBACK_INTERVAL);
         loop {
-            match self.stream.as_mut().unwrap().next().await {
-                Some(Ok(event)) => {
-                    // (todo:) should return error?
-                    self.process_txn(&event).await;
-                }
-                Some(Err(e)) => {
-                    warn!("Error reading from stream:{}", e);
+            let event = tokio::select! {
+                event = self.stream.as_mut().unwrap().next() => event,
+                _ = feedback.tick() => {
+                    self.acknowledge(false).await?;
+                    if !self.in_transaction {
+                        self.backfill().await?;
+                    }
                     continue;
                 }
-                None => {
-                    warn!("Stream closed");
-                    break;
-                }
+            };
+            match event {
+                Some(Ok(event)) => self.process_txn(&event).await?,
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::pg_client::data::{Insert, Update};
+
+    fn columns(columns: &[(&str, &str)]) -> Vec<(String, String)> {
+        columns
+            .iter()
+            .map(|(name, data_type)| (name.to_string(), data_type.to_string()))
+            .collect()
+    }
+
+    fn event(data: WalData) -> WalEvent {
+        WalEvent {
+            table: "users".to_string(),
+            timestamp: String::new(),
+            xid: 0,
+            lsn: 0,
+            pkey: PKey::none(),
+            data,
+        }
+    }
+
+    fn insert() -> WalEvent {
+        event(WalData::Insert(Insert(BTreeMap::new())))
+    }
+
+    fn update() -> WalEvent {
+        event(WalData::Update(Update {
+            values: BTreeMap::new(),
+            old_key: None,
+            old: None,
+        }))
+    }
+
+    #[test]
+    fn the_first_change_of_a_table_is_no_schema_change() {
+        let mut tables = HashMap::new();
+        let users = columns(&[("id", "integer"), ("name", "text")]);
+        assert_eq!(track_columns(&mut tables, &insert(), users.clone()), None);
+        assert_eq!(track_columns(&mut tables, &insert(), users), None);
+    }
+
+    #[test]
+    fn inserts_tell_added_dropped_and_retyped_columns() {
+        let mut tables = HashMap::new();
+        let users = columns(&[("id", "integer"), ("name", "text"), ("age", "integer")]);
+        track_columns(&mut tables, &insert(), users);
+        let altered = columns(&[("id", "bigint"), ("name", "text"), ("email", "text")]);
+        assert_eq!(
+            track_columns(&mut tables, &insert(), altered),
+            Some(SchemaChange {
+                added: vec!["email".to_string()],
+                dropped: vec!["age".to_string()],
+                retyped: vec!["id".to_string()],
+            })
+        );
+    }
+
+    #[test]
+    fn updates_leaving_out_toast_values_drop_no_column() {
+        let mut tables = HashMap::new();
+        let users = columns(&[("id", "integer"), ("bio", "text")]);
+        track_columns(&mut tables, &insert(), users);
+        let without_bio = columns(&[("id", "integer")]);
+        assert_eq!(
+            track_columns(&mut tables, &update(), without_bio.clone()),
+            None
+        );
+        // An insert without it tells it was dropped
+        assert_eq!(
+            track_columns(&mut tables, &insert(), without_bio),
+            Some(SchemaChange {
+                dropped: vec!["bio".to_string()],
+                ..SchemaChange::default()
+            })
+        );
+    }
+
+    // The transactions `outgoing` sends, by their LSN and number of changes
+    fn sent(subscribers: &Subscribers, lsn: u64) -> Vec<(u64, usize)> {
+        outgoing(subscribers, lsn, &[insert()])
+            .into_iter()
+            .map(|(_, transaction)| (transaction.lsn, transaction.changes.len()))
+            .collect()
+    }
+
+    #[test]
+    fn a_restart_rebuilds_the_views_from_a_new_snapshot() {
+        // The restarted stream resumes the slot at its confirmed position 100, an earlier run
+        // wrote some sinks further. The subscriber waits for its snapshot meanwhile.
+        let (sender, _receiver) = tokio::sync::mpsc::channel(10);
+        let subscribers: Subscribers = Arc::new(Mutex::new(vec![Subscriber {
+            id: 0,
+            tables: vec!["users".to_string()],
+            sender,
+            snapshot_lsn: None,
+        }]));
+        let progress = Progress::default();
+        let mut unconfirmed = Unconfirmed::new(100);
+        let dataflow = progress.track();
+        for lsn in [120, 150] {
+            assert_eq!(sent(&subscribers, lsn), vec![]);
+            unconfirmed.push(lsn, false);
+        }
+
+        // The snapshot at 200 contains the transactions streamed again up to it, the
+        // subscriber only receives those after it
+        subscribers.lock().unwrap()[0].snapshot_lsn = Some(PgLsn::from(200));
+        assert_eq!(sent(&subscribers, 180), vec![]);
+        unconfirmed.push(180, false);
+        assert_eq!(sent(&subscribers, 250), vec![(250, 1)]);
+        unconfirmed.push(250, true);
+
+        // Only the transactions of the new run wait for the dataflow
+        unconfirmed.confirm(&progress);
+        assert_eq!(unconfirmed.confirmed(), 180);
+        dataflow.flush(250);
+        unconfirmed.confirm(&progress);
+        assert_eq!(unconfirmed.confirmed(), 250);
+    }
+}
@@ -1,16 +1,11 @@
-use std::{collections::HashMap, env};
+use std::env;
 
-use tokio_postgres::{Error, NoTls};
-use tracing::warn;
+use tokio_postgres::{Client, Error, NoTls};
 
 #[derive(Debug, Clone)]
-
 pub enum KeyType {
     PrimaryKey,
-    ForeignKey {
-        foreign_table: String,
-        foreign_column: String,
-    },
+    ForeignKey,
 }
 
 #[derive(Debug, Clone)]
@@ -19,21 +14,96 @@ pub struct Key {
     pub key_type: KeyType,
 }
 
-// TODO: cache the keys
-pub async fn get_keys_for_table(table_name: String) -> Result<Vec<Key>, Error> {
-    let ev = |name| env::var(name).unwrap();
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub data_type: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableSchema {
+    pub columns: Vec<Column>,
+    pub keys: Vec<Key>,
+    pub indexed_columns: Vec<String>,
+}
+
+impl TableSchema {
+    // Tables that don't exist have no columns in the catalog
+    pub fn exists(&self) -> bool {
+        !self.columns.is_empty()
+    }
+
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|column| column.name == name)
+    }
+
+    pub fn is_keyed_or_indexed(&self, column: &str) -> bool {
+        self.keys.iter().any(|key| key.column_name == column)
+            || self.indexed_columns.iter().any(|indexed| indexed == column)
+    }
+}
+
+// Schema and name of a table, parsed from the name used in queries where tables of the
+// `public` schema are not qualified
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QualifiedName {
+    pub schema: String,
+    pub table: String,
+}
+
+impl QualifiedName {
+    pub fn parse(name: &str) -> Self {
+        match name.split_once('.') {
+            Some((schema, table)) => QualifiedName {
+                schema: schema.to_string(),
+                table: table.to_string(),
+            },
+            None => QualifiedName {
+                schema: "public".to_string(),
+                table: name.to_string(),
+            },
+        }
+    }
 
-    let db_config = format!(
+    // The name used in queries, `events` in `public` and `analytics.events` otherwise
+    pub fn query_name(&self) -> String {
+        if self.schema == "public" {
+            self.table.clone()
+        } else {
+            format!("{}.{}", self.schema, self.table)
+        }
+    }
+
+    // `"analytics"."events"`, keeping the case of both parts
+    pub fn quoted(&self) -> String {
+        format!(
+            "{}.{}",
+            quote_identifier(&self.schema),
+            quote_identifier(&self.table)
+        )
+    }
+}
+
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Connection string of the source database, from the environment
+pub fn db_config() -> String {
+    let ev = |name| env::var(name).unwrap();
+    format!(
         "user={} password={} host={} port={} dbname={}",
         ev("DB_USER"),
         ev("DB_PASSWORD"),
         ev("DB_ADDR"),
         ev("DB_PORT"),
         "postgres"
-    );
+    )
+}
 
+pub async fn connect() -> Result<Client, Error> {
     // connect to the database
-    let (client, connection) = tokio_postgres::connect(&db_config, NoTls).await.unwrap();
+    let (client, connection) = tokio_postgres::connect(&db_config(), NoTls).await?;
 
     // Spawn a task to manage the connection (this will run in the background)
     tokio::spawn(async move {
@@ -41,59 +111,86 @@ pub async fn get_keys_for_table(table_name: String) -> Result<Vec<Key>, Error> {
             eprintln!("connection error: {}", e);
         }
     });
+    Ok(client)
+}
 
-    let query = format!(
-        "SELECT 
-            keys.column_name, 
-            'PRIMARY KEY' AS key_type,
-            NULL AS foreign_table,
-            NULL AS foreign_column
-        FROM 
-            information_schema.table_constraints constraints
-        JOIN 
-            information_schema.key_column_usage keys 
-            ON constraints.constraint_name = keys.constraint_name
-        WHERE 
-            constraints.constraint_type = 'PRIMARY KEY'
-            AND keys.table_name = \'{table_name}\'
+// Load columns, keys and indexed columns of a table from the catalog
+pub async fn get_table_schema(table_name: String) -> Result<TableSchema, Error> {
+    let client = connect().await?;
+    let name = QualifiedName::parse(&table_name);
 
-        UNION ALL
+    let rows = client
+        .query(
+            "SELECT column_name::text, data_type::text
+            FROM information_schema.columns
+            WHERE table_schema = $1 AND table_name = $2
+            ORDER BY ordinal_position",
+            &[&name.schema, &name.table],
+        )
+        .await?;
+    let columns = rows
+        .iter()
+        .map(|row| Column {
+            name: row.get(0),
+            data_type: row.get(1),
+        })
+        .collect::<Vec<Column>>();
+
+    let rows = client
+        .query(
+            "SELECT DISTINCT attribute.attname::text
+            FROM pg_index idx
+            JOIN pg_class class ON class.oid = idx.indrelid
+            JOIN pg_namespace namespace ON namespace.oid = class.relnamespace
+            JOIN pg_attribute attribute
+                ON attribute.attrelid = idx.indrelid AND attribute.attnum = ANY(idx.indkey)
+            WHERE namespace.nspname = $1 AND class.relname = $2",
+            &[&name.schema, &name.table],
+        )
+        .await?;
+    let indexed_columns = rows.iter().map(|row| row.get(0)).collect::<Vec<String>>();
+
+    Ok(TableSchema {
+        keys: query_keys(&client, &name).await?,
+        columns,
+        indexed_columns,
+    })
+}
 
-        SELECT 
-            keys.column_name, 
-            'FOREIGN KEY' AS key_type,
-            ccu.table_name AS foreign_table,
-            ccu.column_name AS foreign_column
+// TODO: cache the keys
+pub async fn get_keys_for_table(table_name: String) -> Result<Vec<Key>, Error> {
+    let client = connect().await?;
+    query_keys(&client, &QualifiedName::parse(&table_name)).await
+}
+
+// Primary and foreign key columns of a table
+async fn query_keys(client: &Client, name: &QualifiedName) -> Result<Vec<Key>, Error> {
+    let query = "SELECT 
+            keys.column_name::text, 
+            constraints.constraint_type::text
         FROM 
             information_schema.table_constraints constraints
         JOIN 
             information_schema.key_column_usage keys 
-            ON constraints.constraint_name = keys.constraint_name
-        JOIN 
-            information_schema.constraint_column_usage ccu 
-            ON ccu.constraint_name = constraints.constraint_name
+            ON constraints.constraint_schema = keys.constraint_schema
+            AND constraints.constraint_name = keys.constraint_name
         WHERE 
-            constraints.constraint_type = 'FOREIGN KEY'
-            AND keys.table_name = \'{table_name}\'",
-    );
+            constraints.constraint_type IN ('PRIMARY KEY', 'FOREIGN KEY')
+            AND keys.table_schema = $1
+            AND keys.table_name = $2";
     let mut keys: Vec<Key> = Vec::new();
     // Execute the query
-    let rows = client.query(&query, &[]).await?;
+    let rows = client.query(query, &[&name.schema, &name.table]).await?;
 
     // Print out each column's schema information
     for row in rows {
         let column_name: &str = row.get(0);
         let key_type: &str = row.get(1);
-        let foreign_table: Option<&str> = row.get(2);
-        let foreign_column: Option<&str> = row.get(3);
         keys.push(Key {
             column_name: column_name.to_string(),
             key_type: match key_type {
                 "PRIMARY KEY" => KeyType::PrimaryKey,
-                "FOREIGN KEY" => KeyType::ForeignKey {
-                    foreign_table: "".to_string(),
-                    foreign_column: "".to_string(),
-                },
+                "FOREIGN KEY" => KeyType::ForeignKey,
                 _ => panic!("Unknown key type"),
             },
         });
@@ -101,3 +198,31 @@ pub async fn get_keys_for_table(table_name: String) -> Result<Vec<Key>, Error> {
 
     Ok(keys)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_without_a_schema_belong_to_public() {
+        let name = QualifiedName::parse("users");
+        assert_eq!(name.schema, "public");
+        assert_eq!(name.table, "users");
+        assert_eq!(name.query_name(), "users");
+        assert_eq!(name.quoted(), r#""public"."users""#);
+    }
+
+    #[test]
+    fn names_of_other_schemas_keep_both_parts() {
+        let name = QualifiedName::parse("Sales.Order");
+        assert_eq!(name.schema, "Sales");
+        assert_eq!(name.table, "Order");
+        assert_eq!(name.query_name(), "Sales.Order");
+        assert_eq!(name.quoted(), r#""Sales"."Order""#);
+    }
+
+    #[test]
+    fn quoting_escapes_double_quotes() {
+        assert_eq!(quote_identifier(r#"my "table""#), r#""my ""table""""#);
+    }
+}
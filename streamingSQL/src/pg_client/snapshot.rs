@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+
+use bytes::{Buf, BytesMut};
+use futures::{pin_mut, StreamExt};
+use serde_json::Value;
+use tokio::sync::mpsc::Sender;
+use tokio_postgres::types::PgLsn;
+use tokio_postgres::{Client, Error};
+use tracing::{debug, warn};
+
+use super::data::{Insert, PKey, Transaction, WalData, WalEvent};
+use super::replication::Subscribers;
+use super::schema::{get_keys_for_table, KeyType, QualifiedName};
+
+// Rows are sent to the dataflow in batches of this size
+const SNAPSHOT_BATCH: usize = 1000;
+
+/// Send the rows of the tables of the subscribers `ids` as of the snapshot exported by a new
+/// replication slot at `lsn`, followed by a transaction at `lsn`, which closes the time of
+/// the rows. Rows are sent in batches while they're read, waiting while a subscriber is full.
+///
+/// The rows become inserts at time 0, ahead of every change streamed after the snapshot.
+/// Since the subscribers only receive the transactions ending after `lsn`, each row is seen
+/// exactly once, either in the snapshot or as a change.
+pub async fn copy_tables(
+    client: &Client,
+    subscribers: &Subscribers,
+    ids: &[usize],
+    snapshot: &str,
+    lsn: PgLsn,
+) -> Result<(), Error> {
+    let mut tables: Vec<String> = vec![];
+    for subscriber in subscribers.lock().unwrap().iter() {
+        if ids.contains(&subscriber.id) {
+            for table in &subscriber.tables {
+                if !tables.contains(table) {
+                    tables.push(table.clone());
+                }
+            }
+        }
+    }
+
+    client
+        .batch_execute(&format!(
+            "BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY; SET TRANSACTION SNAPSHOT '{}'",
+            snapshot.replace('\'', "''")
+        ))
+        .await?;
+    let mut copied = Ok(());
+    for table_name in &tables {
+        copied = copy_table(client, subscribers, ids, table_name).await;
+        if copied.is_err() {
+            break;
+        }
+    }
+    client.batch_execute("COMMIT").await?;
+    copied?;
+
+    for (sender, transaction) in targets(subscribers, ids, u64::from(lsn), &[]) {
+        let _ = sender.send(transaction).await;
+    }
+    Ok(())
+}
+
+// The part of a batch each of the subscribers `ids` reads. Batches of rows are only sent to
+// the subscribers of their table, the transaction closing the snapshot to all of them.
+fn targets(
+    subscribers: &Subscribers,
+    ids: &[usize],
+    lsn: u64,
+    changes: &[WalEvent],
+) -> Vec<(Sender<Transaction>, Transaction)> {
+    subscribers
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|subscriber| ids.contains(&subscriber.id))
+        .map(|subscriber| {
+            (
+                subscriber.sender.clone(),
+                subscriber.transaction(lsn, changes),
+            )
+        })
+        .filter(|(_, transaction)| lsn > 0 || !transaction.changes.is_empty())
+        .collect()
+}
+
+// Stream the rows of a table as JSON objects, which have the same shape as the columns of
+// wal2json. The rows are read in the open snapshot transaction of `client`.
+async fn copy_table(
+    client: &Client,
+    subscribers: &Subscribers,
+    ids: &[usize],
+    table_name: &str,
+) -> Result<(), Error> {
+    let keys = get_keys_for_table(table_name.to_string()).await?;
+    let Some(key) = keys
+        .iter()
+        .find(|key| matches!(key.key_type, KeyType::PrimaryKey))
+    else {
+        warn!("{} has no primary key, skipping its snapshot", table_name);
+        return Ok(());
+    };
+    let name = QualifiedName::parse(table_name);
+    let stream = client
+        .copy_out(&format!(
+            "COPY (SELECT row_to_json(t) FROM {} t) TO STDOUT",
+            name.quoted()
+        ))
+        .await?;
+    pin_mut!(stream);
+
+    let mut rows = 0;
+    let mut batch = vec![];
+    let mut buffer = BytesMut::new();
+    while let Some(data) = stream.next().await {
+        buffer.extend_from_slice(&data?);
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line = buffer.split_to(end);
+            buffer.advance(1);
+            let line = unescape_copy_text(&String::from_utf8_lossy(&line));
+            let values = match serde_json::from_str(&line) {
+                Ok(Value::Object(values)) => values.into_iter().collect::<BTreeMap<_, _>>(),
+                Ok(row) => {
+                    warn!("Skipping snapshot row that is not an object: {}", row);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Skipping snapshot row {}: {}", line, e);
+                    continue;
+                }
+            };
+            batch.push(WalEvent {
+                table: name.query_name(),
+                timestamp: "".to_string(),
+                xid: 0,
+                lsn: 0,
+                pkey: PKey {
+                    col: key.column_name.clone(),
+                    val: values.get(&key.column_name).cloned().unwrap_or(Value::Null),
+                },
+                data: WalData::Insert(Insert(values)),
+            });
+            if batch.len() == SNAPSHOT_BATCH {
+                rows += batch.len();
+                send_batch(subscribers, ids, &batch).await;
+                batch.clear();
+            }
+        }
+    }
+    rows += batch.len();
+    send_batch(subscribers, ids, &batch).await;
+    debug!("Copied {} rows of {}", rows, name.quoted());
+    Ok(())
+}
+
+async fn send_batch(subscribers: &Subscribers, ids: &[usize], batch: &[WalEvent]) {
+    for (sender, transaction) in targets(subscribers, ids, 0, batch) {
+        let _ = sender.send(transaction).await;
+    }
+}
+
+// The text format of COPY escapes backslashes and control characters
+fn unescape_copy_text(line: &str) -> String {
+    let mut text = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('r') => text.push('\r'),
+            Some('t') => text.push('\t'),
+            Some('b') => text.push('\u{8}'),
+            Some('f') => text.push('\u{c}'),
+            Some('v') => text.push('\u{b}'),
+            Some(other) => text.push(other),
+            None => text.push('\\'),
+        }
+    }
+    text
+}
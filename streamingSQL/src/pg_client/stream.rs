@@ -1,59 +1,286 @@
-use std::{env, sync::Arc};
-
-use tokio::sync::broadcast::Sender;
-use tracing::{debug, info};
-
-use crate::pg_client::data::WalEvent;
-
-use super::{publication, replication};
-
-pub async fn start_streaming_changes(
-    tx: Sender<Vec<WalEvent>>,
-    table_name: String,
-) -> Result<(), tokio_postgres::Error> {
-    let ev = |name| env::var(name).unwrap();
-    let db_config = format!(
-        "user={} password={} host={} port={} dbname={}",
-        ev("DB_USER"),
-        ev("DB_PASSWORD"),
-        ev("DB_ADDR"),
-        ev("DB_PORT"),
-        "postgres"
-    );
-    let repl_client = Arc::new(
-        replication::DBClient::new(&format!("{} replication=database", db_config))
-            .await
-            .unwrap()
-            .client,
-    );
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::pg_client::data::Transaction;
+
+use super::pgoutput::PublishedColumns;
+use super::progress::{DataflowProgress, Progress};
+use super::publication::{Publication, TableRead};
+use super::replication::{self, StreamHealth, StreamStatus, Subscriber, Subscribers};
+use super::schema::{db_config, QualifiedName};
+
+// Slot and publication shared by every view of the database
+const REPLICATION_NAME: &str = "streaming_sql";
+// Transactions a subscriber can fall behind before the stream waits for it
+const CHANNEL_CAPACITY: usize = 10000;
+
+/// The single replication stream of the source database.
+///
+/// Dataflows subscribe to the parts of the tables they read. A table is added to the
+/// publication with its first subscriber and dropped from it with the last one, the
+/// transactions of the slot are demultiplexed to the subscribers by table. With Postgres 15 or
+/// later and `pgoutput` the publication only sends the rows and columns the subscribers read,
+/// wal2json sends all of them. The slot and the publication outlive the subscribers, only
+/// `teardown` drops them.
+pub struct ReplicationStream {
+    subscribers: Subscribers,
+    // What each subscriber reads of its tables
+    reads: HashMap<usize, Vec<TableRead>>,
+    // What the publication sends of each table, and the columns the decoder receives of it
+    published: HashMap<String, TableRead>,
+    published_columns: PublishedColumns,
+    // Whether the server supports row filters and column lists
+    restrict: Option<bool>,
+    next_id: usize,
+    storage_client: Option<Arc<tokio_postgres::Client>>,
+    progress: Arc<Progress>,
+    status: Arc<watch::Sender<StreamStatus>>,
+    // Copies the snapshot and streams the changes once started
+    replication: Option<JoinHandle<()>>,
+}
 
-    let storage_client = Arc::new(replication::DBClient::new(&db_config).await.unwrap().client);
+/// The transactions of a subscriber, where it reports the ones written to its sinks, and
+/// where it reports the views it stopped. Only transactions written by every subscriber are
+/// confirmed to the server.
+pub struct Subscription {
+    pub id: usize,
+    pub receiver: Receiver<Transaction>,
+    pub progress: Arc<DataflowProgress>,
+    pub status: Arc<watch::Sender<StreamStatus>>,
+}
 
-    // Hardcoded for now
-    let schema_name = "public".to_string();
+impl ReplicationStream {
+    pub fn new() -> Self {
+        ReplicationStream {
+            subscribers: Arc::new(Mutex::new(vec![])),
+            reads: HashMap::new(),
+            published: HashMap::new(),
+            published_columns: PublishedColumns::default(),
+            restrict: None,
+            next_id: 0,
+            storage_client: None,
+            progress: Arc::new(Progress::default()),
+            status: Arc::new(watch::channel(StreamStatus::default()).0),
+            replication: None,
+        }
+    }
 
-    let publication =
-        publication::Publication::new(Arc::clone(&storage_client), &schema_name, &table_name);
+    async fn client(&mut self) -> Result<Arc<tokio_postgres::Client>, tokio_postgres::Error> {
+        if let Some(client) = &self.storage_client {
+            return Ok(Arc::clone(client));
+        }
+        let client = Arc::new(replication::DBClient::new(&db_config()).await?.client);
+        self.storage_client = Some(Arc::clone(&client));
+        Ok(client)
+    }
 
-    // Check if publication exists or create it if it doesn't
-    if publication.check_exists().await? {
-        debug!("Publication already exists");
-    } else {
-        debug!("Creating publication ");
-        publication.create().await?;
+    async fn publication(&mut self) -> Result<Publication, tokio_postgres::Error> {
+        let publication = Publication::new(self.client().await?, REPLICATION_NAME);
+        if !publication.check_exists().await? {
+            debug!("Creating publication {}", REPLICATION_NAME);
+            publication.create().await?;
+        }
+        Ok(publication)
     }
 
-    let slot_name = format!("slot_{}", table_name.to_lowercase().trim_matches('"'));
-    let mut slot = replication::Slot::new(Arc::clone(&repl_client), &slot_name);
-    slot.get_confirmed_lsn().await?;
-    // Create new replication slot if LSN is None
-    if slot.lsn.is_none() {
-        debug!("Creating replication slot {:?}", slot_name);
-        slot.create().await?;
+    // Follow the condition of the stream, which reconnects by itself when it fails, and of
+    // the views it feeds
+    pub fn status(&self) -> watch::Receiver<StreamStatus> {
+        self.status.subscribe()
     }
-    let mut replicator =
-        replication::Replicator::new(Arc::clone(&repl_client), slot, publication, tx);
-    replicator.start_replication().await;
 
-    Ok(())
+    // Publish what the subscribers read of a table, or drop it from the publication if none
+    // of them reads it
+    async fn publish(&mut self, table_name: &str) -> Result<(), tokio_postgres::Error> {
+        let read = self
+            .reads
+            .values()
+            .flatten()
+            .filter(|read| read.table == table_name)
+            .cloned()
+            .reduce(|left, right| left.union(&right));
+        let Some(mut read) = read else {
+            self.published_columns.lock().unwrap().remove(table_name);
+            if self.published.remove(table_name).is_some() {
+                self.publication()
+                    .await?
+                    .drop_table(&QualifiedName::parse(table_name))
+                    .await?;
+            }
+            return Ok(());
+        };
+        if self.published.get(table_name) == Some(&read) {
+            return Ok(());
+        }
+        // The decoder tells a changed column list from a schema change by the published
+        // columns. A narrower list reaches it with the next change of the table, which may be
+        // decoded before the publication is altered here, so the columns nobody reads anymore
+        // are left out right away. A subscriber widening the list copies the new columns with
+        // its snapshot.
+        if let (Some(columns), Some(Some(published))) = (
+            &read.columns,
+            self.published_columns.lock().unwrap().get_mut(table_name),
+        ) {
+            published.retain(|column| columns.contains(column));
+        }
+        let publication = self.publication().await?;
+        let restrict = match self.restrict {
+            Some(restrict) => restrict,
+            None => *self
+                .restrict
+                .insert(publication.supports_restrictions().await?),
+        };
+        let columns = match publication.set_table(&read, restrict).await {
+            Ok(columns) => columns,
+            Err(e) if restrict => {
+                warn!(
+                    "Failed to restrict the publication of {}, publishing all of it: {}",
+                    table_name, e
+                );
+                read = TableRead::all(table_name);
+                publication.set_table(&read, false).await?
+            }
+            Err(e) => return Err(e),
+        };
+        self.published.insert(table_name.to_string(), read);
+        self.published_columns
+            .lock()
+            .unwrap()
+            .insert(table_name.to_string(), columns);
+        Ok(())
+    }
+
+    // Receive the transactions changing the tables of `reads`, named like in queries. The
+    // changes of rows and columns outside of the reads may be left out.
+    pub async fn subscribe(
+        &mut self,
+        reads: &[TableRead],
+    ) -> Result<Subscription, tokio_postgres::Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.reads.insert(id, reads.to_vec());
+        for read in reads {
+            if let Err(e) = self.publish(&read.table).await {
+                self.reads.remove(&id);
+                return Err(e);
+            }
+        }
+        let tables = reads
+            .iter()
+            .map(|read| read.table.clone())
+            .collect::<Vec<_>>();
+
+        let progress = self.progress.track();
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        // The stream copies the tables before it sends any change
+        self.subscribers.lock().unwrap().push(Subscriber {
+            id,
+            tables,
+            sender: tx,
+            snapshot_lsn: None,
+        });
+        Ok(Subscription {
+            id,
+            receiver: rx,
+            progress,
+            status: Arc::clone(&self.status),
+        })
+    }
+
+    pub async fn unsubscribe(&mut self, id: usize) -> Result<(), tokio_postgres::Error> {
+        let tables = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            let Some(position) = subscribers
+                .iter()
+                .position(|subscriber| subscriber.id == id)
+            else {
+                warn!("Subscriber {} is not subscribed", id);
+                return Ok(());
+            };
+            subscribers.remove(position).tables
+        };
+        self.reads.remove(&id);
+        for table_name in tables {
+            self.publish(&table_name).await?;
+        }
+        Ok(())
+    }
+
+    // Stop streaming and drop the slot and the publication, for when no view will read the
+    // database anymore. Views started afterwards copy their tables again.
+    pub async fn teardown(&mut self) -> Result<(), tokio_postgres::Error> {
+        if let Some(replication) = self.replication.take() {
+            // Closes the replication connection, the slot can only be dropped once inactive
+            replication.abort();
+            let _ = replication.await;
+        }
+        let repl_config = format!("{} replication=database", db_config());
+        let repl_client = Arc::new(replication::DBClient::new(&repl_config).await?.client);
+        let plugin = replication::OutputPlugin::from_env();
+        let mut slot = replication::Slot::new(repl_client, REPLICATION_NAME, plugin);
+        slot.get_confirmed_lsn().await?;
+        if slot.lsn.is_some() {
+            slot.drop_slot().await?;
+        }
+        Publication::new(self.client().await?, REPLICATION_NAME)
+            .drop()
+            .await?;
+        self.published.clear();
+        self.published_columns.lock().unwrap().clear();
+        self.status
+            .send_modify(|status| status.health = StreamHealth::Starting);
+        info!("Tore down replication stream {}", REPLICATION_NAME);
+        Ok(())
+    }
+
+    // Stream the changes of the subscribed tables. Does nothing once the stream runs.
+    //
+    // The slot is permanent, so a restart resumes at its confirmed position and the server
+    // keeps the WAL of the transactions no sink wrote yet. The dataflow state doesn't survive
+    // a restart though, so views are rebuilt rather than resumed: every subscriber, including
+    // those subscribing while the stream runs, starts from a snapshot of its tables that the
+    // stream copies before it sends it any change. The transactions streamed again up to the
+    // snapshot are in it already and aren't sent, and the first write of each sink replaces
+    // the rows of the earlier run.
+    pub async fn start(&mut self) -> Result<(), tokio_postgres::Error> {
+        if self.replication.is_some() {
+            return Ok(());
+        }
+        let repl_config = format!("{} replication=database", db_config());
+        let repl_client = Arc::new(replication::DBClient::new(&repl_config).await?.client);
+
+        let plugin = replication::OutputPlugin::from_env();
+        let slot_name = REPLICATION_NAME.to_string();
+        let mut slot = replication::Slot::new(Arc::clone(&repl_client), &slot_name, plugin);
+        slot.get_confirmed_lsn().await?;
+        match slot.lsn {
+            Some(lsn) => info!("Resuming replication slot {} at {}", slot_name, lsn),
+            None => {
+                debug!("Creating replication slot {:?}", slot_name);
+                slot.create(false).await?;
+            }
+        }
+
+        let mut replicator = replication::Replicator::new(
+            repl_client,
+            repl_config,
+            slot,
+            REPLICATION_NAME.to_string(),
+            Arc::clone(&self.published_columns),
+            Arc::clone(&self.subscribers),
+            Arc::clone(&self.progress),
+            Arc::clone(&self.status),
+        );
+        let replication = tokio::spawn(async move { replicator.start_replication().await });
+        self.replication = Some(replication);
+        info!("Started replication stream {}", REPLICATION_NAME);
+        Ok(())
+    }
 }